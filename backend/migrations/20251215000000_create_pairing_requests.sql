@@ -0,0 +1,27 @@
+-- Sessions created by pairing belong to one named device and can be revoked on their own
+ALTER TABLE sessions ADD COLUMN device_name VARCHAR(100);
+
+-- Short-lived codes that let a signed-in device bring a new device onto the account
+CREATE TABLE pairing_requests (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- Only the session that created the code may approve it
+    requested_by UUID NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+    code_hash VARCHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- Set when a new device redeems the code
+    claimed_at TIMESTAMPTZ,
+    poll_token_hash VARCHAR(64) UNIQUE,
+    device_name VARCHAR(100),
+    device_public_key TEXT,
+    -- Set by the original device
+    approved_at TIMESTAMPTZ,
+    rejected_at TIMESTAMPTZ,
+    sealed_key TEXT,
+    -- Set when the new device collects its credentials
+    completed_at TIMESTAMPTZ,
+    paired_session_id UUID REFERENCES sessions(id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_pairing_requests_user_id ON pairing_requests(user_id, created_at);
@@ -0,0 +1,9 @@
+-- 1. Administrators manage other accounts through /admin
+ALTER TABLE users ADD COLUMN role VARCHAR(20) NOT NULL DEFAULT 'user' CHECK (role IN ('user', 'admin'));
+
+-- 2. Disabled accounts can't sign in and lose their sessions
+ALTER TABLE users ADD COLUMN disabled_at TIMESTAMPTZ;
+
+-- 3. Per-user overrides of the default limits (NULL means use the default)
+ALTER TABLE users ADD COLUMN max_messages_per_minute INTEGER CHECK (max_messages_per_minute > 0);
+ALTER TABLE users ADD COLUMN max_connections INTEGER CHECK (max_connections > 0);
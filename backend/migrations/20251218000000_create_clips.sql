@@ -0,0 +1,15 @@
+-- Clipboard history, previously held in server memory and lost on restart.
+-- Encrypted clips are stored as the ciphertext the client sent.
+CREATE TABLE clips (
+    id BIGSERIAL PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    device_id TEXT NOT NULL,
+    content TEXT NOT NULL,
+    nonce TEXT,
+    encrypted BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Client-supplied time of the copy, in unix milliseconds
+    timestamp BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_clips_user_id ON clips(user_id, id DESC);
@@ -0,0 +1,6 @@
+-- 1. What a clip looks like, for filtering. NULL for encrypted clips whose sender didn't say.
+ALTER TABLE clips ADD COLUMN kind VARCHAR(10) CHECK (kind IN ('text', 'url', 'code'));
+
+-- 2. Indexes for the history filters; paging itself uses idx_clips_user_id
+CREATE INDEX idx_clips_user_device ON clips(user_id, device_id, id DESC);
+CREATE INDEX idx_clips_user_timestamp ON clips(user_id, timestamp);
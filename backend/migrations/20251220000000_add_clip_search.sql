@@ -0,0 +1,7 @@
+-- Full-text search over plaintext clips. 'simple' doesn't stem or drop stop words, which
+-- suits commands, paths and URLs. Encrypted clips get no vector and never match.
+ALTER TABLE clips ADD COLUMN search TSVECTOR GENERATED ALWAYS AS (
+    CASE WHEN encrypted THEN NULL ELSE to_tsvector('simple', content) END
+) STORED;
+
+CREATE INDEX idx_clips_search ON clips USING GIN (search);
@@ -0,0 +1,4 @@
+-- Pinned clips are kept when history is trimmed.
+ALTER TABLE clips ADD COLUMN pinned_at TIMESTAMPTZ;
+
+CREATE INDEX idx_clips_user_pinned ON clips (user_id, pinned_at DESC) WHERE pinned_at IS NOT NULL;
@@ -0,0 +1,10 @@
+-- Per-user retention. NULL means the server default (50 items, no age or size limit).
+ALTER TABLE users
+    ADD COLUMN history_max_items INTEGER CHECK (history_max_items > 0),
+    ADD COLUMN history_max_age_secs BIGINT CHECK (history_max_age_secs > 0),
+    ADD COLUMN history_max_bytes BIGINT CHECK (history_max_bytes > 0);
+
+-- Unix milliseconds, like clips.timestamp. Set when the sender gives the clip a TTL.
+ALTER TABLE clips ADD COLUMN expires_at BIGINT;
+
+CREATE INDEX idx_clips_expires_at ON clips (expires_at) WHERE expires_at IS NOT NULL;
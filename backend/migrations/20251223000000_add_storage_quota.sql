@@ -0,0 +1,2 @@
+-- Overrides STORAGE_QUOTA_BYTES for one user.
+ALTER TABLE users ADD COLUMN storage_quota_bytes BIGINT CHECK (storage_quota_bytes > 0);
@@ -0,0 +1,6 @@
+-- SHA-256 of the stored content (the ciphertext for encrypted clips), in hex.
+ALTER TABLE clips ADD COLUMN content_hash TEXT;
+UPDATE clips SET content_hash = encode(sha256(convert_to(content, 'UTF8')), 'hex');
+ALTER TABLE clips ALTER COLUMN content_hash SET NOT NULL;
+
+CREATE INDEX idx_clips_user_content_hash ON clips (user_id, content_hash);
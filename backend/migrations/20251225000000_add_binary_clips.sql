@@ -0,0 +1,15 @@
+-- Binary clips (images, files) pushed through POST /clips. Their content is base64, so
+-- they carry a MIME type and are left out of full-text search.
+ALTER TABLE clips ADD COLUMN mime_type TEXT;
+
+ALTER TABLE clips DROP CONSTRAINT clips_kind_check;
+ALTER TABLE clips ADD CONSTRAINT clips_kind_check
+    CHECK (kind IN ('text', 'url', 'code', 'binary'));
+
+-- Generated columns can't be altered in place; the index goes with the column.
+ALTER TABLE clips DROP COLUMN search;
+ALTER TABLE clips ADD COLUMN search TSVECTOR GENERATED ALWAYS AS (
+    CASE WHEN encrypted OR kind = 'binary' THEN NULL ELSE to_tsvector('simple', content) END
+) STORED;
+
+CREATE INDEX idx_clips_search ON clips USING GIN (search);
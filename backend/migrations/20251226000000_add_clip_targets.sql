@@ -0,0 +1,2 @@
+-- Devices (session or access token ids) a clip was sent to. NULL means every device.
+ALTER TABLE clips ADD COLUMN targets UUID[];
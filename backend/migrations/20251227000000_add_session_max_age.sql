@@ -0,0 +1,6 @@
+-- Refreshing extends a session 24h at a time, but never past this deadline.
+ALTER TABLE sessions ADD COLUMN max_expires_at TIMESTAMPTZ;
+UPDATE sessions SET max_expires_at = created_at + INTERVAL '30 days';
+ALTER TABLE sessions
+    ALTER COLUMN max_expires_at SET DEFAULT NOW() + INTERVAL '30 days',
+    ALTER COLUMN max_expires_at SET NOT NULL;
@@ -0,0 +1,4 @@
+-- When the user last proved who they are for this session: set by password, 2FA, passkey
+-- and SSO sign-ins, NULL for devices added through pairing. Lets a fresh sign-in stand in
+-- for the password on sensitive actions.
+ALTER TABLE sessions ADD COLUMN signed_in_at TIMESTAMPTZ;
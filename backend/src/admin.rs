@@ -0,0 +1,287 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post, put},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    audit::{self, AuditEvent},
+    db,
+    error::AppError,
+    middleware::{AdminUser, ClientMeta},
+    models::{AdminUserInfo, AdminUserQuery, ConnectionCount, Role, UserLimits},
+    state::AppState,
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/users", get(list_users))
+        .route("/users/{id}", get(get_user))
+        .route("/users/{id}/disable", post(disable_user))
+        .route("/users/{id}/enable", post(enable_user))
+        .route("/users/{id}/logout", post(logout_user))
+        .route("/users/{id}/limits", put(set_limits))
+        .route("/connections", get(list_connections))
+}
+
+struct UserRow {
+    id: Uuid,
+    email: String,
+    first_name: String,
+    last_name: String,
+    role: String,
+    email_verified_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+    disabled_at: Option<DateTime<Utc>>,
+    max_messages_per_minute: Option<i32>,
+    max_connections: Option<i32>,
+    storage_quota_bytes: Option<i64>,
+}
+
+impl UserRow {
+    fn into_info(self, state: &AppState) -> AdminUserInfo {
+        AdminUserInfo {
+            connections: state.connection_count(&self.id),
+            id: self.id,
+            email: self.email,
+            first_name: self.first_name,
+            last_name: self.last_name,
+            role: Role::parse(&self.role).unwrap_or(Role::User),
+            email_verified: self.email_verified_at.is_some(),
+            created_at: self.created_at,
+            disabled_at: self.disabled_at,
+            limits: UserLimits {
+                max_messages_per_minute: self.max_messages_per_minute,
+                max_connections: self.max_connections,
+                storage_quota_bytes: self.storage_quota_bytes,
+            },
+        }
+    }
+}
+
+async fn list_users(
+    _: AdminUser,
+    State(state): State<AppState>,
+    Query(query): Query<AdminUserQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let pattern = query
+        .q
+        .as_deref()
+        .map(str::trim)
+        .filter(|q| !q.is_empty())
+        .map(|q| format!("%{}%", escape_like(q)));
+
+    let rows = sqlx::query_as!(
+        UserRow,
+        "SELECT id, email, first_name, last_name, role, email_verified_at, created_at,
+                disabled_at, max_messages_per_minute, max_connections, storage_quota_bytes
+         FROM users
+         WHERE $1::TEXT IS NULL OR email ILIKE $1
+            OR first_name || ' ' || last_name ILIKE $1
+         ORDER BY created_at DESC, id
+         LIMIT $2 OFFSET $3",
+        pattern,
+        limit,
+        offset
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let users: Vec<AdminUserInfo> = rows.into_iter().map(|r| r.into_info(&state)).collect();
+    Ok(Json(users))
+}
+
+async fn get_user(
+    _: AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let row = sqlx::query_as!(
+        UserRow,
+        "SELECT id, email, first_name, last_name, role, email_verified_at, created_at,
+                disabled_at, max_messages_per_minute, max_connections, storage_quota_bytes
+         FROM users WHERE id = $1",
+        id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(user_not_found)?;
+
+    Ok(Json(row.into_info(&state)))
+}
+
+/// Blocks sign-in and ends every session. Access tokens stop working while the account
+/// is disabled but are kept, so re-enabling it doesn't break the user's scripts.
+async fn disable_user(
+    AdminUser { user_id: admin_id }: AdminUser,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    if id == admin_id {
+        return Err(AppError::BadRequest(
+            "You can't disable your own account".into(),
+        ));
+    }
+
+    let mut tx = state.pool.begin().await?;
+    let updated = sqlx::query!(
+        "UPDATE users SET disabled_at = COALESCE(disabled_at, NOW()), updated_at = NOW()
+         WHERE id = $1",
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(user_not_found());
+    }
+    db::revoke_sessions(&mut *tx, id).await?;
+    tx.commit().await?;
+
+    state.notify_credentials_changed(id);
+    let details = json!({ "admin_id": admin_id });
+    audit::record(
+        &state,
+        Some(id),
+        AuditEvent::AccountDisabled,
+        &meta,
+        details,
+    )
+    .await;
+    tracing::info!(user = %id, admin = %admin_id, "account disabled");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn enable_user(
+    AdminUser { user_id: admin_id }: AdminUser,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let updated = sqlx::query!(
+        "UPDATE users SET disabled_at = NULL, updated_at = NOW() WHERE id = $1",
+        id
+    )
+    .execute(&state.pool)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(user_not_found());
+    }
+
+    let details = json!({ "admin_id": admin_id });
+    audit::record(&state, Some(id), AuditEvent::AccountEnabled, &meta, details).await;
+    tracing::info!(user = %id, admin = %admin_id, "account enabled");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Signs the user out everywhere; open sockets close straight away.
+async fn logout_user(
+    AdminUser { user_id: admin_id }: AdminUser,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut tx = state.pool.begin().await?;
+    let exists = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM users WHERE id = $1) AS "exists!""#,
+        id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if !exists {
+        return Err(user_not_found());
+    }
+    let revoked = db::revoke_sessions(&mut *tx, id).await?;
+    tx.commit().await?;
+
+    state.notify_credentials_changed(id);
+    let details = json!({ "admin_id": admin_id, "sessions": revoked });
+    audit::record(
+        &state,
+        Some(id),
+        AuditEvent::SessionsRevoked,
+        &meta,
+        details,
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replaces the user's limit overrides. Connected devices pick the new limits up when
+/// they reconnect; force a logout to apply them at once.
+async fn set_limits(
+    AdminUser { user_id: admin_id }: AdminUser,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(limits): Json<UserLimits>,
+) -> Result<impl IntoResponse, AppError> {
+    let invalid = [limits.max_messages_per_minute, limits.max_connections]
+        .iter()
+        .flatten()
+        .any(|n| *n <= 0)
+        || limits.storage_quota_bytes.is_some_and(|n| n <= 0);
+    if invalid {
+        return Err(AppError::BadRequest("Limits must be positive".into()));
+    }
+
+    let updated = sqlx::query!(
+        "UPDATE users SET max_messages_per_minute = $1, max_connections = $2,
+                storage_quota_bytes = $3, updated_at = NOW()
+         WHERE id = $4",
+        limits.max_messages_per_minute,
+        limits.max_connections,
+        limits.storage_quota_bytes,
+        id
+    )
+    .execute(&state.pool)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(user_not_found());
+    }
+
+    let details = json!({ "admin_id": admin_id, "limits": limits });
+    audit::record(&state, Some(id), AuditEvent::LimitsChanged, &meta, details).await;
+    Ok(Json(limits))
+}
+
+/// Users with open sockets, busiest first.
+async fn list_connections(
+    _: AdminUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut counts: Vec<ConnectionCount> = state
+        .connection_counts()
+        .into_iter()
+        .map(|(user_id, connections)| ConnectionCount {
+            user_id,
+            connections,
+        })
+        .collect();
+    counts.sort_by_key(|c| std::cmp::Reverse(c.connections));
+    Ok(Json(counts))
+}
+
+fn user_not_found() -> AppError {
+    AppError::NotFound("User not found".into())
+}
+
+/// Treats `%` and `_` in a search term literally.
+fn escape_like(term: &str) -> String {
+    term.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
@@ -0,0 +1,113 @@
+use serde_json::Value;
+use sqlx::PgExecutor;
+use uuid::Uuid;
+
+use crate::{middleware::ClientMeta, state::AppState};
+
+const OPERATOR_USER_AGENT: &str = "echo-admin";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    LoginSucceeded,
+    LoginFailed,
+    Registered,
+    TokenRefreshed,
+    DeviceConnected,
+    DevicePaired,
+    DeviceRevoked,
+    PasswordChanged,
+    PasswordReset,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    AccessTokenCreated,
+    AccessTokenRevoked,
+    DataExported,
+    AccountDeleted,
+    AccountDisabled,
+    AccountEnabled,
+    SessionsRevoked,
+    LimitsChanged,
+    RoleChanged,
+}
+
+impl AuditEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::LoginSucceeded => "login_succeeded",
+            Self::LoginFailed => "login_failed",
+            Self::Registered => "registered",
+            Self::TokenRefreshed => "token_refreshed",
+            Self::DeviceConnected => "device_connected",
+            Self::DevicePaired => "device_paired",
+            Self::DeviceRevoked => "device_revoked",
+            Self::PasswordChanged => "password_changed",
+            Self::PasswordReset => "password_reset",
+            Self::TwoFactorEnabled => "two_factor_enabled",
+            Self::TwoFactorDisabled => "two_factor_disabled",
+            Self::AccessTokenCreated => "access_token_created",
+            Self::AccessTokenRevoked => "access_token_revoked",
+            Self::DataExported => "data_exported",
+            Self::AccountDeleted => "account_deleted",
+            Self::AccountDisabled => "account_disabled",
+            Self::AccountEnabled => "account_enabled",
+            Self::SessionsRevoked => "sessions_revoked",
+            Self::LimitsChanged => "limits_changed",
+            Self::RoleChanged => "role_changed",
+        }
+    }
+}
+
+/// Appends an event to `audit_events`.
+///
+/// A failed write is logged rather than returned: users shouldn't be locked out of their
+/// account because the audit table is unavailable.
+pub async fn record(
+    state: &AppState,
+    user_id: Option<Uuid>,
+    event: AuditEvent,
+    meta: &ClientMeta,
+    details: Value,
+) {
+    if let Err(e) = insert(&state.pool, user_id, event, meta, details).await {
+        tracing::error!(
+            event = event.as_str(),
+            "Failed to record audit event: {e:?}"
+        );
+    }
+}
+
+/// Records a change made with the `echo-admin` CLI, as part of the caller's transaction so
+/// the change and its audit row land together. The user agent marks where it came from.
+pub async fn record_operator(
+    executor: impl PgExecutor<'_>,
+    user_id: Uuid,
+    event: AuditEvent,
+    details: Value,
+) -> Result<(), sqlx::Error> {
+    let meta = ClientMeta {
+        ip: None,
+        user_agent: Some(OPERATOR_USER_AGENT.into()),
+    };
+    insert(executor, Some(user_id), event, &meta, details).await
+}
+
+async fn insert(
+    executor: impl PgExecutor<'_>,
+    user_id: Option<Uuid>,
+    event: AuditEvent,
+    meta: &ClientMeta,
+    details: Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO audit_events (user_id, event, ip, user_agent, details)
+         VALUES ($1, $2, $3, $4, $5)",
+        user_id,
+        event.as_str(),
+        meta.ip.map(|ip| ip.to_string()),
+        meta.user_agent,
+        details
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
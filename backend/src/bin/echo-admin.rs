@@ -0,0 +1,282 @@
+//! Operator tasks that would otherwise mean writing SQL against the database by hand.
+//!
+//! Reads `DATABASE_URL` the same way the server does. Changes take effect on a running
+//! server immediately for new requests; open sockets notice revocations within a minute.
+//! Account changes are written to the audit log, so users see them in `/me/activity`.
+
+use anyhow::{bail, Context};
+use backend::{
+    audit::{self, AuditEvent},
+    config::Config,
+    db, token,
+};
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(name = "echo-admin", about = "Manage an Echo server's users and data")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Apply pending database migrations
+    Migrate,
+    /// Create a user with a verified email and print their temporary password
+    CreateUser {
+        email: String,
+        #[arg(long, default_value = "")]
+        first_name: String,
+        #[arg(long, default_value = "")]
+        last_name: String,
+        /// Make the user an administrator
+        #[arg(long)]
+        admin: bool,
+    },
+    /// Block sign-in and revoke every session
+    DisableUser {
+        email: String,
+    },
+    EnableUser {
+        email: String,
+    },
+    /// Change a user's role
+    SetRole {
+        email: String,
+        #[arg(value_parser = ["user", "admin"])]
+        role: String,
+    },
+    /// Replace a user's password with a temporary one and revoke their sessions
+    ResetPassword {
+        email: String,
+    },
+    /// List a user's active sessions
+    Sessions {
+        email: String,
+    },
+    RevokeSession {
+        id: Uuid,
+    },
+    /// Revoke every session a user has
+    RevokeSessions {
+        email: String,
+    },
+    /// Delete stored clipboard history
+    PurgeHistory {
+        /// Only this user's history
+        #[arg(long, required_unless_present = "all", conflicts_with = "all")]
+        email: Option<String>,
+        /// Every user's history
+        #[arg(long)]
+        all: bool,
+    },
+    /// Print row counts
+    Stats,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenvy::dotenv().ok();
+    let cli = Cli::parse();
+
+    let config = Config::from_env();
+    let pool = db::connect(&config.database_url)
+        .await
+        .context("Failed to connect to the database")?;
+
+    match cli.command {
+        Command::Migrate => {
+            db::migrate(&pool).await?;
+            println!("Migrations applied");
+        }
+        Command::CreateUser {
+            email,
+            first_name,
+            last_name,
+            admin,
+        } => {
+            let password = token::generate();
+            let hash = hash(password.clone()).await?;
+            let role = if admin { "admin" } else { "user" };
+            let id = sqlx::query_scalar!(
+                "INSERT INTO users (first_name, last_name, email, password_hash, role, email_verified_at)
+                 VALUES ($1, $2, $3, $4, $5, NOW()) RETURNING id",
+                first_name,
+                last_name,
+                email,
+                hash,
+                role
+            )
+            .fetch_one(&pool)
+            .await
+            .context("Failed to create user (is the email already registered?)")?;
+            println!("Created {role} {email} ({id})");
+            println!("Temporary password: {password}");
+        }
+        Command::DisableUser { email } => {
+            let id = find_user(&pool, &email).await?;
+            let mut tx = pool.begin().await?;
+            sqlx::query!(
+                "UPDATE users SET disabled_at = COALESCE(disabled_at, NOW()), updated_at = NOW()
+                 WHERE id = $1",
+                id
+            )
+            .execute(&mut *tx)
+            .await?;
+            let revoked = db::revoke_sessions(&mut *tx, id).await?;
+            let details = json!({ "sessions": revoked });
+            audit::record_operator(&mut *tx, id, AuditEvent::AccountDisabled, details).await?;
+            tx.commit().await?;
+            println!("Disabled {email} and revoked {revoked} session(s)");
+        }
+        Command::EnableUser { email } => {
+            let id = find_user(&pool, &email).await?;
+            let mut tx = pool.begin().await?;
+            sqlx::query!(
+                "UPDATE users SET disabled_at = NULL, updated_at = NOW() WHERE id = $1",
+                id
+            )
+            .execute(&mut *tx)
+            .await?;
+            audit::record_operator(&mut *tx, id, AuditEvent::AccountEnabled, json!({})).await?;
+            tx.commit().await?;
+            println!("Enabled {email}");
+        }
+        Command::SetRole { email, role } => {
+            let id = find_user(&pool, &email).await?;
+            let mut tx = pool.begin().await?;
+            sqlx::query!(
+                "UPDATE users SET role = $1, updated_at = NOW() WHERE id = $2",
+                role,
+                id
+            )
+            .execute(&mut *tx)
+            .await?;
+            let details = json!({ "role": role });
+            audit::record_operator(&mut *tx, id, AuditEvent::RoleChanged, details).await?;
+            tx.commit().await?;
+            println!("{email} is now {role}");
+        }
+        Command::ResetPassword { email } => {
+            let id = find_user(&pool, &email).await?;
+            let password = token::generate();
+            let hash = hash(password.clone()).await?;
+            let mut tx = pool.begin().await?;
+            sqlx::query!(
+                "UPDATE users SET password_hash = $1, updated_at = NOW() WHERE id = $2",
+                hash,
+                id
+            )
+            .execute(&mut *tx)
+            .await?;
+            let revoked = db::revoke_sessions(&mut *tx, id).await?;
+            let details = json!({ "sessions": revoked });
+            audit::record_operator(&mut *tx, id, AuditEvent::PasswordReset, details).await?;
+            tx.commit().await?;
+            println!("Reset password for {email} and revoked {revoked} session(s)");
+            println!("Temporary password: {password}");
+        }
+        Command::Sessions { email } => {
+            let id = find_user(&pool, &email).await?;
+            let sessions = sqlx::query!(
+                "SELECT id, device_name, created_at, expires_at FROM sessions
+                 WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+                 ORDER BY created_at",
+                id
+            )
+            .fetch_all(&pool)
+            .await?;
+            for s in &sessions {
+                println!(
+                    "{}  {:<20}  created {}  expires {}",
+                    s.id,
+                    s.device_name.as_deref().unwrap_or("-"),
+                    s.created_at.format("%Y-%m-%d %H:%M"),
+                    s.expires_at.format("%Y-%m-%d %H:%M")
+                );
+            }
+            println!("{} active session(s)", sessions.len());
+        }
+        Command::RevokeSession { id } => {
+            let mut tx = pool.begin().await?;
+            let Some(user_id) = sqlx::query_scalar!(
+                "UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL
+                 RETURNING user_id",
+                id
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+            else {
+                bail!("No active session {id}");
+            };
+            let details = json!({ "session_id": id });
+            audit::record_operator(&mut *tx, user_id, AuditEvent::DeviceRevoked, details).await?;
+            tx.commit().await?;
+            println!("Revoked session {id}");
+        }
+        Command::RevokeSessions { email } => {
+            let id = find_user(&pool, &email).await?;
+            let mut tx = pool.begin().await?;
+            let revoked = db::revoke_sessions(&mut *tx, id).await?;
+            let details = json!({ "sessions": revoked });
+            audit::record_operator(&mut *tx, id, AuditEvent::SessionsRevoked, details).await?;
+            tx.commit().await?;
+            println!("Revoked {revoked} session(s) for {email}");
+        }
+        Command::PurgeHistory { email, .. } => {
+            let deleted = match email {
+                Some(email) => {
+                    let id = find_user(&pool, &email).await?;
+                    sqlx::query!("DELETE FROM clips WHERE user_id = $1", id)
+                        .execute(&pool)
+                        .await?
+                }
+                None => sqlx::query!("DELETE FROM clips").execute(&pool).await?,
+            };
+            println!("Deleted {} clip(s)", deleted.rows_affected());
+        }
+        Command::Stats => {
+            let stats = sqlx::query!(
+                r#"SELECT
+                    (SELECT COUNT(*) FROM users) AS "users!",
+                    (SELECT COUNT(*) FROM users WHERE role = 'admin') AS "admins!",
+                    (SELECT COUNT(*) FROM users WHERE disabled_at IS NOT NULL) AS "disabled!",
+                    (SELECT COUNT(*) FROM sessions
+                     WHERE revoked_at IS NULL AND expires_at > NOW()) AS "sessions!",
+                    (SELECT COUNT(*) FROM personal_access_tokens
+                     WHERE revoked_at IS NULL AND (expires_at IS NULL OR expires_at > NOW())) AS "access_tokens!",
+                    (SELECT COUNT(*) FROM clips) AS "clips!",
+                    (SELECT COUNT(*) FROM audit_events
+                     WHERE created_at > NOW() - INTERVAL '1 day') AS "audit_events_24h!""#
+            )
+            .fetch_one(&pool)
+            .await?;
+            println!("users            {}", stats.users);
+            println!("  admins         {}", stats.admins);
+            println!("  disabled       {}", stats.disabled);
+            println!("active sessions  {}", stats.sessions);
+            println!("access tokens    {}", stats.access_tokens);
+            println!("stored clips     {}", stats.clips);
+            println!("audit events/24h {}", stats.audit_events_24h);
+        }
+    }
+
+    Ok(())
+}
+
+async fn find_user(pool: &PgPool, email: &str) -> anyhow::Result<Uuid> {
+    sqlx::query_scalar!("SELECT id FROM users WHERE LOWER(email) = LOWER($1)", email)
+        .fetch_optional(pool)
+        .await?
+        .with_context(|| format!("No user with email {email}"))
+}
+
+async fn hash(password: String) -> anyhow::Result<String> {
+    backend::hash_password(password)
+        .await
+        .map_err(|e| anyhow::anyhow!("{e:?}"))
+}
@@ -0,0 +1,108 @@
+use std::{path::PathBuf, str::FromStr};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub database_url: String,
+    /// HS256 secret. Signs tokens when no `jwt_signing_key` is set; otherwise only verifies
+    /// tokens issued before the switch to asymmetric keys.
+    pub jwt_secret: Option<String>,
+    /// PKCS#8 PEM (Ed25519 or P-256) used to sign new tokens.
+    pub jwt_signing_key: Option<PathBuf>,
+    /// Retired keys whose tokens are still accepted during rotation.
+    pub jwt_verification_keys: Vec<PathBuf>,
+    /// Base URL of the client used to build links in outgoing emails.
+    pub app_url: String,
+    pub mail_from: String,
+    /// When unset, emails are logged (and written to `mail_dir`) instead of sent.
+    pub smtp_url: Option<String>,
+    pub mail_dir: Option<PathBuf>,
+    /// WebAuthn relying party: the domain passkeys are bound to, and the origin clients run on.
+    pub webauthn_rp_id: String,
+    pub webauthn_rp_origin: String,
+    /// Accept `/ws?token=` for old clients. Off by default because URLs leak into logs.
+    pub ws_allow_query_token: bool,
+    /// Take the client address from the right-most `X-Forwarded-For` entry. Only enable
+    /// behind a single reverse proxy that appends it.
+    pub trust_proxy_headers: bool,
+    /// Apply pending migrations on startup. Otherwise the server only checks the schema.
+    pub run_migrations: bool,
+    /// How often expired and out-of-retention clips are swept.
+    pub retention_sweep_secs: u64,
+    /// Larger WebSocket messages close the connection.
+    pub ws_max_frame_bytes: usize,
+    /// Larger clips are rejected with a `clip_too_large` error.
+    pub max_clip_bytes: usize,
+    /// Stored clip content per user, unless an admin sets a different quota.
+    pub storage_quota_bytes: i64,
+    /// OpenID Connect provider for single sign-on. SSO is disabled when unset.
+    pub oidc_issuer: Option<String>,
+    pub oidc_client_id: Option<String>,
+    /// Omit for public clients; PKCE protects the code exchange either way.
+    pub oidc_client_secret: Option<String>,
+    /// Where the provider sends users back to; the client posts the code to `/login/oidc/finish`.
+    pub oidc_redirect_uri: String,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let app_url = std::env::var("APP_URL").unwrap_or_else(|_| "http://localhost:1420".into());
+
+        Self {
+            database_url: std::env::var("DATABASE_URL").expect("DATABASE_URL required"),
+            jwt_secret: std::env::var("JWT_SECRET").ok().filter(|s| !s.is_empty()),
+            jwt_signing_key: std::env::var("JWT_SIGNING_KEY")
+                .ok()
+                .filter(|s| !s.is_empty())
+                .map(PathBuf::from),
+            jwt_verification_keys: std::env::var("JWT_VERIFICATION_KEYS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(PathBuf::from)
+                .collect(),
+            mail_from: std::env::var("MAIL_FROM")
+                .unwrap_or_else(|_| "Echo <no-reply@localhost>".into()),
+            smtp_url: std::env::var("SMTP_URL").ok().filter(|s| !s.is_empty()),
+            mail_dir: std::env::var("MAIL_DIR")
+                .ok()
+                .filter(|s| !s.is_empty())
+                .map(PathBuf::from),
+            webauthn_rp_id: std::env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| "localhost".into()),
+            webauthn_rp_origin: std::env::var("WEBAUTHN_RP_ORIGIN")
+                .unwrap_or_else(|_| app_url.clone()),
+            oidc_issuer: std::env::var("OIDC_ISSUER").ok().filter(|s| !s.is_empty()),
+            oidc_client_id: std::env::var("OIDC_CLIENT_ID")
+                .ok()
+                .filter(|s| !s.is_empty()),
+            oidc_client_secret: std::env::var("OIDC_CLIENT_SECRET")
+                .ok()
+                .filter(|s| !s.is_empty()),
+            oidc_redirect_uri: std::env::var("OIDC_REDIRECT_URI")
+                .unwrap_or_else(|_| format!("{app_url}/oidc/callback")),
+            app_url,
+            ws_allow_query_token: std::env::var("WS_ALLOW_QUERY_TOKEN")
+                .map(|v| matches!(v.as_str(), "1" | "true"))
+                .unwrap_or(false),
+            trust_proxy_headers: std::env::var("TRUST_PROXY_HEADERS")
+                .map(|v| matches!(v.as_str(), "1" | "true"))
+                .unwrap_or(false),
+            run_migrations: std::env::var("RUN_MIGRATIONS")
+                .map(|v| matches!(v.as_str(), "1" | "true"))
+                .unwrap_or(false),
+            retention_sweep_secs: positive("RETENTION_SWEEP_INTERVAL_SECS", 60),
+            ws_max_frame_bytes: positive("WS_MAX_FRAME_BYTES", 1024 * 1024),
+            max_clip_bytes: positive("MAX_CLIP_BYTES", 256 * 1024),
+            storage_quota_bytes: positive("STORAGE_QUOTA_BYTES", 32 * 1024 * 1024),
+        }
+    }
+}
+
+/// A positive number from the environment, or `default` when unset or invalid.
+fn positive<T: FromStr + PartialOrd + Default>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > T::default())
+        .unwrap_or(default)
+}
@@ -0,0 +1,105 @@
+use anyhow::{bail, Context};
+use sqlx::{migrate::Migrator, postgres::PgPoolOptions, PgExecutor, PgPool};
+use uuid::Uuid;
+
+/// `backend/migrations`, compiled into the binary.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Arbitrary, but shared by every replica so only one migrates at a time.
+const MIGRATION_LOCK_KEY: i64 = 0x6563_686f_6d69_6772; // "echomigr"
+
+pub async fn connect(database_url: &str) -> Result<PgPool, sqlx::Error> {
+    PgPoolOptions::new()
+        .max_connections(50)
+        .connect(database_url)
+        .await
+}
+
+/// Signs the user out everywhere; returns how many sessions were still live. Open
+/// sockets close once they recheck their credential.
+pub async fn revoke_sessions(
+    executor: impl PgExecutor<'_>,
+    user_id: Uuid,
+) -> Result<u64, sqlx::Error> {
+    let revoked = sqlx::query!(
+        "UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+        user_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(revoked.rows_affected())
+}
+
+/// Applies pending migrations. Replicas starting together wait on an advisory lock, so
+/// the first one migrates and the rest find nothing left to do.
+pub async fn migrate(pool: &PgPool) -> anyhow::Result<()> {
+    let mut conn = pool.acquire().await?;
+    sqlx::query("SELECT pg_advisory_lock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut *conn)
+        .await
+        .context("Failed to take the migration lock")?;
+
+    let result = async {
+        check_not_newer(&mut conn).await?;
+        MIGRATOR.run(&mut *conn).await.context("Migration failed")
+    }
+    .await;
+
+    sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut *conn)
+        .await?;
+    result
+}
+
+/// Refuses a schema this binary doesn't know about, and warns about pending migrations
+/// when they aren't run at startup.
+pub async fn check_schema(pool: &PgPool) -> anyhow::Result<()> {
+    let mut conn = pool.acquire().await?;
+    check_not_newer(&mut conn).await?;
+
+    let applied = applied_versions(&mut conn).await?;
+    let pending = MIGRATOR
+        .iter()
+        .filter(|m| !applied.contains(&m.version))
+        .count();
+    if pending > 0 {
+        tracing::warn!(
+            pending,
+            "database has pending migrations; set RUN_MIGRATIONS=true or run `echo-admin migrate`"
+        );
+    }
+    Ok(())
+}
+
+/// A rolled-back deploy would otherwise run against tables it doesn't understand.
+async fn check_not_newer(conn: &mut sqlx::PgConnection) -> anyhow::Result<()> {
+    let known: Vec<i64> = MIGRATOR.iter().map(|m| m.version).collect();
+    let unknown: Vec<i64> = applied_versions(conn)
+        .await?
+        .into_iter()
+        .filter(|v| !known.contains(v))
+        .collect();
+
+    if let Some(newest) = unknown.iter().max() {
+        bail!(
+            "Database schema is newer than this build (migration {newest} is unknown); deploy a newer build"
+        );
+    }
+    Ok(())
+}
+
+async fn applied_versions(conn: &mut sqlx::PgConnection) -> anyhow::Result<Vec<i64>> {
+    let exists: bool = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(&mut *conn)
+        .await?;
+    if !exists {
+        return Ok(Vec::new());
+    }
+
+    let versions = sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(versions)
+}
@@ -8,9 +8,15 @@ use serde::Serialize;
 #[derive(Debug)]
 pub enum AppError {
     Auth(String),
+    BadRequest(String),
+    Forbidden(String),
     Database(sqlx::Error),
     Internal(String),
     Conflict(String),
+    NotFound(String),
+    TooManyRequests(String),
+    PayloadTooLarge(String),
+    InsufficientStorage(String),
 }
 
 #[derive(Serialize)]
@@ -22,6 +28,8 @@ impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let (status, message) = match self {
             Self::Auth(msg) => (StatusCode::UNAUTHORIZED, msg),
+            Self::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            Self::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
             Self::Database(e) => {
                 tracing::error!("Database error: {:?}", e);
                 (StatusCode::INTERNAL_SERVER_ERROR, "Database error".into())
@@ -31,6 +39,10 @@ impl IntoResponse for AppError {
                 (StatusCode::INTERNAL_SERVER_ERROR, "Internal error".into())
             }
             Self::Conflict(msg) => (StatusCode::CONFLICT, msg),
+            Self::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+            Self::TooManyRequests(msg) => (StatusCode::TOO_MANY_REQUESTS, msg),
+            Self::PayloadTooLarge(msg) => (StatusCode::PAYLOAD_TOO_LARGE, msg),
+            Self::InsufficientStorage(msg) => (StatusCode::INSUFFICIENT_STORAGE, msg),
         };
         (status, Json(ErrorBody { error: message })).into_response()
     }
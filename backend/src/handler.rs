@@ -1,163 +1,2814 @@
 use crate::{
+    audit::{self, AuditEvent},
+    db,
     error::AppError,
-    middleware::AuthUser,
-    models::{AuthResponse, Claims, ClipboardMessage, LoginRequest, RegisterRequest, WsQuery},
-    state::AppState,
+    mailer::Email,
+    middleware::{
+        authenticate, is_active, AuthUser, ClientMeta, Credential, SessionUser, ACCESS_TOKEN_PREFIX,
+    },
+    models::{
+        now_millis, AccessTokenInfo, AccountDeletion, AccountExport, ActivityQuery,
+        ApprovePairingRequest, AuditEventInfo, AuthResponse, ChallengeClaims,
+        ChangePasswordRequest, Claims, ClientFrame, ClipKind, ClipboardMessage,
+        CreateAccessTokenRequest, CreatedAccessToken, DeleteHistoryQuery, DeletedClips, DeviceInfo,
+        ErrorCode, EventsQuery, ForgotPasswordRequest, HistoryQuery, LoginRequest, OidcLoginFinish,
+        OidcLoginStart, PairingClaim, PairingCode, PairingCredentials, PairingInfo, PairingPending,
+        PairingStatus, PasskeyInfo, PasskeyLoginChallenge, PasskeyLoginFinish, PasskeyLoginStart,
+        PasskeyRegistrationChallenge, PasskeyRegistrationFinish, PasswordConfirmation,
+        PollPairingRequest, PushClipQuery, PushClipRequest, RecoveryCodes, RedeemPairingRequest,
+        RegisterRequest, ResetPasswordRequest, RetentionPolicy, Scope, SearchQuery, ServerFrame,
+        TotpCodeRequest, TotpEnrollment, TwoFactorChallenge, TwoFactorLoginRequest,
+        UpdateProfileRequest, UserLimits, UserProfile, VerifyEmailRequest, WsQuery, WsTicket,
+        CHALLENGE_AUDIENCE, SESSION_AUDIENCE,
+    },
+    oidc::{IdTokenClaims, PendingLogin},
+    passkey::Ceremony,
+    state::{
+        AppState, HubEvent, PinOutcome, StoreError, Stored, MAX_PINNED_CLIPS, WS_TICKET_EXPIRY_SECS,
+    },
+    token, totp,
 };
 use argon2::{
     password_hash::{rand_core::OsRng, SaltString},
     Argon2, PasswordHasher, PasswordVerifier,
 };
 use axum::{
+    body::Bytes,
     extract::{
-        ws::{Message, WebSocket},
-        Query, State, WebSocketUpgrade,
+        ws::{CloseFrame, Message, WebSocket},
+        Path, Query, State, WebSocketUpgrade,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
     },
-    http::StatusCode,
-    response::IntoResponse,
     Json,
 };
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
 use futures::{SinkExt, StreamExt};
-use jsonwebtoken::{encode, EncodingKey, Header};
-use std::{sync::Arc, time::Duration};
-use tokio::sync::Mutex;
+use jsonwebtoken::Validation;
+use serde_json::json;
+use sqlx::{PgExecutor, Postgres, Transaction};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
 use uuid::Uuid;
+use webauthn_rs::prelude::Passkey;
 
 const JWT_EXPIRY_HOURS: u64 = 24;
 const PING_INTERVAL_SECS: u64 = 30;
+const WS_AUTH_TIMEOUT_SECS: u64 = 5;
+const WS_PROTOCOL: &str = "echo.v1";
+const WS_BEARER_PROTOCOL_PREFIX: &str = "bearer.";
+/// How often open sockets recheck that their credential hasn't been revoked.
+const WS_REVALIDATE_SECS: u64 = 60;
+/// How long before expiry a socket is asked to send fresh credentials.
+const WS_REFRESH_WARNING_SECS: u64 = 60;
+/// Private-use close codes (RFC 6455 §7.4.2).
+const CLOSE_UNAUTHORIZED: u16 = 4001;
+const CLOSE_EXPIRED: u16 = 4002;
+const CLOSE_REVOKED: u16 = 4003;
+const CLOSE_TOO_MANY_CONNECTIONS: u16 = 4004;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+const VERIFY_TOKEN_EXPIRY_HOURS: i64 = 24;
+const RESET_TOKEN_EXPIRY_MINS: i64 = 60;
+const MAX_NAME_LEN: usize = 100;
+const MAX_ACCESS_TOKEN_DAYS: u32 = 3650;
+/// How recent a sign-in must be to delete the account without the password or a code.
+const REAUTH_WINDOW_MINS: i64 = 5;
+const PAIRING_EXPIRY_MINS: i64 = 10;
+const PAIRING_RATE_WINDOW_MINS: i64 = 10;
+const MAX_PAIRINGS_PER_WINDOW: i64 = 5;
+const CHALLENGE_EXPIRY_SECS: u64 = 300;
+const DEFAULT_ACTIVITY_LIMIT: i64 = 50;
+const MAX_ACTIVITY_LIMIT: i64 = 200;
+const DEFAULT_HISTORY_PAGE_SIZE: i64 = 50;
+const MAX_HISTORY_PAGE_SIZE: i64 = 200;
+const DEFAULT_SEARCH_LIMIT: i64 = 20;
+const MAX_SEARCH_LIMIT: i64 = 100;
+const MAX_RETENTION_ITEMS: i32 = 1000;
+const MIN_RETENTION_AGE_SECS: i64 = 60;
+const MAX_CLIP_TTL_SECS: u64 = 7 * 24 * 3600;
+
+type WsSender = Arc<Mutex<futures::stream::SplitSink<WebSocket, Message>>>;
+
+pub async fn login(
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Json(payload): Json<LoginRequest>,
+) -> Result<Response, AppError> {
+    let user = sqlx::query!(
+        "SELECT id, password_hash, disabled_at FROM users WHERE email = $1",
+        payload.email
+    )
+    .fetch_optional(&state.pool)
+    .await?;
+
+    let Some(user) = user else {
+        let details = json!({ "method": "password", "email": payload.email });
+        audit::record(&state, None, AuditEvent::LoginFailed, &meta, details).await;
+        return Err(AppError::Auth("Invalid credentials".into()));
+    };
+
+    if !verify_password(payload.password, user.password_hash).await? {
+        let details = json!({ "method": "password" });
+        audit::record(
+            &state,
+            Some(user.id),
+            AuditEvent::LoginFailed,
+            &meta,
+            details,
+        )
+        .await;
+        return Err(AppError::Auth("Invalid credentials".into()));
+    }
+    if user.disabled_at.is_some() {
+        return Err(account_disabled());
+    }
+
+    if totp_enabled(&state, user.id).await? {
+        return two_factor_challenge(&state, user.id);
+    }
+
+    let token = create_session(&state, user.id).await?;
+    let details = json!({ "method": "password" });
+    audit::record(
+        &state,
+        Some(user.id),
+        AuditEvent::LoginSucceeded,
+        &meta,
+        details,
+    )
+    .await;
+    Ok((StatusCode::OK, Json(AuthResponse { token })).into_response())
+}
+
+/// Second login step: trades a challenge token plus a TOTP or recovery code for a session.
+pub async fn login_two_factor(
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Json(payload): Json<TwoFactorLoginRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut validation = Validation::default();
+    validation.set_required_spec_claims(&["exp", "sub", "aud"]);
+    validation.set_audience(&[CHALLENGE_AUDIENCE]);
+    let claims = state
+        .jwt_keys
+        .decode::<ChallengeClaims>(&payload.challenge_token, validation)
+        .map_err(|_| AppError::Auth("Invalid or expired challenge".into()))?;
+
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::Auth("Invalid or expired challenge".into()))?;
+
+    if !state.second_factor_allowed(&user_id) {
+        return Err(AppError::Auth(
+            "Too many failed attempts, try again later".into(),
+        ));
+    }
+
+    let method = if payload.code.is_some() {
+        "totp"
+    } else {
+        "recovery_code"
+    };
+    let passed = match (payload.code, payload.recovery_code) {
+        (Some(code), _) => check_totp_code(&state, user_id, &code, true).await?,
+        (None, Some(recovery_code)) => {
+            sqlx::query!(
+                "UPDATE recovery_codes SET used_at = NOW()
+                 WHERE id = (
+                     SELECT id FROM recovery_codes
+                     WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+                     LIMIT 1
+                 )",
+                user_id,
+                totp::hash_recovery_code(&recovery_code)
+            )
+            .execute(&state.pool)
+            .await?
+            .rows_affected()
+                == 1
+        }
+        (None, None) => {
+            return Err(AppError::BadRequest(
+                "Either code or recovery_code is required".into(),
+            ))
+        }
+    };
+
+    if !passed {
+        state.record_second_factor_failure(user_id);
+        let details = json!({ "method": method });
+        audit::record(
+            &state,
+            Some(user_id),
+            AuditEvent::LoginFailed,
+            &meta,
+            details,
+        )
+        .await;
+        return Err(AppError::Auth("Invalid code".into()));
+    }
+
+    state.clear_second_factor_failures(&user_id);
+    let token = create_session(&state, user_id).await?;
+    let details = json!({ "method": method });
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::LoginSucceeded,
+        &meta,
+        details,
+    )
+    .await;
+    Ok((StatusCode::OK, Json(AuthResponse { token })))
+}
+
+pub async fn register(
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Json(payload): Json<RegisterRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let hash = hash_password(payload.password).await?;
+
+    let result = sqlx::query!(
+        "INSERT INTO users (first_name, last_name, email, password_hash) VALUES ($1, $2, $3, $4) RETURNING id",
+        payload.first_name,
+        payload.last_name,
+        payload.email,
+        hash
+    )
+    .fetch_one(&state.pool)
+    .await;
+
+    let user_id = match result {
+        Ok(r) => r.id,
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Err(AppError::Conflict("Email already exists".into()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    send_verification_email(&state, user_id, &payload.email).await?;
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::Registered,
+        &meta,
+        json!({}),
+    )
+    .await;
+
+    let token = create_session(&state, user_id).await?;
+    Ok((StatusCode::CREATED, Json(AuthResponse { token })))
+}
+
+pub async fn resend_verification(
+    SessionUser { user_id, .. }: SessionUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = sqlx::query!(
+        "SELECT email, email_verified_at FROM users WHERE id = $1",
+        user_id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    if user.email_verified_at.is_some() {
+        return Err(AppError::Conflict("Email already verified".into()));
+    }
+
+    send_verification_email(&state, user_id, &user.email).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn verify_email(
+    State(state): State<AppState>,
+    Json(payload): Json<VerifyEmailRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut tx = state.pool.begin().await?;
+
+    let user_id = consume_email_token(&mut tx, &payload.token, EmailTokenPurpose::VerifyEmail)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("Invalid or expired token".into()))?;
+
+    sqlx::query!(
+        "UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW()), updated_at = NOW() WHERE id = $1",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn forgot_password(
+    State(state): State<AppState>,
+    Json(payload): Json<ForgotPasswordRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = sqlx::query!("SELECT id FROM users WHERE email = $1", payload.email)
+        .fetch_optional(&state.pool)
+        .await?;
+
+    // Always accept so the endpoint can't be used to discover registered emails.
+    if let Some(user) = user {
+        let token = create_email_token(&state, user.id, EmailTokenPurpose::ResetPassword).await?;
+        state.send_email(Email {
+            to: payload.email,
+            subject: "Reset your Echo password".into(),
+            body: format!(
+                "Someone asked to reset the password for your Echo account.\n\n\
+                 Reset it here (the link expires in {RESET_TOKEN_EXPIRY_MINS} minutes):\n\
+                 {}/reset-password?token={token}\n\n\
+                 If this wasn't you, you can ignore this email.",
+                state.config.app_url
+            ),
+        });
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn reset_password(
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Json(payload): Json<ResetPasswordRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let hash = hash_password(payload.password).await?;
+    let mut tx = state.pool.begin().await?;
+
+    let user_id = consume_email_token(&mut tx, &payload.token, EmailTokenPurpose::ResetPassword)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("Invalid or expired token".into()))?;
+
+    sqlx::query!(
+        "UPDATE users SET password_hash = $1, updated_at = NOW() WHERE id = $2",
+        hash,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // Any other outstanding reset links are now stale.
+    sqlx::query!(
+        "UPDATE email_tokens SET used_at = NOW() WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL",
+        user_id,
+        EmailTokenPurpose::ResetPassword.as_str()
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    db::revoke_sessions(&mut *tx, user_id).await?;
+
+    tx.commit().await?;
+    state.notify_credentials_changed(user_id);
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::PasswordReset,
+        &meta,
+        json!({}),
+    )
+    .await;
+    tracing::info!(user = %user_id, "password reset, sessions revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_me(
+    SessionUser { user_id, .. }: SessionUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(Json(fetch_profile(&state, user_id).await?))
+}
+
+pub async fn update_me(
+    SessionUser { user_id, .. }: SessionUser,
+    State(state): State<AppState>,
+    Json(payload): Json<UpdateProfileRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    for name in [&payload.first_name, &payload.last_name]
+        .into_iter()
+        .flatten()
+    {
+        if name.trim().is_empty() || name.chars().count() > MAX_NAME_LEN {
+            return Err(AppError::BadRequest(format!(
+                "Names must be between 1 and {MAX_NAME_LEN} characters"
+            )));
+        }
+    }
+
+    let current = sqlx::query!(
+        "SELECT email, password_hash FROM users WHERE id = $1",
+        user_id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    let new_email = payload.email.filter(|email| *email != current.email);
+    if new_email.is_some() {
+        let password = payload
+            .current_password
+            .ok_or_else(|| AppError::Auth("Current password required to change email".into()))?;
+        if !verify_password(password, current.password_hash).await? {
+            return Err(AppError::Auth("Invalid credentials".into()));
+        }
+    }
+
+    let result = sqlx::query!(
+        "UPDATE users SET
+            first_name = COALESCE($2, first_name),
+            last_name = COALESCE($3, last_name),
+            email = COALESCE($4, email),
+            email_verified_at = CASE WHEN $4::VARCHAR IS NULL THEN email_verified_at END,
+            updated_at = NOW()
+         WHERE id = $1",
+        user_id,
+        payload.first_name,
+        payload.last_name,
+        new_email
+    )
+    .execute(&state.pool)
+    .await;
+
+    match result {
+        Ok(_) => {}
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Err(AppError::Conflict("Email already exists".into()));
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    if let Some(email) = &new_email {
+        send_verification_email(&state, user_id, email).await?;
+    }
+
+    Ok(Json(fetch_profile(&state, user_id).await?))
+}
+
+pub async fn change_password(
+    SessionUser {
+        user_id,
+        session_id,
+    }: SessionUser,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = sqlx::query!("SELECT password_hash FROM users WHERE id = $1", user_id)
+        .fetch_one(&state.pool)
+        .await?;
+
+    if !verify_password(payload.current_password, user.password_hash).await? {
+        return Err(AppError::Auth("Invalid credentials".into()));
+    }
+
+    let hash = hash_password(payload.new_password).await?;
+    let mut tx = state.pool.begin().await?;
+
+    sqlx::query!(
+        "UPDATE users SET password_hash = $1, updated_at = NOW() WHERE id = $2",
+        hash,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // Keep the caller signed in, but sign out every other device.
+    sqlx::query!(
+        "UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND id <> $2 AND revoked_at IS NULL",
+        user_id,
+        session_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    state.notify_credentials_changed(user_id);
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::PasswordChanged,
+        &meta,
+        json!({}),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Permanently deletes the account. Everything tied to the user row goes with it, and
+/// open sockets are closed when their credential recheck finds the session gone.
+///
+/// SSO accounts have no password anyone knows, so a TOTP code or a fresh sign-in also
+/// confirms it's the owner.
+pub async fn delete_me(
+    SessionUser {
+        user_id,
+        session_id,
+    }: SessionUser,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Json(payload): Json<AccountDeletion>,
+) -> Result<impl IntoResponse, AppError> {
+    let confirmed = match (payload.password, payload.code) {
+        (Some(password), _) => {
+            let hash =
+                sqlx::query_scalar!("SELECT password_hash FROM users WHERE id = $1", user_id)
+                    .fetch_one(&state.pool)
+                    .await?;
+            verify_password(password, hash).await?
+        }
+        (None, Some(code)) => check_totp_code(&state, user_id, &code, true).await?,
+        (None, None) => sqlx::query_scalar!(
+            r#"SELECT EXISTS(
+                   SELECT 1 FROM sessions WHERE id = $1 AND signed_in_at > NOW() - $2 * INTERVAL '1 minute'
+               ) AS "recent!""#,
+            session_id,
+            REAUTH_WINDOW_MINS as f64
+        )
+        .fetch_one(&state.pool)
+        .await?,
+    };
+    if !confirmed {
+        return Err(AppError::Auth(
+            "Confirm with your password, a two-factor code, or by signing in again".into(),
+        ));
+    }
+
+    // Recorded without the user id: the user's own audit rows go with the account.
+    let details = json!({ "user_id": user_id });
+    audit::record(&state, None, AuditEvent::AccountDeleted, &meta, details).await;
+    sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+        .execute(&state.pool)
+        .await?;
+    state.notify_credentials_changed(user_id);
+
+    tracing::info!(user = %user_id, "account deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn export_me(
+    SessionUser {
+        user_id,
+        session_id,
+    }: SessionUser,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let profile = fetch_profile(&state, user_id).await?;
+
+    let devices = sqlx::query_as!(
+        DeviceInfo,
+        r#"SELECT id, device_name AS name, id = $2 AS "current!", FALSE AS "online!",
+                  created_at, expires_at, revoked_at
+           FROM sessions WHERE user_id = $1 ORDER BY created_at"#,
+        user_id,
+        session_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let devices = mark_online(&state, user_id, devices);
+
+    let export = AccountExport {
+        exported_at: Utc::now(),
+        profile,
+        devices,
+        clipboard_history: state.get_history(&user_id, session_id).await?,
+    };
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::DataExported,
+        &meta,
+        json!({}),
+    )
+    .await;
+
+    Ok((
+        [(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"echo-export.json\"",
+        )],
+        Json(export),
+    ))
+}
+
+/// Signed-in devices: every active session, including ones added through pairing.
+pub async fn list_devices(
+    SessionUser {
+        user_id,
+        session_id,
+    }: SessionUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let devices = sqlx::query_as!(
+        DeviceInfo,
+        r#"SELECT id, device_name AS name, id = $2 AS "current!", FALSE AS "online!",
+                  created_at, expires_at, revoked_at
+           FROM sessions
+           WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+           ORDER BY created_at"#,
+        user_id,
+        session_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Json(mark_online(&state, user_id, devices)))
+}
+
+fn mark_online(state: &AppState, user_id: Uuid, mut devices: Vec<DeviceInfo>) -> Vec<DeviceInfo> {
+    for device in &mut devices {
+        device.online = state.is_online(user_id, device.id);
+    }
+    devices
+}
+
+pub async fn revoke_device(
+    SessionUser { user_id, .. }: SessionUser,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let revoked = sqlx::query_scalar!(
+        "UPDATE sessions SET revoked_at = NOW()
+         WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL RETURNING device_name",
+        id,
+        user_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Device not found".into()))?;
+
+    state.notify_credentials_changed(user_id);
+    let details = json!({ "session_id": id, "device_name": revoked });
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::DeviceRevoked,
+        &meta,
+        details,
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_activity(
+    SessionUser { user_id, .. }: SessionUser,
+    State(state): State<AppState>,
+    Query(query): Query<ActivityQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_ACTIVITY_LIMIT)
+        .clamp(1, MAX_ACTIVITY_LIMIT);
+
+    let events = sqlx::query_as!(
+        AuditEventInfo,
+        "SELECT id, event, ip, user_agent, details, created_at FROM audit_events
+         WHERE user_id = $1 AND ($2::BIGINT IS NULL OR id < $2)
+         ORDER BY id DESC LIMIT $3",
+        user_id,
+        query.before,
+        limit
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Json(events))
+}
+
+/// Starts (or restarts) TOTP enrollment; 2FA stays off until a code is confirmed.
+pub async fn enroll_totp(
+    SessionUser { user_id, .. }: SessionUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    if totp_enabled(&state, user_id).await? {
+        return Err(AppError::Conflict(
+            "Two-factor authentication already enabled".into(),
+        ));
+    }
+
+    let email = sqlx::query_scalar!("SELECT email FROM users WHERE id = $1", user_id)
+        .fetch_one(&state.pool)
+        .await?;
+
+    let secret = totp::generate_secret();
+    let (secret_base32, otpauth_uri) = totp::provisioning(&secret, &email)?;
+
+    sqlx::query!(
+        "INSERT INTO user_totp (user_id, secret) VALUES ($1, $2)
+         ON CONFLICT (user_id) DO UPDATE SET secret = EXCLUDED.secret, last_used_step = NULL, created_at = NOW()",
+        user_id,
+        secret
+    )
+    .execute(&state.pool)
+    .await?;
+
+    Ok(Json(TotpEnrollment {
+        secret: secret_base32,
+        otpauth_uri,
+    }))
+}
+
+/// Confirms enrollment with a first code and returns one-time recovery codes.
+pub async fn confirm_totp(
+    SessionUser { user_id, .. }: SessionUser,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Json(payload): Json<TotpCodeRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if !check_totp_code(&state, user_id, &payload.code, false).await? {
+        return Err(AppError::Auth("Invalid code".into()));
+    }
+
+    let codes: Vec<String> = (0..totp::RECOVERY_CODE_COUNT)
+        .map(|_| totp::generate_recovery_code())
+        .collect();
+    let hashes: Vec<String> = codes.iter().map(|c| totp::hash_recovery_code(c)).collect();
+
+    let mut tx = state.pool.begin().await?;
+
+    sqlx::query!(
+        "UPDATE user_totp SET enabled_at = NOW() WHERE user_id = $1",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!(
+        "INSERT INTO recovery_codes (user_id, code_hash) SELECT $1, * FROM UNNEST($2::VARCHAR[])",
+        user_id,
+        &hashes
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::TwoFactorEnabled,
+        &meta,
+        json!({}),
+    )
+    .await;
+    tracing::info!(user = %user_id, "two-factor authentication enabled");
+    Ok(Json(RecoveryCodes {
+        recovery_codes: codes,
+    }))
+}
+
+pub async fn disable_totp(
+    SessionUser { user_id, .. }: SessionUser,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Json(payload): Json<PasswordConfirmation>,
+) -> Result<impl IntoResponse, AppError> {
+    let hash = sqlx::query_scalar!("SELECT password_hash FROM users WHERE id = $1", user_id)
+        .fetch_one(&state.pool)
+        .await?;
+
+    if !verify_password(payload.password, hash).await? {
+        return Err(AppError::Auth("Invalid credentials".into()));
+    }
+
+    let mut tx = state.pool.begin().await?;
+    sqlx::query!("DELETE FROM user_totp WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::TwoFactorDisabled,
+        &meta,
+        json!({}),
+    )
+    .await;
+    tracing::info!(user = %user_id, "two-factor authentication disabled");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn start_passkey_registration(
+    SessionUser { user_id, .. }: SessionUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = sqlx::query!(
+        "SELECT email, first_name, last_name FROM users WHERE id = $1",
+        user_id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    let existing = load_passkeys(&state, user_id).await?;
+    let exclude = existing.iter().map(|pk| pk.cred_id().clone()).collect();
+
+    let (options, reg_state) = state
+        .webauthn
+        .start_passkey_registration(
+            user_id,
+            &user.email,
+            &format!("{} {}", user.first_name, user.last_name),
+            Some(exclude),
+        )
+        .map_err(|e| AppError::Internal(format!("Passkey registration failed: {e}")))?;
+
+    let ceremony_id = state.start_passkey_ceremony(Ceremony::Registration {
+        user_id,
+        state: reg_state,
+    });
+
+    Ok(Json(PasskeyRegistrationChallenge {
+        ceremony_id,
+        options,
+    }))
+}
+
+pub async fn finish_passkey_registration(
+    SessionUser { user_id, .. }: SessionUser,
+    State(state): State<AppState>,
+    Json(payload): Json<PasskeyRegistrationFinish>,
+) -> Result<impl IntoResponse, AppError> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(AppError::BadRequest(format!(
+            "Passkey name must be between 1 and {MAX_NAME_LEN} characters"
+        )));
+    }
+
+    let reg_state = match state.take_passkey_ceremony(&payload.ceremony_id) {
+        Some(Ceremony::Registration {
+            user_id: owner,
+            state,
+        }) if owner == user_id => state,
+        _ => return Err(AppError::BadRequest("Unknown or expired ceremony".into())),
+    };
+
+    let passkey = state
+        .webauthn
+        .finish_passkey_registration(&payload.credential, &reg_state)
+        .map_err(|e| AppError::BadRequest(format!("Passkey registration failed: {e}")))?;
+
+    let serialized =
+        serde_json::to_value(&passkey).map_err(|e| AppError::Internal(e.to_string()))?;
+
+    let result = sqlx::query!(
+        "INSERT INTO passkeys (user_id, credential_id, name, passkey) VALUES ($1, $2, $3, $4)
+         RETURNING id, name, created_at, last_used_at",
+        user_id,
+        passkey.cred_id().to_vec(),
+        name,
+        serialized
+    )
+    .fetch_one(&state.pool)
+    .await;
+
+    let row = match result {
+        Ok(row) => row,
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Err(AppError::Conflict("Passkey already registered".into()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    tracing::info!(user = %user_id, "passkey registered");
+    Ok((
+        StatusCode::CREATED,
+        Json(PasskeyInfo {
+            id: row.id,
+            name: row.name,
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+        }),
+    ))
+}
+
+pub async fn list_passkeys(
+    SessionUser { user_id, .. }: SessionUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let passkeys = sqlx::query_as!(
+        PasskeyInfo,
+        "SELECT id, name, created_at, last_used_at FROM passkeys WHERE user_id = $1 ORDER BY created_at",
+        user_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Json(passkeys))
+}
+
+pub async fn delete_passkey(
+    SessionUser { user_id, .. }: SessionUser,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let deleted = sqlx::query!(
+        "DELETE FROM passkeys WHERE id = $1 AND user_id = $2",
+        id,
+        user_id
+    )
+    .execute(&state.pool)
+    .await?;
+
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::NotFound("Passkey not found".into()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn start_passkey_login(
+    State(state): State<AppState>,
+    Json(payload): Json<PasskeyLoginStart>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = sqlx::query!("SELECT id FROM users WHERE email = $1", payload.email)
+        .fetch_optional(&state.pool)
+        .await?
+        .ok_or_else(|| AppError::Auth("Invalid credentials".into()))?;
+
+    let passkeys = load_passkeys(&state, user.id).await?;
+    if passkeys.is_empty() {
+        return Err(AppError::Auth("Invalid credentials".into()));
+    }
+
+    let (options, auth_state) = state
+        .webauthn
+        .start_passkey_authentication(&passkeys)
+        .map_err(|e| AppError::Internal(format!("Passkey login failed: {e}")))?;
+
+    let ceremony_id = state.start_passkey_ceremony(Ceremony::Authentication {
+        user_id: user.id,
+        state: auth_state,
+    });
+
+    Ok(Json(PasskeyLoginChallenge {
+        ceremony_id,
+        options,
+    }))
+}
+
+/// Passkeys already prove possession and user verification, so this skips the TOTP step.
+pub async fn finish_passkey_login(
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Json(payload): Json<PasskeyLoginFinish>,
+) -> Result<impl IntoResponse, AppError> {
+    let (user_id, auth_state) = match state.take_passkey_ceremony(&payload.ceremony_id) {
+        Some(Ceremony::Authentication { user_id, state }) => (user_id, state),
+        _ => return Err(AppError::BadRequest("Unknown or expired ceremony".into())),
+    };
+
+    let result = state
+        .webauthn
+        .finish_passkey_authentication(&payload.credential, &auth_state)
+        .map_err(|_| AppError::Auth("Invalid credentials".into()))?;
+
+    let row = sqlx::query!(
+        "SELECT id, passkey FROM passkeys WHERE user_id = $1 AND credential_id = $2",
+        user_id,
+        result.cred_id().to_vec()
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(|| AppError::Auth("Invalid credentials".into()))?;
+
+    // Persist the authenticator's new signature counter and backup state.
+    let mut passkey: Passkey =
+        serde_json::from_value(row.passkey).map_err(|e| AppError::Internal(e.to_string()))?;
+    passkey.update_credential(&result);
+    let serialized =
+        serde_json::to_value(&passkey).map_err(|e| AppError::Internal(e.to_string()))?;
+
+    sqlx::query!(
+        "UPDATE passkeys SET passkey = $1, last_used_at = NOW() WHERE id = $2",
+        serialized,
+        row.id
+    )
+    .execute(&state.pool)
+    .await?;
+
+    let token = create_session(&state, user_id).await?;
+    let details = json!({ "method": "passkey" });
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::LoginSucceeded,
+        &meta,
+        details,
+    )
+    .await;
+    Ok((StatusCode::OK, Json(AuthResponse { token })))
+}
+
+pub async fn start_oidc_login(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let oidc = state
+        .oidc
+        .as_ref()
+        .ok_or_else(|| AppError::NotFound("Single sign-on is not configured".into()))?;
+
+    let pending = PendingLogin::start();
+    let authorization_url = oidc.authorization_url(&pending).await?;
+    let login_state = pending.state.clone();
+    state.start_oidc_login(pending);
+
+    Ok(Json(OidcLoginStart {
+        authorization_url,
+        state: login_state,
+    }))
+}
+
+pub async fn finish_oidc_login(
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Json(payload): Json<OidcLoginFinish>,
+) -> Result<Response, AppError> {
+    let oidc = state
+        .oidc
+        .as_ref()
+        .ok_or_else(|| AppError::NotFound("Single sign-on is not configured".into()))?;
+
+    let pending = state
+        .take_oidc_login(&payload.state)
+        .ok_or_else(|| AppError::BadRequest("Unknown or expired login".into()))?;
+
+    let claims = oidc.exchange(&payload.code, &pending).await?;
+    let user_id = find_or_provision_oidc_user(&state, oidc.issuer(), &claims).await?;
+
+    // The provider vouches for the password step only; TOTP is still ours to ask for.
+    if totp_enabled(&state, user_id).await? {
+        return two_factor_challenge(&state, user_id);
+    }
+
+    tracing::info!(user = %user_id, "signed in with single sign-on");
+    let token = create_session(&state, user_id).await?;
+    let details = json!({ "method": "sso", "issuer": oidc.issuer() });
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::LoginSucceeded,
+        &meta,
+        details,
+    )
+    .await;
+    Ok(Json(AuthResponse { token }).into_response())
+}
+
+/// Resolves a provider identity to a user: an already linked account, else an existing
+/// account with the same verified email, else a newly provisioned one.
+async fn find_or_provision_oidc_user(
+    state: &AppState,
+    issuer: &str,
+    claims: &IdTokenClaims,
+) -> Result<Uuid, AppError> {
+    let linked = sqlx::query_scalar!(
+        "UPDATE oidc_identities SET last_login_at = NOW()
+         WHERE issuer = $1 AND subject = $2 RETURNING user_id",
+        issuer,
+        claims.sub
+    )
+    .fetch_optional(&state.pool)
+    .await?;
+    if let Some(user_id) = linked {
+        return Ok(user_id);
+    }
+
+    let email = claims
+        .email
+        .as_deref()
+        .filter(|_| claims.email_verified)
+        .ok_or_else(|| {
+            AppError::Auth("Your identity provider didn't share a verified email".into())
+        })?;
+
+    let existing = sqlx::query!(
+        "SELECT id, email_verified_at FROM users WHERE LOWER(email) = LOWER($1)",
+        email
+    )
+    .fetch_optional(&state.pool)
+    .await?;
+
+    let mut tx = state.pool.begin().await?;
+    let user_id = match existing {
+        // Only link to accounts that proved they own the address, otherwise someone could
+        // pre-register a colleague's email and later share their SSO account.
+        Some(user) if user.email_verified_at.is_none() => {
+            return Err(AppError::Conflict(
+                "An account with this email exists but hasn't been verified. Verify it or sign in with your password first".into(),
+            ));
+        }
+        Some(user) => user.id,
+        None => {
+            // SSO users get an unguessable password; they can set one later via password reset.
+            let hash = hash_password(token::generate()).await?;
+            let (first_name, last_name) = claims.names();
+            let inserted = sqlx::query_scalar!(
+                "INSERT INTO users (first_name, last_name, email, password_hash, email_verified_at)
+                 VALUES ($1, $2, $3, $4, NOW())
+                 ON CONFLICT (email) DO NOTHING RETURNING id",
+                first_name,
+                last_name,
+                email,
+                hash
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            match inserted {
+                Some(id) => id,
+                // A concurrent first login (or registration) took the email; the former
+                // committed its identity link alongside the user.
+                None => {
+                    drop(tx);
+                    return linked_oidc_user(state, issuer, &claims.sub)
+                        .await?
+                        .ok_or_else(|| {
+                            AppError::Conflict("An account with this email already exists".into())
+                        });
+                }
+            }
+        }
+    };
+
+    // A concurrent login for the same identity may have linked it first; theirs wins.
+    let linked = sqlx::query_scalar!(
+        "INSERT INTO oidc_identities (issuer, subject, user_id, email) VALUES ($1, $2, $3, $4)
+         ON CONFLICT (issuer, subject) DO UPDATE SET last_login_at = NOW()
+         RETURNING user_id",
+        issuer,
+        claims.sub,
+        user_id,
+        email
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if linked != user_id {
+        return Ok(linked);
+    }
+
+    tx.commit().await?;
+    Ok(user_id)
+}
+
+async fn linked_oidc_user(
+    state: &AppState,
+    issuer: &str,
+    subject: &str,
+) -> Result<Option<Uuid>, AppError> {
+    let user_id = sqlx::query_scalar!(
+        "SELECT user_id FROM oidc_identities WHERE issuer = $1 AND subject = $2",
+        issuer,
+        subject
+    )
+    .fetch_optional(&state.pool)
+    .await?;
+    Ok(user_id)
+}
+
+pub async fn create_pairing(
+    SessionUser {
+        user_id,
+        session_id,
+    }: SessionUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let recent = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM pairing_requests
+           WHERE user_id = $1 AND created_at > NOW() - make_interval(mins => $2)"#,
+        user_id,
+        PAIRING_RATE_WINDOW_MINS as i32
+    )
+    .fetch_one(&state.pool)
+    .await?;
+    if recent >= MAX_PAIRINGS_PER_WINDOW {
+        return Err(AppError::TooManyRequests(
+            "Too many pairing codes requested, try again later".into(),
+        ));
+    }
+
+    let code = token::generate_code();
+    let expires_at = Utc::now() + chrono::Duration::minutes(PAIRING_EXPIRY_MINS);
+    let id = sqlx::query_scalar!(
+        "INSERT INTO pairing_requests (user_id, requested_by, code_hash, expires_at)
+         VALUES ($1, $2, $3, $4) RETURNING id",
+        user_id,
+        session_id,
+        token::hash_code(&code),
+        expires_at
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(PairingCode {
+            id,
+            code,
+            expires_at,
+        }),
+    ))
+}
+
+/// Called by the new device, which isn't signed in yet.
+pub async fn redeem_pairing(
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Json(payload): Json<RedeemPairingRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let ip = meta.ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    if !state.pairing_allowed(&ip) {
+        return Err(AppError::TooManyRequests(
+            "Too many attempts, try again later".into(),
+        ));
+    }
+
+    let device_name = payload.device_name.trim();
+    if device_name.is_empty() || device_name.chars().count() > MAX_NAME_LEN {
+        return Err(AppError::BadRequest(format!(
+            "Device name must be between 1 and {MAX_NAME_LEN} characters"
+        )));
+    }
+
+    let poll_token = token::generate();
+    let claimed = sqlx::query!(
+        "UPDATE pairing_requests
+         SET claimed_at = NOW(), poll_token_hash = $2, device_name = $3, device_public_key = $4
+         WHERE code_hash = $1 AND claimed_at IS NULL AND expires_at > NOW()
+         RETURNING id, expires_at",
+        token::hash_code(&payload.code),
+        token::hash(&poll_token),
+        device_name,
+        payload.public_key
+    )
+    .fetch_optional(&state.pool)
+    .await?;
+
+    let Some(claimed) = claimed else {
+        state.record_pairing_failure(ip);
+        return Err(AppError::BadRequest("Invalid or expired code".into()));
+    };
+
+    Ok(Json(PairingClaim {
+        id: claimed.id,
+        poll_token,
+        expires_at: claimed.expires_at,
+    }))
+}
+
+pub async fn get_pairing(
+    SessionUser { user_id, .. }: SessionUser,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let row = sqlx::query!(
+        "SELECT id, device_name, device_public_key, created_at, expires_at,
+                claimed_at, approved_at, rejected_at, completed_at
+         FROM pairing_requests WHERE id = $1 AND user_id = $2",
+        id,
+        user_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Pairing request not found".into()))?;
+
+    let status = pairing_status(
+        row.expires_at,
+        row.claimed_at,
+        row.approved_at,
+        row.rejected_at,
+        row.completed_at,
+    );
+    Ok(Json(PairingInfo {
+        id: row.id,
+        status,
+        device_name: row.device_name,
+        device_public_key: row.device_public_key,
+        created_at: row.created_at,
+        expires_at: row.expires_at,
+    }))
+}
+
+pub async fn approve_pairing(
+    SessionUser { session_id, .. }: SessionUser,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<ApprovePairingRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let approved = sqlx::query!(
+        "UPDATE pairing_requests SET approved_at = NOW(), sealed_key = $3
+         WHERE id = $1 AND requested_by = $2 AND claimed_at IS NOT NULL
+           AND approved_at IS NULL AND rejected_at IS NULL AND expires_at > NOW()",
+        id,
+        session_id,
+        payload.sealed_key
+    )
+    .execute(&state.pool)
+    .await?;
+
+    if approved.rows_affected() == 0 {
+        return Err(AppError::NotFound(
+            "No pairing request awaiting approval".into(),
+        ));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn reject_pairing(
+    SessionUser { session_id, .. }: SessionUser,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let rejected = sqlx::query!(
+        "UPDATE pairing_requests SET rejected_at = NOW()
+         WHERE id = $1 AND requested_by = $2 AND approved_at IS NULL AND rejected_at IS NULL",
+        id,
+        session_id
+    )
+    .execute(&state.pool)
+    .await?;
+
+    if rejected.rows_affected() == 0 {
+        return Err(AppError::NotFound("No pending pairing request".into()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Polled by the new device. Returns 202 until the request is approved, then hands out
+/// the device's own session exactly once.
+pub async fn poll_pairing(
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<PollPairingRequest>,
+) -> Result<Response, AppError> {
+    let row = sqlx::query!(
+        "SELECT user_id, device_name, sealed_key, expires_at,
+                claimed_at, approved_at, rejected_at, completed_at
+         FROM pairing_requests WHERE id = $1 AND poll_token_hash = $2",
+        id,
+        token::hash(&payload.poll_token)
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Pairing request not found".into()))?;
+
+    let status = pairing_status(
+        row.expires_at,
+        row.claimed_at,
+        row.approved_at,
+        row.rejected_at,
+        row.completed_at,
+    );
+    match status {
+        PairingStatus::Approved => {}
+        PairingStatus::Pending | PairingStatus::Claimed => {
+            return Ok((StatusCode::ACCEPTED, Json(PairingPending { status })).into_response());
+        }
+        PairingStatus::Rejected => {
+            return Err(AppError::Forbidden("Pairing was rejected".into()));
+        }
+        PairingStatus::Completed | PairingStatus::Expired => {
+            return Err(AppError::BadRequest("Pairing request has expired".into()));
+        }
+    }
+
+    // Mint the session and mark the request completed together: a failed insert leaves the
+    // request approved for another poll, and of two concurrent polls only one commits. The
+    // update re-checks approval and expiry, since either can change after the read above.
+    let mut tx = state.pool.begin().await?;
+    let (session_id, token) = create_device_session(
+        &state,
+        &mut *tx,
+        row.user_id,
+        row.device_name.as_deref(),
+        None,
+    )
+    .await?;
+    let completed = sqlx::query!(
+        "UPDATE pairing_requests SET completed_at = NOW(), paired_session_id = $1
+         WHERE id = $2 AND completed_at IS NULL AND approved_at IS NOT NULL
+           AND rejected_at IS NULL AND expires_at > NOW()",
+        session_id,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if completed.rows_affected() == 0 {
+        let rejected = sqlx::query_scalar!(
+            r#"SELECT rejected_at IS NOT NULL AS "rejected!" FROM pairing_requests WHERE id = $1"#,
+            id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        return Err(if rejected {
+            AppError::Forbidden("Pairing was rejected".into())
+        } else {
+            AppError::BadRequest("Pairing request has expired".into())
+        });
+    }
+    tx.commit().await?;
+
+    tracing::info!(user = %row.user_id, session = %session_id, "device paired");
+    let details = json!({ "session_id": session_id, "device_name": row.device_name });
+    audit::record(
+        &state,
+        Some(row.user_id),
+        AuditEvent::DevicePaired,
+        &meta,
+        details,
+    )
+    .await;
+    Ok(Json(PairingCredentials {
+        token,
+        sealed_key: row.sealed_key,
+    })
+    .into_response())
+}
+
+fn pairing_status(
+    expires_at: DateTime<Utc>,
+    claimed_at: Option<DateTime<Utc>>,
+    approved_at: Option<DateTime<Utc>>,
+    rejected_at: Option<DateTime<Utc>>,
+    completed_at: Option<DateTime<Utc>>,
+) -> PairingStatus {
+    if completed_at.is_some() {
+        PairingStatus::Completed
+    } else if rejected_at.is_some() {
+        PairingStatus::Rejected
+    } else if expires_at <= Utc::now() {
+        PairingStatus::Expired
+    } else if approved_at.is_some() {
+        PairingStatus::Approved
+    } else if claimed_at.is_some() {
+        PairingStatus::Claimed
+    } else {
+        PairingStatus::Pending
+    }
+}
+
+pub async fn list_access_tokens(
+    SessionUser { user_id, .. }: SessionUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let rows = sqlx::query!(
+        "SELECT id, name, scopes, created_at, expires_at, last_used_at FROM personal_access_tokens
+         WHERE user_id = $1 AND revoked_at IS NULL ORDER BY created_at",
+        user_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let tokens: Vec<AccessTokenInfo> = rows
+        .into_iter()
+        .map(|r| AccessTokenInfo {
+            id: r.id,
+            name: r.name,
+            scopes: r.scopes.iter().filter_map(|s| Scope::parse(s)).collect(),
+            created_at: r.created_at,
+            expires_at: r.expires_at,
+            last_used_at: r.last_used_at,
+        })
+        .collect();
+
+    Ok(Json(tokens))
+}
+
+pub async fn create_access_token(
+    SessionUser { user_id, .. }: SessionUser,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Json(payload): Json<CreateAccessTokenRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(AppError::BadRequest(format!(
+            "Token name must be between 1 and {MAX_NAME_LEN} characters"
+        )));
+    }
+    if payload.scopes.is_empty() {
+        return Err(AppError::BadRequest(
+            "At least one scope is required".into(),
+        ));
+    }
+
+    let mut scopes: Vec<Scope> = Vec::new();
+    for scope in payload.scopes {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    let scope_names: Vec<String> = scopes.iter().map(|s| s.as_str().to_string()).collect();
+    let expires_at = match payload.expires_in_days {
+        Some(days) if days > MAX_ACCESS_TOKEN_DAYS => {
+            return Err(AppError::BadRequest(format!(
+                "Tokens can last at most {MAX_ACCESS_TOKEN_DAYS} days"
+            )));
+        }
+        Some(days) => Some(
+            Utc::now()
+                .checked_add_signed(chrono::Duration::days(days.into()))
+                .ok_or_else(|| AppError::BadRequest("Invalid token lifetime".into()))?,
+        ),
+        None => None,
+    };
+
+    let token = format!("{ACCESS_TOKEN_PREFIX}{}", token::generate());
+
+    let row = sqlx::query!(
+        "INSERT INTO personal_access_tokens (user_id, name, token_hash, scopes, expires_at)
+         VALUES ($1, $2, $3, $4, $5)
+         RETURNING id, created_at",
+        user_id,
+        name,
+        token::hash(&token),
+        &scope_names,
+        expires_at
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    tracing::info!(user = %user_id, token = %row.id, "personal access token created");
+    let details = json!({ "token_id": row.id, "name": name, "scopes": scope_names });
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::AccessTokenCreated,
+        &meta,
+        details,
+    )
+    .await;
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedAccessToken {
+            token,
+            info: AccessTokenInfo {
+                id: row.id,
+                name: name.to_string(),
+                scopes,
+                created_at: row.created_at,
+                expires_at,
+                last_used_at: None,
+            },
+        }),
+    ))
+}
+
+pub async fn revoke_access_token(
+    SessionUser { user_id, .. }: SessionUser,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let revoked = sqlx::query!(
+        "UPDATE personal_access_tokens SET revoked_at = NOW()
+         WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+        id,
+        user_id
+    )
+    .execute(&state.pool)
+    .await?;
+
+    if revoked.rows_affected() == 0 {
+        return Err(AppError::NotFound("Token not found".into()));
+    }
+    state.notify_credentials_changed(user_id);
+    let details = json!({ "token_id": id });
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::AccessTokenRevoked,
+        &meta,
+        details,
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn load_passkeys(state: &AppState, user_id: Uuid) -> Result<Vec<Passkey>, AppError> {
+    let rows = sqlx::query_scalar!("SELECT passkey FROM passkeys WHERE user_id = $1", user_id)
+        .fetch_all(&state.pool)
+        .await?;
+
+    rows.into_iter()
+        .map(|value| serde_json::from_value(value).map_err(|e| AppError::Internal(e.to_string())))
+        .collect()
+}
+
+/// Verifies a TOTP code against the user's secret and records its step to block replays.
+async fn check_totp_code(
+    state: &AppState,
+    user_id: Uuid,
+    code: &str,
+    require_enabled: bool,
+) -> Result<bool, AppError> {
+    let Some(row) = sqlx::query!(
+        "SELECT secret, enabled_at, last_used_step FROM user_totp WHERE user_id = $1",
+        user_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Ok(false);
+    };
+
+    if require_enabled && row.enabled_at.is_none() {
+        return Ok(false);
+    }
+
+    let Some(step) = totp::verify(&row.secret, code, unix_now(), row.last_used_step) else {
+        return Ok(false);
+    };
+
+    // Conditional update so two concurrent requests can't both spend the same code.
+    let updated = sqlx::query!(
+        "UPDATE user_totp SET last_used_step = $2
+         WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)",
+        user_id,
+        step
+    )
+    .execute(&state.pool)
+    .await?;
+
+    Ok(updated.rows_affected() == 1)
+}
+
+async fn totp_enabled(state: &AppState, user_id: Uuid) -> Result<bool, AppError> {
+    let enabled = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM user_totp WHERE user_id = $1 AND enabled_at IS NOT NULL) AS "enabled!""#,
+        user_id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+    Ok(enabled)
+}
+
+/// First-step response for accounts with TOTP: no session yet, just a challenge for `/login/2fa`.
+fn two_factor_challenge(state: &AppState, user_id: Uuid) -> Result<Response, AppError> {
+    let challenge_token = generate_challenge(state, user_id)?;
+    Ok(Json(TwoFactorChallenge {
+        two_factor_required: true,
+        challenge_token,
+        expires_in: CHALLENGE_EXPIRY_SECS,
+    })
+    .into_response())
+}
+
+fn generate_challenge(state: &AppState, user_id: Uuid) -> Result<String, AppError> {
+    let now = unix_now() as usize;
+
+    let claims = ChallengeClaims {
+        sub: user_id.to_string(),
+        aud: CHALLENGE_AUDIENCE.into(),
+        iat: now,
+        exp: now + CHALLENGE_EXPIRY_SECS as usize,
+    };
+
+    state.jwt_keys.encode(&claims)
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+pub(crate) async fn user_limits(state: &AppState, user_id: Uuid) -> Result<UserLimits, AppError> {
+    let limits = sqlx::query_as!(
+        UserLimits,
+        "SELECT max_messages_per_minute, max_connections, storage_quota_bytes
+         FROM users WHERE id = $1",
+        user_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .unwrap_or_default();
+    Ok(limits)
+}
+
+async fn fetch_profile(state: &AppState, user_id: Uuid) -> Result<UserProfile, AppError> {
+    let user = sqlx::query!(
+        "SELECT id, email, first_name, last_name, email_verified_at, created_at, updated_at
+         FROM users WHERE id = $1",
+        user_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(|| AppError::Auth("User no longer exists".into()))?;
+
+    Ok(UserProfile {
+        id: user.id,
+        email: user.email,
+        first_name: user.first_name,
+        last_name: user.last_name,
+        email_verified: user.email_verified_at.is_some(),
+        created_at: user.created_at,
+        updated_at: user.updated_at,
+    })
+}
+
+#[derive(Clone, Copy)]
+enum EmailTokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl EmailTokenPurpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::VerifyEmail => "verify_email",
+            Self::ResetPassword => "reset_password",
+        }
+    }
+
+    fn expiry(self) -> chrono::Duration {
+        match self {
+            Self::VerifyEmail => chrono::Duration::hours(VERIFY_TOKEN_EXPIRY_HOURS),
+            Self::ResetPassword => chrono::Duration::minutes(RESET_TOKEN_EXPIRY_MINS),
+        }
+    }
+}
+
+async fn create_email_token(
+    state: &AppState,
+    user_id: Uuid,
+    purpose: EmailTokenPurpose,
+) -> Result<String, AppError> {
+    let token = token::generate();
+
+    sqlx::query!(
+        "INSERT INTO email_tokens (user_id, purpose, token_hash, expires_at) VALUES ($1, $2, $3, $4)",
+        user_id,
+        purpose.as_str(),
+        token::hash(&token),
+        Utc::now() + purpose.expiry()
+    )
+    .execute(&state.pool)
+    .await?;
+
+    Ok(token)
+}
+
+/// Marks the token used and returns its owner, or `None` if it is unknown, used or expired.
+async fn consume_email_token(
+    tx: &mut Transaction<'_, Postgres>,
+    token: &str,
+    purpose: EmailTokenPurpose,
+) -> Result<Option<Uuid>, AppError> {
+    let row = sqlx::query!(
+        "UPDATE email_tokens SET used_at = NOW()
+         WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW()
+         RETURNING user_id",
+        token::hash(token),
+        purpose.as_str()
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    Ok(row.map(|r| r.user_id))
+}
+
+async fn send_verification_email(
+    state: &AppState,
+    user_id: Uuid,
+    email: &str,
+) -> Result<(), AppError> {
+    let token = create_email_token(state, user_id, EmailTokenPurpose::VerifyEmail).await?;
+    state.send_email(Email {
+        to: email.to_string(),
+        subject: "Verify your Echo email address".into(),
+        body: format!(
+            "Welcome to Echo!\n\n\
+             Confirm your email address here (the link expires in {VERIFY_TOKEN_EXPIRY_HOURS} hours):\n\
+             {}/verify-email?token={token}",
+            state.config.app_url
+        ),
+    });
+    Ok(())
+}
+
+pub async fn hash_password(password: String) -> Result<String, AppError> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    tokio::task::spawn_blocking(move || {
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|h| h.to_string())
+    })
+    .await?
+    .map_err(|e| AppError::Internal(format!("Hash failed: {e}")))
+}
+
+async fn verify_password(password: String, hash: String) -> Result<bool, AppError> {
+    let valid = tokio::task::spawn_blocking(move || {
+        argon2::PasswordHash::new(&hash)
+            .ok()
+            .map(|h| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &h)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    })
+    .await?;
+
+    Ok(valid)
+}
+
+/// A session for a user who just proved who they are.
+async fn create_session(state: &AppState, user_id: Uuid) -> Result<String, AppError> {
+    create_device_session(state, &state.pool, user_id, None, Some(Utc::now()))
+        .await
+        .map(|(_, token)| token)
+}
+
+async fn create_device_session(
+    state: &AppState,
+    executor: impl PgExecutor<'_>,
+    user_id: Uuid,
+    device_name: Option<&str>,
+    signed_in_at: Option<DateTime<Utc>>,
+) -> Result<(Uuid, String), AppError> {
+    // Every sign-in path ends here, so this is where disabled accounts are turned away.
+    let session = sqlx::query!(
+        "INSERT INTO sessions (user_id, expires_at, device_name, signed_in_at)
+         SELECT id, $2, $3, $4 FROM users WHERE id = $1 AND disabled_at IS NULL
+         RETURNING id",
+        user_id,
+        Utc::now() + chrono::Duration::hours(JWT_EXPIRY_HOURS as i64),
+        device_name,
+        signed_in_at
+    )
+    .fetch_optional(executor)
+    .await?
+    .ok_or_else(account_disabled)?;
+
+    let token = generate_jwt(state, user_id, session.id)?;
+    Ok((session.id, token))
+}
+
+/// Issues a fresh token for the caller's session and pushes the session's expiry out,
+/// so long-running clients can stay signed in without the password.
+pub async fn refresh_token(
+    SessionUser {
+        user_id,
+        session_id,
+    }: SessionUser,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let extended = sqlx::query!(
+        "UPDATE sessions SET expires_at = LEAST($1, max_expires_at)
+         WHERE id = $2 AND max_expires_at > NOW()",
+        Utc::now() + chrono::Duration::hours(JWT_EXPIRY_HOURS as i64),
+        session_id
+    )
+    .execute(&state.pool)
+    .await?;
+    if extended.rows_affected() == 0 {
+        return Err(AppError::Auth(
+            "Session has reached its maximum age, please sign in again".into(),
+        ));
+    }
+
+    let token = generate_jwt(&state, user_id, session_id)?;
+    let details = json!({ "session_id": session_id });
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::TokenRefreshed,
+        &meta,
+        details,
+    )
+    .await;
+    Ok(Json(AuthResponse { token }))
+}
+
+fn account_disabled() -> AppError {
+    AppError::Forbidden("This account has been disabled".into())
+}
+
+fn generate_jwt(state: &AppState, user_id: Uuid, session_id: Uuid) -> Result<String, AppError> {
+    let now = unix_now() as usize;
+
+    let claims = Claims {
+        sub: user_id.to_string(),
+        sid: session_id.to_string(),
+        aud: SESSION_AUDIENCE.into(),
+        iat: now,
+        exp: now + (JWT_EXPIRY_HOURS as usize * 3600),
+    };
+
+    state.jwt_keys.encode(&claims)
+}
+
+pub async fn jwks(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.jwt_keys.jwks())
+}
+
+pub async fn protected(AuthUser { user_id, .. }: AuthUser) -> impl IntoResponse {
+    format!("Welcome! Your ID is: {user_id}")
+}
+
+pub async fn get_history(
+    auth: AuthUser,
+    State(state): State<AppState>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::HistoryRead)?;
+
+    if query.cursor.is_some() && query.since.is_some() {
+        return Err(AppError::BadRequest(
+            "Use either cursor or since, not both".into(),
+        ));
+    }
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err(AppError::BadRequest("from must not be after to".into()));
+        }
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_PAGE_SIZE)
+        .clamp(1, MAX_HISTORY_PAGE_SIZE);
+
+    Ok(Json(
+        state
+            .history_page(&auth.user_id, credential_id(&auth), &query, limit)
+            .await?,
+    ))
+}
+
+pub async fn delete_clip(
+    auth: AuthUser,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::ClipsWrite)?;
+
+    if !state.delete_clip(&auth.user_id, id).await? {
+        return Err(AppError::NotFound("Clip not found".into()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_usage(
+    auth: AuthUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::HistoryRead)?;
+    Ok(Json(state.storage_usage(&auth.user_id).await?))
+}
 
-pub async fn login(
+pub async fn get_retention(
+    SessionUser { user_id, .. }: SessionUser,
     State(state): State<AppState>,
-    Json(payload): Json<LoginRequest>,
 ) -> Result<impl IntoResponse, AppError> {
-    let user = sqlx::query!(
-        "SELECT id, password_hash FROM users WHERE email = $1",
-        payload.email
+    let policy = sqlx::query_as!(
+        RetentionPolicy,
+        "SELECT history_max_items AS max_items, history_max_age_secs AS max_age_secs,
+                history_max_bytes AS max_bytes
+         FROM users WHERE id = $1",
+        user_id
     )
-    .fetch_optional(&state.pool)
-    .await?
-    .ok_or_else(|| AppError::Auth("Invalid credentials".into()))?;
+    .fetch_one(&state.pool)
+    .await?;
+    Ok(Json(policy))
+}
 
-    let hash = user.password_hash.clone();
-    let password = payload.password;
+/// Replaces the retention policy. Clips beyond the new item or size limit are dropped at
+/// once; those past a new age limit go at the next sweep.
+pub async fn update_retention(
+    SessionUser { user_id, .. }: SessionUser,
+    State(state): State<AppState>,
+    Json(policy): Json<RetentionPolicy>,
+) -> Result<impl IntoResponse, AppError> {
+    if policy
+        .max_items
+        .is_some_and(|n| !(1..=MAX_RETENTION_ITEMS).contains(&n))
+    {
+        return Err(AppError::BadRequest(format!(
+            "max_items must be between 1 and {MAX_RETENTION_ITEMS}"
+        )));
+    }
+    if policy
+        .max_age_secs
+        .is_some_and(|secs| secs < MIN_RETENTION_AGE_SECS)
+    {
+        return Err(AppError::BadRequest(format!(
+            "max_age_secs must be at least {MIN_RETENTION_AGE_SECS}"
+        )));
+    }
+    if policy.max_bytes.is_some_and(|bytes| bytes <= 0) {
+        return Err(AppError::BadRequest("max_bytes must be positive".into()));
+    }
 
-    let valid = tokio::task::spawn_blocking(move || {
-        argon2::PasswordHash::new(&hash)
-            .ok()
-            .map(|h| {
-                Argon2::default()
-                    .verify_password(password.as_bytes(), &h)
-                    .is_ok()
-            })
-            .unwrap_or(false)
-    })
+    sqlx::query!(
+        "UPDATE users SET history_max_items = $1, history_max_age_secs = $2,
+                history_max_bytes = $3, updated_at = NOW()
+         WHERE id = $4",
+        policy.max_items,
+        policy.max_age_secs,
+        policy.max_bytes,
+        user_id
+    )
+    .execute(&state.pool)
     .await?;
+    state.apply_retention(user_id).await?;
 
-    if !valid {
-        return Err(AppError::Auth("Invalid credentials".into()));
+    Ok(Json(policy))
+}
+
+/// Pinned clips, most recently pinned first.
+pub async fn pinned_history(
+    auth: AuthUser,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::HistoryRead)?;
+    Ok(Json(
+        state
+            .pinned_clips(&auth.user_id, credential_id(&auth))
+            .await?,
+    ))
+}
+
+pub async fn pin_clip(
+    auth: AuthUser,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::ClipsWrite)?;
+    set_pinned(&state, &auth.user_id, id, true).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn unpin_clip(
+    auth: AuthUser,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::ClipsWrite)?;
+    set_pinned(&state, &auth.user_id, id, false).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn set_pinned(
+    state: &AppState,
+    user_id: &Uuid,
+    id: i64,
+    pinned: bool,
+) -> Result<(), AppError> {
+    match state.set_pinned(user_id, id, pinned).await? {
+        PinOutcome::Done => Ok(()),
+        PinOutcome::NotFound => Err(AppError::NotFound("Clip not found".into())),
+        PinOutcome::LimitReached => Err(AppError::Conflict(format!(
+            "You can pin at most {MAX_PINNED_CLIPS} clips"
+        ))),
     }
+}
 
-    let token = generate_jwt(user.id, &state.jwt_secret)?;
-    Ok((StatusCode::OK, Json(AuthResponse { token })))
+/// Deletes the clips matching the filters, or everything with `all=true`. Connected
+/// devices are told which clips went so they can drop them too.
+pub async fn delete_history(
+    auth: AuthUser,
+    State(state): State<AppState>,
+    Query(query): Query<DeleteHistoryQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::ClipsWrite)?;
+
+    if let Some(reason) = invalid_deletion(&query) {
+        return Err(AppError::BadRequest(reason.into()));
+    }
+    let deleted = state.delete_history(&auth.user_id, &query).await?.len();
+    Ok(Json(DeletedClips { deleted }))
 }
 
-pub async fn register(
+/// An empty filter is refused rather than taken to mean everything, so a client bug
+/// can't wipe the history.
+fn invalid_deletion(query: &DeleteHistoryQuery) -> Option<&'static str> {
+    let filtered = query.device.is_some() || query.from.is_some() || query.to.is_some();
+    match (query.all, filtered) {
+        (true, true) => Some("Use either all or filters, not both"),
+        (false, false) => Some("Give a device, from or to, or all=true"),
+        _ => match (query.from, query.to) {
+            (Some(from), Some(to)) if from > to => Some("from must not be after to"),
+            _ => None,
+        },
+    }
+}
+
+/// Full-text search over plaintext history. Every word is matched as a prefix, so
+/// `cur loc` finds "curl localhost".
+pub async fn search_history(
+    auth: AuthUser,
     State(state): State<AppState>,
-    Json(payload): Json<RegisterRequest>,
+    Query(query): Query<SearchQuery>,
 ) -> Result<impl IntoResponse, AppError> {
-    let salt = SaltString::generate(&mut OsRng);
-    let password = payload.password;
+    auth.require(Scope::HistoryRead)?;
 
-    let hash = tokio::task::spawn_blocking(move || {
-        Argon2::default()
-            .hash_password(password.as_bytes(), &salt)
-            .map(|h| h.to_string())
-    })
-    .await?
-    .map_err(|e| AppError::Internal(format!("Hash failed: {e}")))?;
+    if !query.q.chars().any(char::is_alphanumeric) {
+        return Err(AppError::BadRequest(
+            "Search query must contain a word".into(),
+        ));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
 
-    let result = sqlx::query!(
-        "INSERT INTO users (first_name, last_name, email, password_hash) VALUES ($1, $2, $3, $4) RETURNING id",
-        payload.first_name,
-        payload.last_name,
-        payload.email,
-        hash
-    )
-    .fetch_one(&state.pool)
-    .await;
+    Ok(Json(
+        state
+            .search_history(&auth.user_id, credential_id(&auth), &query.q, limit)
+            .await?,
+    ))
+}
 
-    let user_id = match result {
-        Ok(r) => r.id,
-        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
-            return Err(AppError::Conflict("Email already exists".into()));
+/// Sends a clip to the user's devices without holding a socket open. A JSON body has the
+/// same fields as a socket clip; a text body is the clip itself; anything else is stored
+/// as a `binary` clip with base64 content and the body's MIME type.
+pub async fn push_clip(
+    auth: AuthUser,
+    State(state): State<AppState>,
+    Query(query): Query<PushClipQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::ClipsWrite)?;
+    let user_id = auth.user_id;
+    let device_id = credential_id(&auth).to_string();
+
+    let limits = user_limits(&state, user_id).await?;
+    let max_messages = limits
+        .max_messages_per_minute
+        .and_then(|n| u32::try_from(n).ok());
+    if !state.check_rate_limit(&device_id, max_messages) {
+        return Err(AppError::TooManyRequests(
+            "Too many clips; try again shortly".into(),
+        ));
+    }
+
+    let mut msg = clip_from_body(&device_id, &headers, &body)?;
+    msg.ttl_secs = msg.ttl_secs.or(query.ttl_secs);
+    if let (None, Some(targets)) = (&msg.targets, &query.targets) {
+        let targets = targets
+            .split(',')
+            .map(|id| id.trim().parse())
+            .collect::<Result<Vec<Uuid>, _>>()
+            .map_err(|_| AppError::BadRequest("targets must be device ids".into()))?;
+        msg.targets = Some(targets);
+    }
+    let hash = msg.content_hash();
+    match publish_clip(&state, user_id, &device_id, msg).await? {
+        Some(clip) => Ok((StatusCode::CREATED, Json(clip))),
+        None => {
+            let newest = state
+                .clip_by_hash(&user_id, credential_id(&auth), &hash)
+                .await?
+                .ok_or_else(|| AppError::Internal("Repeated clip has gone".into()))?;
+            Ok((StatusCode::OK, Json(newest)))
         }
-        Err(e) => return Err(e.into()),
-    };
+    }
+}
 
-    let token = generate_jwt(user_id, &state.jwt_secret)?;
-    Ok((StatusCode::CREATED, Json(AuthResponse { token })))
+/// The session or access token id that names a device in clip `targets`. HTTP clients
+/// have no socket id, so it's also their device id for rate limits and echo suppression.
+fn credential_id(auth: &AuthUser) -> Uuid {
+    match &auth.credential {
+        Credential::Session(id) | Credential::AccessToken { id, .. } => *id,
+    }
 }
 
-fn generate_jwt(user_id: Uuid, secret: &str) -> Result<String, AppError> {
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as usize;
+fn clip_from_body(
+    device_id: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<ClipboardMessage, AppError> {
+    if body.is_empty() {
+        return Err(AppError::BadRequest("Clip is empty".into()));
+    }
 
-    let claims = Claims {
-        sub: user_id.to_string(),
-        iat: now,
-        exp: now + (JWT_EXPIRY_HOURS as usize * 3600),
-    };
+    let mime_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| "text/plain".into());
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
-    )
-    .map_err(|e| AppError::Internal(e.to_string()))
-}
+    if mime_type == "application/json" {
+        let request: PushClipRequest = serde_json::from_slice(body)
+            .map_err(|e| AppError::BadRequest(format!("Invalid clip: {e}")))?;
+        let mut msg = ClipboardMessage::new(device_id, request.content);
+        msg.encrypted = request.encrypted;
+        msg.nonce = request.nonce;
+        msg.kind = request.kind;
+        msg.mime_type = request.mime_type;
+        msg.ttl_secs = request.ttl_secs;
+        msg.targets = request.targets;
+        return Ok(msg);
+    }
 
-pub async fn protected(AuthUser { user_id }: AuthUser) -> impl IntoResponse {
-    format!("Welcome! Your ID is: {user_id}")
+    // `curl -d` sends form encoding by default, but the body is still the text as typed.
+    if mime_type.starts_with("text/") || mime_type == "application/x-www-form-urlencoded" {
+        let content = String::from_utf8(body.to_vec())
+            .map_err(|_| AppError::BadRequest("Text clips must be UTF-8".into()))?;
+        return Ok(ClipboardMessage::new(device_id, content));
+    }
+
+    let mut msg = ClipboardMessage::new(device_id, STANDARD.encode(body));
+    msg.kind = Some(ClipKind::Binary);
+    msg.mime_type = Some(mime_type);
+    Ok(msg)
 }
 
-pub async fn get_history(
-    AuthUser { user_id }: AuthUser,
+pub async fn create_ws_ticket(
+    auth: AuthUser,
     State(state): State<AppState>,
-) -> impl IntoResponse {
-    Json(state.get_history(&user_id))
+) -> Result<impl IntoResponse, AppError> {
+    auth.require(Scope::ClipsSubscribe)?;
+
+    Ok(Json(WsTicket {
+        ticket: state.issue_ws_ticket(auth),
+        expires_in: WS_TICKET_EXPIRY_SECS,
+    }))
 }
 
+/// Accepts credentials, in order of preference, from the `Authorization` header, a
+/// `bearer.<token>` subprotocol, a ticket, or (if enabled) `?token=`. With none of
+/// these the upgrade still succeeds and the first frame must be an `auth` frame.
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     Query(params): Query<WsQuery>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    headers: HeaderMap,
+    meta: ClientMeta,
+    State(state): State<AppState>,
+) -> Response {
+    let ws = ws
+        .protocols([WS_PROTOCOL])
+        .max_message_size(state.config.ws_max_frame_bytes)
+        .max_frame_size(state.config.ws_max_frame_bytes);
+
+    let header_token = bearer
+        .map(|TypedHeader(Authorization(bearer))| bearer.token().to_string())
+        .or_else(|| subprotocol_token(&headers));
+
+    let auth = if let Some(token) = header_token {
+        authenticate(&state, &token).await.map(Some)
+    } else if let Some(ticket) = &params.ticket {
+        redeem_ticket(&state, ticket).await.map(Some)
+    } else if let Some(token) = &params.token {
+        if state.config.ws_allow_query_token {
+            authenticate(&state, token).await.map(Some)
+        } else {
+            Err(AppError::Auth(
+                "Query string tokens are disabled; use a header or ticket".into(),
+            ))
+        }
+    } else {
+        Ok(None)
+    };
+
+    let auth = match auth {
+        Ok(auth) => auth,
+        Err(e) => return e.into_response(),
+    };
+    if let Some(Err(e)) = auth.as_ref().map(|a| a.require(Scope::ClipsSubscribe)) {
+        return e.into_response();
+    }
+
+    ws.on_upgrade(move |socket| handle_socket(socket, state, auth, meta))
+}
+
+/// What devices are sent for a hub event: the clip itself, or a server frame.
+fn hub_json(event: HubEvent) -> serde_json::Result<String> {
+    match event {
+        HubEvent::Clip(msg) => serde_json::to_string(&msg),
+        HubEvent::Deleted(ids) => serde_json::to_string(&ServerFrame::Deleted { ids }),
+        HubEvent::Pinned { id, pinned: true } => serde_json::to_string(&ServerFrame::Pinned { id }),
+        HubEvent::Pinned { id, pinned: false } => {
+            serde_json::to_string(&ServerFrame::Unpinned { id })
+        }
+        HubEvent::Expired(ids) => serde_json::to_string(&ServerFrame::Expired { ids }),
+        HubEvent::Presence {
+            device_id,
+            online: true,
+        } => serde_json::to_string(&ServerFrame::DeviceOnline { device_id }),
+        HubEvent::Presence {
+            device_id,
+            online: false,
+        } => serde_json::to_string(&ServerFrame::DeviceOffline { device_id }),
+    }
+}
+
+/// Server-sent events for networks that break WebSockets. Streams the same messages a
+/// socket receives; clients send with `POST /clips`. Clips carry their id as the event
+/// id, so reconnecting with `Last-Event-ID` first replays the clips that were missed.
+pub async fn events(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
     State(state): State<AppState>,
-) -> impl IntoResponse {
-    let validation = jsonwebtoken::Validation::default();
-    let key = jsonwebtoken::DecodingKey::from_secret(state.jwt_secret.as_bytes());
+) -> Result<impl IntoResponse, AppError> {
+    let auth = match (bearer, &query.ticket) {
+        (Some(TypedHeader(Authorization(bearer))), _) => {
+            authenticate(&state, bearer.token()).await?
+        }
+        (None, Some(ticket)) => redeem_ticket(&state, ticket).await?,
+        (None, None) => {
+            return Err(AppError::Auth(
+                "Missing Authorization header or ticket".into(),
+            ));
+        }
+    };
+    auth.require(Scope::ClipsSubscribe)?;
+    let user_id = auth.user_id;
+
+    let limits = user_limits(&state, user_id).await?;
+    let max_connections = limits.max_connections.and_then(|n| usize::try_from(n).ok());
+    if max_connections.is_some_and(|max| state.connection_count(&user_id) >= max) {
+        return Err(AppError::TooManyRequests("Too many connections".into()));
+    }
+
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+
+    // Announced before subscribing, so only the user's other devices hear it. Subscribe
+    // before the backlog is read so clips stored in between aren't missed.
+    let device_id = credential_id(&auth);
+    let hub = state.get_or_create_channel(user_id);
+    state.device_connected(user_id, device_id);
+    let rx = hub.subscribe();
+    let (out, events) = mpsc::channel(16);
+    tokio::spawn(async move {
+        if let Err(e) = feed_events(&state, auth, rx, last_event_id, out).await {
+            tracing::error!(user = %user_id, "event stream failed: {e:?}");
+        }
+        state.device_disconnected(user_id, device_id);
+        state.cleanup_channel_if_empty(&user_id, &hub);
+    });
+
+    let stream = futures::stream::unfold(events, |mut events| async move {
+        let event = events.recv().await?;
+        Some((Ok::<_, Infallible>(event), events))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Replays clips after `last_event_id`, then relays the user's events until the client
+/// goes away or its credential expires or is revoked.
+async fn feed_events(
+    state: &AppState,
+    auth: AuthUser,
+    mut rx: broadcast::Receiver<HubEvent>,
+    last_event_id: Option<i64>,
+    out: mpsc::Sender<Event>,
+) -> Result<(), sqlx::Error> {
+    let credential = credential_id(&auth);
+    let device_id = credential.to_string();
+    let mut changes = state.subscribe_credential_changes();
+    let mut recheck = tokio::time::interval(Duration::from_secs(WS_REVALIDATE_SECS));
+    let lapsed = credential_lapse(state, &auth, &mut changes, &mut recheck);
+    tokio::pin!(lapsed);
+
+    let mut replayed = last_event_id.unwrap_or_default();
+    let mut since = last_event_id;
+    while let Some(after) = since {
+        let query = HistoryQuery {
+            since: Some(after),
+            ..Default::default()
+        };
+        let page = state
+            .history_page(&auth.user_id, credential, &query, MAX_HISTORY_PAGE_SIZE)
+            .await?;
+        since = page.next_cursor;
+        for clip in page.items {
+            replayed = replayed.max(clip.id.unwrap_or_default());
+            if clip.device_id == device_id {
+                continue;
+            }
+            if let Some(event) = sse_event(HubEvent::Clip(clip)) {
+                if out.send(event).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    loop {
+        let event = tokio::select! {
+            _ = out.closed() => return Ok(()),
+            _ = &mut lapsed => return Ok(()),
+            // A client that falls behind is dropped, like a socket; it resumes from its
+            // last event id.
+            event = rx.recv() => match event {
+                Ok(event) => event,
+                Err(_) => return Ok(()),
+            },
+        };
+        if let HubEvent::Clip(clip) = &event {
+            let seen = clip.id.is_some_and(|id| id <= replayed);
+            if clip.device_id == device_id || !clip.is_for(credential) || seen {
+                continue;
+            }
+        }
+        if let Some(event) = sse_event(event) {
+            if out.send(event).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn sse_event(event: HubEvent) -> Option<Event> {
+    let id = match &event {
+        HubEvent::Clip(clip) => clip.id,
+        _ => None,
+    };
+    let event = Event::default().data(hub_json(event).ok()?);
+    Some(match id {
+        Some(id) => event.id(id.to_string()),
+        None => event,
+    })
+}
+
+/// Tickets outlive nothing but a few seconds, but the account may have been disabled
+/// (or the credential revoked) since it was issued.
+async fn redeem_ticket(state: &AppState, ticket: &str) -> Result<AuthUser, AppError> {
+    let auth = state
+        .redeem_ws_ticket(ticket)
+        .ok_or_else(|| AppError::Auth("Invalid or expired ticket".into()))?;
+    if !is_active(state, &auth).await? {
+        return Err(AppError::Auth("Invalid or expired ticket".into()));
+    }
+    Ok(auth)
+}
+
+/// Browsers can't set headers on WebSockets, but they can offer subprotocols.
+fn subprotocol_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| protocol.trim().strip_prefix(WS_BEARER_PROTOCOL_PREFIX))
+        .map(str::to_string)
+}
+
+/// Waits briefly for an `auth` frame on a socket that was upgraded without credentials.
+async fn authenticate_first_frame(socket: &mut WebSocket, state: &AppState) -> Option<AuthUser> {
+    let first =
+        tokio::time::timeout(Duration::from_secs(WS_AUTH_TIMEOUT_SECS), socket.recv()).await;
+
+    let result = match first {
+        Ok(Some(Ok(Message::Text(text)))) => match serde_json::from_str::<ClientFrame>(&text) {
+            Ok(ClientFrame::Auth { token }) => authenticate(state, &token)
+                .await
+                .and_then(|auth| auth.require(Scope::ClipsSubscribe).map(|_| auth))
+                .map_err(|_| "invalid credentials"),
+            _ => Err("expected auth frame"),
+        },
+        Ok(_) => Err("expected auth frame"),
+        Err(_) => Err("authentication timed out"),
+    };
+
+    match result {
+        Ok(auth) => {
+            let frame = serde_json::to_string(&authenticated_frame(&auth)).ok()?;
+            socket.send(Message::Text(frame.into())).await.ok()?;
+            Some(auth)
+        }
+        Err(reason) => {
+            let _ = socket.send(close_frame(CLOSE_UNAUTHORIZED, reason)).await;
+            None
+        }
+    }
+}
+
+/// Validates an `auth` frame sent on an open socket. The new credential must belong to
+/// the same user; a socket can't be handed over to another account.
+async fn reauthenticate(
+    state: &AppState,
+    current: &AuthUser,
+    token: &str,
+) -> Result<AuthUser, AppError> {
+    let auth = authenticate(state, token).await?;
+    auth.require(Scope::ClipsSubscribe)?;
+    if auth.user_id != current.user_id {
+        return Err(AppError::Auth("Token belongs to a different user".into()));
+    }
+    Ok(auth)
+}
+
+fn authenticated_frame(auth: &AuthUser) -> ServerFrame {
+    ServerFrame::Authenticated {
+        expires_at: auth.expires_at.map(|exp| exp.timestamp()),
+    }
+}
+
+fn close_frame(code: u16, reason: &'static str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    }))
+}
+
+/// Why a clip from one of the user's devices wasn't relayed.
+struct Rejection {
+    code: ErrorCode,
+    message: String,
+}
+
+impl From<Rejection> for AppError {
+    fn from(Rejection { code, message }: Rejection) -> Self {
+        match code {
+            ErrorCode::ClipTooLarge => AppError::PayloadTooLarge(message),
+            ErrorCode::QuotaExceeded => AppError::InsufficientStorage(message),
+            ErrorCode::UnknownHash => AppError::NotFound(message),
+            ErrorCode::UnknownDevice => AppError::BadRequest(message),
+            ErrorCode::NotStored => AppError::Internal(message),
+        }
+    }
+}
+
+/// Checks, stores and fans out a clip sent by one of the user's devices. Returns the clip
+/// as relayed, or `None` when it repeated the user's newest clip and was dropped.
+async fn publish_clip(
+    state: &AppState,
+    user_id: Uuid,
+    device_id: &str,
+    mut msg: ClipboardMessage,
+) -> Result<Option<ClipboardMessage>, Rejection> {
+    if msg.content.len() > state.config.max_clip_bytes {
+        return Err(Rejection {
+            code: ErrorCode::ClipTooLarge,
+            message: format!("Clips are limited to {} bytes", state.config.max_clip_bytes),
+        });
+    }
+
+    msg.targets = msg
+        .targets
+        .take()
+        .map(|mut targets| {
+            targets.sort();
+            targets.dedup();
+            targets
+        })
+        .filter(|targets| !targets.is_empty());
+    if let Some(targets) = &msg.targets {
+        match state.unknown_targets(&user_id, targets).await {
+            Ok(unknown) if unknown.is_empty() => {}
+            Ok(unknown) => {
+                return Err(Rejection {
+                    code: ErrorCode::UnknownDevice,
+                    message: format!("No signed-in device {}", unknown[0]),
+                });
+            }
+            // Targets only narrow who hears the clip, so it's still safe to send.
+            Err(e) => tracing::error!(user = %user_id, "failed to check targets: {e:?}"),
+        }
+    }
+
+    msg.device_id = device_id.to_string();
+    msg.pinned = false;
+    msg.expires_at = msg
+        .ttl_secs
+        .map(|ttl| now_millis() + ttl.clamp(1, MAX_CLIP_TTL_SECS) * 1000);
+    if msg.kind != Some(ClipKind::Binary) {
+        msg.mime_type = None;
+        if !msg.encrypted {
+            msg.kind = Some(ClipKind::detect(&msg.content));
+        }
+    }
+    msg.hash = Some(msg.content_hash());
+
+    msg.id = match state.add_to_history(user_id, &msg).await {
+        Ok(Stored::New(id)) => Some(id),
+        Ok(Stored::Duplicate(id)) => {
+            tracing::debug!(user = %user_id, id, "dropped repeat of the newest clip");
+            return Ok(None);
+        }
+        Err(StoreError::QuotaExceeded { quota }) => {
+            return Err(Rejection {
+                code: ErrorCode::QuotaExceeded,
+                message: format!("Storing this clip would exceed your {quota} byte quota"),
+            });
+        }
+        // Relaying it anyway would hand out a clip that history, pins and deletes can't
+        // refer to.
+        Err(StoreError::Database(e)) => {
+            tracing::error!(user = %user_id, "failed to store clip: {e:?}");
+            return Err(Rejection {
+                code: ErrorCode::NotStored,
+                message: "The clip couldn't be saved; try again".into(),
+            });
+        }
+    };
+    state.publish(&user_id, HubEvent::Clip(msg.clone()));
+    Ok(Some(msg))
+}
 
-    let claims = match jsonwebtoken::decode::<Claims>(&params.token, &key, &validation) {
-        Ok(data) => data.claims,
-        Err(_) => return (StatusCode::UNAUTHORIZED, "Invalid token").into_response(),
+async fn send_error(sender: &WsSender, Rejection { code, message }: Rejection) {
+    tracing::debug!(?code, "rejected websocket message: {message}");
+    send_frame(sender, &ServerFrame::Error { code, message }).await;
+}
+
+async fn send_frame(sender: &WsSender, frame: &ServerFrame) -> bool {
+    let Ok(json) = serde_json::to_string(frame) else {
+        return false;
     };
+    sender
+        .lock()
+        .await
+        .send(Message::Text(json.into()))
+        .await
+        .is_ok()
+}
+
+/// Converts a wall-clock time into a tokio deadline, clamped to now if it has passed.
+fn deadline(at: DateTime<Utc>) -> tokio::time::Instant {
+    tokio::time::Instant::now() + (at - Utc::now()).to_std().unwrap_or_default()
+}
+
+/// Why a credential stopped being good for a live connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lapse {
+    Expired,
+    Revoked,
+}
+
+/// Resolves once `auth` expires or is found revoked, or with `None` if the server is
+/// shutting down. Revocations made through the API are picked up immediately; anything
+/// else is caught by the periodic `recheck`. Shared by sockets and event streams.
+async fn credential_lapse(
+    state: &AppState,
+    auth: &AuthUser,
+    changes: &mut broadcast::Receiver<Uuid>,
+    recheck: &mut tokio::time::Interval,
+) -> Option<Lapse> {
+    let expires_at = auth.expires_at.map(deadline);
+    loop {
+        let recheck_now = tokio::select! {
+            _ = sleep_until(expires_at) => return Some(Lapse::Expired),
+            _ = recheck.tick() => true,
+            changed = changes.recv() => match changed {
+                Ok(user_id) => user_id == auth.user_id,
+                Err(broadcast::error::RecvError::Lagged(_)) => true,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            },
+        };
+
+        if recheck_now {
+            match is_active(state, auth).await {
+                Ok(true) => {}
+                Ok(false) => return Some(Lapse::Revoked),
+                Err(e) => tracing::warn!(user = %auth.user_id, "credential recheck failed: {e:?}"),
+            }
+        }
+    }
+}
+
+async fn sleep_until(at: Option<tokio::time::Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
 
-    let user_id = match Uuid::parse_str(&claims.sub) {
-        Ok(id) => id,
-        Err(_) => return (StatusCode::UNAUTHORIZED, "Invalid user ID").into_response(),
+/// Closes the socket once its credential lapses, and asks the client for a fresh one
+/// shortly before it expires.
+async fn guard_credentials(state: AppState, sender: WsSender, mut auth: watch::Receiver<AuthUser>) {
+    // Subscribe before the first (immediate) recheck so a revocation racing the
+    // upgrade is never missed.
+    let mut changes = state.subscribe_credential_changes();
+    let mut recheck = tokio::time::interval(Duration::from_secs(WS_REVALIDATE_SECS));
+    let mut warned = false;
+
+    let (code, reason) = loop {
+        let current = auth.borrow_and_update().clone();
+        let warn_at = current
+            .expires_at
+            .map(|exp| deadline(exp) - Duration::from_secs(WS_REFRESH_WARNING_SECS))
+            .filter(|_| !warned);
+
+        tokio::select! {
+            _ = sleep_until(warn_at) => {
+                warned = true;
+                let frame = ServerFrame::Reauthenticate {
+                    expires_at: current.expires_at.map(|e| e.timestamp()).unwrap_or_default(),
+                };
+                if !send_frame(&sender, &frame).await {
+                    return;
+                }
+            }
+            lapse = credential_lapse(&state, &current, &mut changes, &mut recheck) => match lapse {
+                Some(Lapse::Expired) => break (CLOSE_EXPIRED, "credentials expired"),
+                Some(Lapse::Revoked) => break (CLOSE_REVOKED, "credentials revoked"),
+                None => return,
+            },
+            changed = auth.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                warned = false;
+            }
+        }
     };
 
-    ws.on_upgrade(move |socket| handle_socket(socket, state, user_id))
+    tracing::info!(user = %auth.borrow().user_id, reason, "closing websocket");
+    let _ = sender.lock().await.send(close_frame(code, reason)).await;
 }
 
-async fn handle_socket(socket: WebSocket, state: AppState, user_id: Uuid) {
-    let device_id = Uuid::new_v4().to_string();
+async fn handle_socket(
+    mut socket: WebSocket,
+    state: AppState,
+    auth: Option<AuthUser>,
+    meta: ClientMeta,
+) {
+    let auth = match auth {
+        Some(auth) => auth,
+        None => match authenticate_first_frame(&mut socket, &state).await {
+            Some(auth) => auth,
+            None => return,
+        },
+    };
+
+    let user_id = auth.user_id;
+    let credential = match &auth.credential {
+        Credential::Session(session) => {
+            tracing::debug!(user = %user_id, session = %session, "websocket authenticated");
+            json!({ "session_id": session })
+        }
+        Credential::AccessToken { id, .. } => {
+            tracing::debug!(user = %user_id, token = %id, "websocket authenticated");
+            json!({ "token_id": id })
+        }
+    };
+
+    let limits = match user_limits(&state, user_id).await {
+        Ok(limits) => limits,
+        Err(e) => {
+            tracing::warn!(user = %user_id, "failed to load limits: {e:?}");
+            UserLimits::default()
+        }
+    };
+    let max_connections = limits.max_connections.and_then(|n| usize::try_from(n).ok());
+    if max_connections.is_some_and(|max| state.connection_count(&user_id) >= max) {
+        tracing::warn!(user = %user_id, "connection limit reached");
+        let _ = socket
+            .send(close_frame(
+                CLOSE_TOO_MANY_CONNECTIONS,
+                "too many connections",
+            ))
+            .await;
+        return;
+    }
+
+    // The credential names the device, as for HTTP pushes, so history can tell which
+    // targeted clips a socket sent.
+    let device_id = credential_id(&auth).to_string();
     tracing::info!(user = %user_id, device = %device_id, "device connected");
+    let details = json!({ "device_id": device_id, "credential": credential });
+    audit::record(
+        &state,
+        Some(user_id),
+        AuditEvent::DeviceConnected,
+        &meta,
+        details,
+    )
+    .await;
 
     let (sender, receiver) = socket.split();
-    let sender = Arc::new(Mutex::new(sender));
+    let sender: WsSender = Arc::new(Mutex::new(sender));
+    let (auth_tx, auth_rx) = watch::channel(auth);
 
+    // Announced before subscribing, so only the user's other devices hear it.
+    let presence_id = credential_id(&auth_tx.borrow());
     let tx = state.get_or_create_channel(user_id);
+    state.device_connected(user_id, presence_id);
     let mut rx = tx.subscribe();
 
     let ping_sender = Arc::clone(&sender);
@@ -178,13 +2829,16 @@ async fn handle_socket(socket: WebSocket, state: AppState, user_id: Uuid) {
     });
 
     let my_device = device_id.clone();
+    let viewer = auth_tx.subscribe();
     let broadcast_sender = Arc::clone(&sender);
     let send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if msg.device_id == my_device {
-                continue;
-            }
-            if let Ok(json) = serde_json::to_string(&msg) {
+        while let Ok(event) = rx.recv().await {
+            let json = match event {
+                HubEvent::Clip(msg) if msg.device_id == my_device => continue,
+                HubEvent::Clip(msg) if !msg.is_for(credential_id(&viewer.borrow())) => continue,
+                event => hub_json(event),
+            };
+            if let Ok(json) = json {
                 if broadcast_sender
                     .lock()
                     .await
@@ -198,44 +2852,176 @@ async fn handle_socket(socket: WebSocket, state: AppState, user_id: Uuid) {
         }
     });
 
+    let guard_task = tokio::spawn(guard_credentials(
+        state.clone(),
+        Arc::clone(&sender),
+        auth_rx,
+    ));
+
     let recv_task = tokio::spawn(handle_incoming(
         receiver,
-        tx.clone(),
+        sender,
         device_id,
         state.clone(),
-        user_id,
+        auth_tx,
+        limits
+            .max_messages_per_minute
+            .and_then(|n| u32::try_from(n).ok()),
     ));
 
     tokio::select! {
         _ = send_task => {},
         _ = recv_task => {},
         _ = ping_task => {},
+        _ = guard_task => {},
     }
 
+    state.device_disconnected(user_id, presence_id);
     state.cleanup_channel_if_empty(&user_id, &tx);
 }
 
+/// Whether a receive failed because the frame was over `ws_max_frame_bytes`.
+fn is_too_big(error: axum::Error) -> bool {
+    matches!(
+        error.into_inner().downcast_ref::<tungstenite::Error>(),
+        Some(tungstenite::Error::Capacity(
+            tungstenite::error::CapacityError::MessageTooLong { .. }
+        ))
+    )
+}
+
 async fn handle_incoming(
     mut receiver: futures::stream::SplitStream<WebSocket>,
-    tx: tokio::sync::broadcast::Sender<ClipboardMessage>,
+    sender: WsSender,
     device_id: String,
     state: AppState,
-    user_id: Uuid,
+    auth: watch::Sender<AuthUser>,
+    max_messages: Option<u32>,
 ) {
-    while let Some(Ok(msg)) = receiver.next().await {
+    let user_id = auth.borrow().user_id;
+
+    while let Some(msg) = receiver.next().await {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                tracing::debug!(device = %device_id, "websocket receive failed: {e}");
+                let frame = if is_too_big(e) {
+                    close_frame(CLOSE_MESSAGE_TOO_BIG, "message too big")
+                } else {
+                    close_frame(CLOSE_PROTOCOL_ERROR, "protocol error")
+                };
+                let _ = sender.lock().await.send(frame).await;
+                break;
+            }
+        };
         match msg {
             Message::Text(text) => {
-                if !state.check_rate_limit(&device_id) {
+                if let Ok(frame) = serde_json::from_str::<ClientFrame>(&text) {
+                    match frame {
+                        ClientFrame::Auth { token } => {
+                            let current = auth.borrow().clone();
+                            match reauthenticate(&state, &current, &token).await {
+                                Ok(refreshed) => {
+                                    tracing::debug!(device = %device_id, "websocket credentials refreshed");
+                                    send_frame(&sender, &authenticated_frame(&refreshed)).await;
+                                    auth.send_replace(refreshed);
+                                }
+                                Err(_) => {
+                                    let _ = sender
+                                        .lock()
+                                        .await
+                                        .send(close_frame(
+                                            CLOSE_UNAUTHORIZED,
+                                            "invalid credentials",
+                                        ))
+                                        .await;
+                                    break;
+                                }
+                            }
+                        }
+                        ClientFrame::Recopy { hash, targets } => {
+                            if !can_write(&auth, &device_id) {
+                                continue;
+                            }
+                            if !state.check_rate_limit(&device_id, max_messages) {
+                                tracing::warn!(device = %device_id, "rate limited");
+                                continue;
+                            }
+                            let viewer = credential_id(&auth.borrow());
+                            let stored = match state.clip_by_hash(&user_id, viewer, &hash).await {
+                                Ok(stored) => stored,
+                                Err(e) => {
+                                    tracing::error!(user = %user_id, "failed to look up clip: {e:?}");
+                                    continue;
+                                }
+                            };
+                            let result = match stored {
+                                Some(stored) => {
+                                    let clip = ClipboardMessage {
+                                        timestamp: now_millis(),
+                                        ttl_secs: None,
+                                        targets,
+                                        ..stored
+                                    };
+                                    publish_clip(&state, user_id, &device_id, clip).await
+                                }
+                                None => Err(Rejection {
+                                    code: ErrorCode::UnknownHash,
+                                    message: "No stored clip has that hash; send the content"
+                                        .into(),
+                                }),
+                            };
+                            if let Err(rejection) = result {
+                                send_error(&sender, rejection).await;
+                            }
+                        }
+                        ClientFrame::Delete { id } => {
+                            if !can_write(&auth, &device_id) {
+                                continue;
+                            }
+                            if let Err(e) = state.delete_clip(&user_id, id).await {
+                                tracing::error!(user = %user_id, "failed to delete clip: {e:?}");
+                            }
+                        }
+                        ClientFrame::DeleteHistory(query) => {
+                            if !can_write(&auth, &device_id) {
+                                continue;
+                            }
+                            if let Some(reason) = invalid_deletion(&query) {
+                                tracing::warn!(device = %device_id, reason, "ignored delete_history frame");
+                                continue;
+                            }
+                            if let Err(e) = state.delete_history(&user_id, &query).await {
+                                tracing::error!(user = %user_id, "failed to delete history: {e:?}");
+                            }
+                        }
+                        ClientFrame::Pin { id } | ClientFrame::Unpin { id } => {
+                            if !can_write(&auth, &device_id) {
+                                continue;
+                            }
+                            let pinned = matches!(frame, ClientFrame::Pin { .. });
+                            if let Err(e) = set_pinned(&state, &user_id, id, pinned).await {
+                                tracing::warn!(device = %device_id, id, "pin change failed: {e:?}");
+                            }
+                        }
+                    }
+                    continue;
+                }
+                if !can_write(&auth, &device_id) {
+                    continue;
+                }
+                if !state.check_rate_limit(&device_id, max_messages) {
                     tracing::warn!(device = %device_id, "rate limited");
                     continue;
                 }
 
-                let mut clipboard_msg = serde_json::from_str::<ClipboardMessage>(&text)
+                let clipboard_msg = serde_json::from_str::<ClipboardMessage>(&text)
                     .unwrap_or_else(|_| ClipboardMessage::new(&device_id, text.to_string()));
-
-                clipboard_msg.device_id = device_id.clone();
-                state.add_to_history(user_id, clipboard_msg.clone());
-                let _ = tx.send(clipboard_msg);
+                if let Err(rejection) =
+                    publish_clip(&state, user_id, &device_id, clipboard_msg).await
+                {
+                    send_error(&sender, rejection).await;
+                }
             }
             Message::Pong(_) => tracing::debug!(device = %device_id, "pong received"),
             Message::Close(_) => break,
@@ -243,3 +3029,11 @@ async fn handle_incoming(
         }
     }
 }
+
+fn can_write(auth: &watch::Sender<AuthUser>, device_id: &str) -> bool {
+    let allowed = auth.borrow().has_scope(Scope::ClipsWrite);
+    if !allowed {
+        tracing::warn!(device = %device_id, "dropped write from read-only token");
+    }
+    allowed
+}
@@ -0,0 +1,228 @@
+use anyhow::{bail, Context};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{config::Config, error::AppError};
+
+struct VerificationKey {
+    /// `None` only for the legacy HS256 secret, which predates key ids.
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+    /// Public JWK published at `/.well-known/jwks.json`.
+    jwk: Option<Value>,
+}
+
+struct SigningKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: EncodingKey,
+}
+
+/// Signs new tokens with one key and accepts tokens from any configured key,
+/// so signing keys can be rotated without logging everyone out.
+pub struct JwtKeys {
+    signing: SigningKey,
+    verification: Vec<VerificationKey>,
+}
+
+impl JwtKeys {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let read = |path: &std::path::PathBuf| {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read key file {}", path.display()))
+        };
+
+        let signing_pem = config.jwt_signing_key.as_ref().map(read).transpose()?;
+        let verification_pems = config
+            .jwt_verification_keys
+            .iter()
+            .map(read)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Self::new(
+            config.jwt_secret.as_deref(),
+            signing_pem.as_deref(),
+            &verification_pems,
+        )
+    }
+
+    /// `signing_pem` is a PKCS#8 Ed25519 or P-256 private key. Without one, tokens are
+    /// signed with the HS256 `secret`. The secret, if present, is always accepted for
+    /// verification so existing sessions survive the switch to asymmetric keys.
+    pub fn new(
+        secret: Option<&str>,
+        signing_pem: Option<&str>,
+        verification_pems: &[String],
+    ) -> anyhow::Result<Self> {
+        let mut verification = Vec::new();
+
+        let signing = match signing_pem {
+            Some(pem) => {
+                let (signing, verifying) = parse_private_key(pem)?;
+                verification.push(verifying);
+                signing
+            }
+            None => {
+                let Some(secret) = secret else {
+                    bail!("Either JWT_SIGNING_KEY or JWT_SECRET is required");
+                };
+                SigningKey {
+                    kid: None,
+                    algorithm: Algorithm::HS256,
+                    key: EncodingKey::from_secret(secret.as_bytes()),
+                }
+            }
+        };
+
+        for pem in verification_pems {
+            let key = if pem.contains("PRIVATE KEY") {
+                parse_private_key(pem)?.1
+            } else {
+                parse_public_key(pem)?
+            };
+            if !verification.iter().any(|k| k.kid == key.kid) {
+                verification.push(key);
+            }
+        }
+
+        if let Some(secret) = secret {
+            verification.push(VerificationKey {
+                kid: None,
+                algorithm: Algorithm::HS256,
+                key: DecodingKey::from_secret(secret.as_bytes()),
+                jwk: None,
+            });
+        }
+
+        Ok(Self {
+            signing,
+            verification,
+        })
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, AppError> {
+        let mut header = Header::new(self.signing.algorithm);
+        header.kid = self.signing.kid.clone();
+
+        encode(&header, claims, &self.signing.key).map_err(|e| AppError::Internal(e.to_string()))
+    }
+
+    /// Picks the verification key by the token's `kid` and pins its algorithm.
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        mut validation: Validation,
+    ) -> Result<T, jsonwebtoken::errors::Error> {
+        let header = decode_header(token)?;
+        let key = self
+            .verification
+            .iter()
+            .find(|k| k.kid == header.kid)
+            .ok_or(jsonwebtoken::errors::ErrorKind::InvalidSignature)?;
+
+        validation.algorithms = vec![key.algorithm];
+        decode::<T>(token, &key.key, &validation).map(|data| data.claims)
+    }
+
+    pub fn jwks(&self) -> Value {
+        let keys: Vec<&Value> = self
+            .verification
+            .iter()
+            .filter_map(|k| k.jwk.as_ref())
+            .collect();
+        json!({ "keys": keys })
+    }
+}
+
+fn parse_private_key(pem: &str) -> anyhow::Result<(SigningKey, VerificationKey)> {
+    if let Ok(key) = ed25519_dalek::SigningKey::from_pkcs8_pem(pem) {
+        let verifying = ed25519_verification_key(&key.verifying_key())?;
+        let signing = SigningKey {
+            kid: verifying.kid.clone(),
+            algorithm: Algorithm::EdDSA,
+            key: EncodingKey::from_ed_pem(pem.as_bytes())?,
+        };
+        return Ok((signing, verifying));
+    }
+
+    if let Ok(key) = p256::SecretKey::from_pkcs8_pem(pem) {
+        let verifying = es256_verification_key(&key.public_key())?;
+        let signing = SigningKey {
+            kid: verifying.kid.clone(),
+            algorithm: Algorithm::ES256,
+            key: EncodingKey::from_ec_pem(pem.as_bytes())?,
+        };
+        return Ok((signing, verifying));
+    }
+
+    bail!("Unsupported private key: expected a PKCS#8 Ed25519 or P-256 key")
+}
+
+fn parse_public_key(pem: &str) -> anyhow::Result<VerificationKey> {
+    if let Ok(key) = ed25519_dalek::VerifyingKey::from_public_key_pem(pem) {
+        return ed25519_verification_key(&key);
+    }
+    if let Ok(key) = p256::PublicKey::from_public_key_pem(pem) {
+        return es256_verification_key(&key);
+    }
+    bail!("Unsupported public key: expected an Ed25519 or P-256 key")
+}
+
+fn ed25519_verification_key(key: &ed25519_dalek::VerifyingKey) -> anyhow::Result<VerificationKey> {
+    let x = URL_SAFE_NO_PAD.encode(key.as_bytes());
+    let kid = thumbprint(&format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{x}"}}"#));
+
+    Ok(VerificationKey {
+        algorithm: Algorithm::EdDSA,
+        key: DecodingKey::from_ed_components(&x)?,
+        jwk: Some(json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "x": x,
+            "kid": kid,
+            "alg": "EdDSA",
+            "use": "sig",
+        })),
+        kid: Some(kid),
+    })
+}
+
+fn es256_verification_key(key: &p256::PublicKey) -> anyhow::Result<VerificationKey> {
+    let point = key.to_encoded_point(false);
+    let (Some(x), Some(y)) = (point.x(), point.y()) else {
+        bail!("Invalid P-256 public key");
+    };
+    let x = URL_SAFE_NO_PAD.encode(x);
+    let y = URL_SAFE_NO_PAD.encode(y);
+    let kid = thumbprint(&format!(
+        r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#
+    ));
+
+    Ok(VerificationKey {
+        algorithm: Algorithm::ES256,
+        key: DecodingKey::from_ec_components(&x, &y)?,
+        jwk: Some(json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": x,
+            "y": y,
+            "kid": kid,
+            "alg": "ES256",
+            "use": "sig",
+        })),
+        kid: Some(kid),
+    })
+}
+
+/// RFC 7638 JWK thumbprint of the canonical member JSON, used as the `kid`.
+fn thumbprint(canonical: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes()))
+}
@@ -0,0 +1,109 @@
+//! The Echo server as a library, shared by the `backend` server and the `echo-admin`
+//! operator CLI.
+
+mod admin;
+pub mod audit;
+pub mod config;
+pub mod db;
+pub mod error;
+mod handler;
+mod keys;
+mod mailer;
+mod middleware;
+mod models;
+mod oidc;
+mod passkey;
+pub mod retention;
+pub mod state;
+#[cfg(test)]
+mod tests;
+pub mod token;
+mod totp;
+
+use crate::state::AppState;
+use axum::{
+    routing::{delete, get, post, put},
+    Router,
+};
+use tower_http::cors::CorsLayer;
+
+pub use handler::hash_password;
+
+pub fn app(state: AppState) -> Router {
+    Router::new()
+        .route("/health", get(|| async { "OK" }))
+        .route("/.well-known/jwks.json", get(handler::jwks))
+        .route("/register", post(handler::register))
+        .route("/login", post(handler::login))
+        .route("/login/2fa", post(handler::login_two_factor))
+        .route("/login/passkey/start", post(handler::start_passkey_login))
+        .route("/login/passkey/finish", post(handler::finish_passkey_login))
+        .route("/login/oidc/start", post(handler::start_oidc_login))
+        .route("/login/oidc/finish", post(handler::finish_oidc_login))
+        .route("/verify-email", post(handler::verify_email))
+        .route("/verify-email/resend", post(handler::resend_verification))
+        .route("/password/forgot", post(handler::forgot_password))
+        .route("/password/reset", post(handler::reset_password))
+        .route("/token/refresh", post(handler::refresh_token))
+        .route("/ws", get(handler::ws_handler))
+        .route("/ws/ticket", post(handler::create_ws_ticket))
+        .route("/protected", get(handler::protected))
+        .route(
+            "/me",
+            get(handler::get_me)
+                .patch(handler::update_me)
+                .delete(handler::delete_me),
+        )
+        .route("/me/export", get(handler::export_me))
+        .route("/me/activity", get(handler::list_activity))
+        .route("/me/devices", get(handler::list_devices))
+        .route("/me/devices/{id}", delete(handler::revoke_device))
+        .route("/me/password", post(handler::change_password))
+        .route("/me/usage", get(handler::get_usage))
+        .route(
+            "/me/retention",
+            get(handler::get_retention).put(handler::update_retention),
+        )
+        .route(
+            "/me/2fa/totp",
+            post(handler::enroll_totp).delete(handler::disable_totp),
+        )
+        .route("/me/2fa/totp/confirm", post(handler::confirm_totp))
+        .route("/pairing", post(handler::create_pairing))
+        .route("/pairing/redeem", post(handler::redeem_pairing))
+        .route("/pairing/{id}", get(handler::get_pairing))
+        .route("/pairing/{id}/approve", post(handler::approve_pairing))
+        .route("/pairing/{id}/reject", post(handler::reject_pairing))
+        .route("/pairing/{id}/poll", post(handler::poll_pairing))
+        .route(
+            "/me/tokens",
+            get(handler::list_access_tokens).post(handler::create_access_token),
+        )
+        .route("/me/tokens/{id}", delete(handler::revoke_access_token))
+        .route("/me/passkeys", get(handler::list_passkeys))
+        .route("/me/passkeys/{id}", delete(handler::delete_passkey))
+        .route(
+            "/me/passkeys/register/start",
+            post(handler::start_passkey_registration),
+        )
+        .route(
+            "/me/passkeys/register/finish",
+            post(handler::finish_passkey_registration),
+        )
+        .route(
+            "/history",
+            get(handler::get_history).delete(handler::delete_history),
+        )
+        .route("/history/pinned", get(handler::pinned_history))
+        .route("/history/{id}", delete(handler::delete_clip))
+        .route(
+            "/history/{id}/pin",
+            put(handler::pin_clip).delete(handler::unpin_clip),
+        )
+        .route("/history/search", get(handler::search_history))
+        .route("/clips", post(handler::push_clip))
+        .route("/events", get(handler::events))
+        .nest("/admin", admin::router())
+        .layer(CorsLayer::permissive())
+        .with_state(state)
+}
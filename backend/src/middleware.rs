@@ -1,19 +1,66 @@
 use axum::{
-    extract::{FromRef, FromRequestParts},
-    http::request::Parts,
+    extract::{ConnectInfo, FromRef, FromRequestParts},
+    http::{header, request::Parts},
     RequestPartsExt,
 };
 use axum_extra::{
     headers::{authorization::Bearer, Authorization},
     TypedHeader,
 };
-use jsonwebtoken::{decode, DecodingKey, Validation};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::Validation;
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
 use uuid::Uuid;
 
-use crate::{error::AppError, models::Claims, state::AppState};
+use crate::{
+    error::AppError,
+    models::{Claims, Role, Scope, SESSION_AUDIENCE},
+    state::AppState,
+    token,
+};
+
+pub const ACCESS_TOKEN_PREFIX: &str = "echo_pat_";
+
+/// Only bump `last_used_at` this often so every request doesn't write to the database.
+const LAST_USED_RESOLUTION_SECS: i64 = 60;
+const MAX_USER_AGENT_LEN: usize = 512;
+
+#[derive(Clone)]
+pub enum Credential {
+    Session(Uuid),
+    AccessToken { id: Uuid, scopes: Vec<Scope> },
+}
 
+/// Any authenticated caller: a login session or a personal access token.
+#[derive(Clone)]
 pub struct AuthUser {
     pub user_id: Uuid,
+    pub credential: Credential,
+    /// When the credential stops being accepted; `None` for tokens that never expire.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl AuthUser {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        match &self.credential {
+            Credential::Session(_) => true,
+            Credential::AccessToken { scopes, .. } => scopes.contains(&scope),
+        }
+    }
+
+    pub fn require(&self, scope: Scope) -> Result<(), AppError> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden(format!(
+                "Token is missing the {} scope",
+                scope.as_str()
+            )))
+        }
+    }
 }
 
 impl<S> FromRequestParts<S> for AuthUser
@@ -31,16 +78,217 @@ where
             .await
             .map_err(|_| AppError::Auth("Missing or invalid Authorization header".into()))?;
 
-        let token_data = decode::<Claims>(
-            bearer.token(),
-            &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
-            &Validation::default(),
-        )
-        .map_err(|_| AppError::Auth("Invalid or expired token".into()))?;
+        authenticate(&state, bearer.token()).await
+    }
+}
+
+/// A caller signed in with a login session. Account management requires this,
+/// so a leaked access token can't be used to take over the account.
+pub struct SessionUser {
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+}
+
+impl<S> FromRequestParts<S> for SessionUser
+where
+    S: Send + Sync,
+    AppState: FromRef<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let auth = AuthUser::from_request_parts(parts, state).await?;
+
+        match auth.credential {
+            Credential::Session(session_id) => Ok(Self {
+                user_id: auth.user_id,
+                session_id,
+            }),
+            Credential::AccessToken { .. } => Err(AppError::Forbidden(
+                "Access tokens can't be used for this endpoint".into(),
+            )),
+        }
+    }
+}
+
+/// A signed-in administrator. Like `SessionUser`, access tokens are never accepted.
+pub struct AdminUser {
+    pub user_id: Uuid,
+}
+
+impl<S> FromRequestParts<S> for AdminUser
+where
+    S: Send + Sync,
+    AppState: FromRef<S>,
+{
+    type Rejection = AppError;
 
-        let user_id = Uuid::parse_str(&token_data.claims.sub)
-            .map_err(|_| AppError::Auth("Invalid user ID in token".into()))?;
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let SessionUser { user_id, .. } = SessionUser::from_request_parts(parts, state).await?;
+        let state = AppState::from_ref(state);
+
+        let role = sqlx::query_scalar!("SELECT role FROM users WHERE id = $1", user_id)
+            .fetch_one(&state.pool)
+            .await?;
 
+        if role != Role::Admin.as_str() {
+            return Err(AppError::Forbidden("Administrator access required".into()));
+        }
         Ok(Self { user_id })
     }
 }
+
+/// Where a request came from, for the audit log and per-client rate limits.
+#[derive(Debug, Clone, Default)]
+pub struct ClientMeta {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl<S> FromRequestParts<S> for ClientMeta
+where
+    S: Send + Sync,
+    AppState: FromRef<S>,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let state = AppState::from_ref(state);
+
+        // Clients can send their own X-Forwarded-For, so only the right-most entry, the one
+        // appended by our proxy, is trustworthy.
+        let forwarded = state
+            .config
+            .trust_proxy_headers
+            .then(|| parts.headers.get("x-forwarded-for"))
+            .flatten()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+
+        let ip = forwarded.or_else(|| {
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+        });
+
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|ua| ua.chars().take(MAX_USER_AGENT_LEN).collect());
+
+        Ok(Self { ip, user_agent })
+    }
+}
+
+/// Validates a session JWT or a personal access token.
+pub async fn authenticate(state: &AppState, token: &str) -> Result<AuthUser, AppError> {
+    if token.starts_with(ACCESS_TOKEN_PREFIX) {
+        authenticate_access_token(state, token).await
+    } else {
+        authenticate_session(state, token).await
+    }
+}
+
+async fn authenticate_session(state: &AppState, token: &str) -> Result<AuthUser, AppError> {
+    let mut validation = Validation::default();
+    validation.set_required_spec_claims(&["exp", "aud"]);
+    validation.set_audience(&[SESSION_AUDIENCE]);
+    let claims = state
+        .jwt_keys
+        .decode::<Claims>(token, validation)
+        .map_err(|_| AppError::Auth("Invalid or expired token".into()))?;
+
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::Auth("Invalid user ID in token".into()))?;
+    let session_id = Uuid::parse_str(&claims.sid)
+        .map_err(|_| AppError::Auth("Invalid session ID in token".into()))?;
+
+    let active = sqlx::query_scalar!(
+        r#"SELECT EXISTS(
+            SELECT 1 FROM sessions s JOIN users u ON u.id = s.user_id
+            WHERE s.id = $1 AND s.user_id = $2 AND s.revoked_at IS NULL AND s.expires_at > NOW()
+              AND u.disabled_at IS NULL
+        ) AS "active!""#,
+        session_id,
+        user_id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    if !active {
+        return Err(AppError::Auth("Session has been revoked".into()));
+    }
+
+    Ok(AuthUser {
+        user_id,
+        credential: Credential::Session(session_id),
+        expires_at: DateTime::from_timestamp(claims.exp as i64, 0),
+    })
+}
+
+async fn authenticate_access_token(state: &AppState, token: &str) -> Result<AuthUser, AppError> {
+    let row = sqlx::query!(
+        "SELECT t.id, t.user_id, t.scopes, t.last_used_at, t.expires_at
+         FROM personal_access_tokens t JOIN users u ON u.id = t.user_id
+         WHERE t.token_hash = $1 AND t.revoked_at IS NULL
+           AND (t.expires_at IS NULL OR t.expires_at > NOW()) AND u.disabled_at IS NULL",
+        token::hash(token)
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(|| AppError::Auth("Invalid or expired token".into()))?;
+
+    let stale = row.last_used_at.is_none_or(|last| {
+        Utc::now() - last > chrono::Duration::seconds(LAST_USED_RESOLUTION_SECS)
+    });
+    if stale {
+        sqlx::query!(
+            "UPDATE personal_access_tokens SET last_used_at = NOW() WHERE id = $1",
+            row.id
+        )
+        .execute(&state.pool)
+        .await?;
+    }
+
+    Ok(AuthUser {
+        user_id: row.user_id,
+        credential: Credential::AccessToken {
+            id: row.id,
+            scopes: row.scopes.iter().filter_map(|s| Scope::parse(s)).collect(),
+        },
+        expires_at: row.expires_at,
+    })
+}
+
+/// Rechecks that an already authenticated credential hasn't since been revoked
+/// (or its account disabled).
+pub async fn is_active(state: &AppState, auth: &AuthUser) -> Result<bool, AppError> {
+    let active = match &auth.credential {
+        Credential::Session(session_id) => {
+            sqlx::query_scalar!(
+                r#"SELECT EXISTS(
+                    SELECT 1 FROM sessions s JOIN users u ON u.id = s.user_id
+                    WHERE s.id = $1 AND s.revoked_at IS NULL AND u.disabled_at IS NULL
+                ) AS "active!""#,
+                session_id
+            )
+            .fetch_one(&state.pool)
+            .await?
+        }
+        Credential::AccessToken { id, .. } => {
+            sqlx::query_scalar!(
+                r#"SELECT EXISTS(
+                    SELECT 1 FROM personal_access_tokens t JOIN users u ON u.id = t.user_id
+                    WHERE t.id = $1 AND t.revoked_at IS NULL AND u.disabled_at IS NULL
+                ) AS "active!""#,
+                id
+            )
+            .fetch_one(&state.pool)
+            .await?
+        }
+    };
+    Ok(active)
+}
@@ -1,5 +1,12 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+use webauthn_rs::prelude::{
+    CreationChallengeResponse, PublicKeyCredential, RegisterPublicKeyCredential,
+    RequestChallengeResponse,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct RegisterRequest {
@@ -20,44 +27,764 @@ pub struct AuthResponse {
     pub token: String,
 }
 
+/// Returned by `/login` instead of [`AuthResponse`] when the account has 2FA enabled.
+#[derive(Debug, Serialize)]
+pub struct TwoFactorChallenge {
+    pub two_factor_required: bool,
+    pub challenge_token: String,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorLoginRequest {
+    pub challenge_token: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordConfirmation {
+    pub password: String,
+}
+
+/// Proof of identity for deleting the account: the password, a current TOTP code, or
+/// neither if the session signed in within the last few minutes (e.g. through SSO or a
+/// passkey).
+#[derive(Debug, Default, Deserialize)]
+pub struct AccountDeletion {
+    pub password: Option<String>,
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyLoginStart {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PasskeyLoginChallenge {
+    pub ceremony_id: Uuid,
+    pub options: RequestChallengeResponse,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OidcLoginStart {
+    /// Open this in a browser; the provider redirects back with `code` and `state`.
+    pub authorization_url: String,
+    pub state: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OidcLoginFinish {
+    pub code: String,
+    pub state: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyLoginFinish {
+    pub ceremony_id: Uuid,
+    pub credential: PublicKeyCredential,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PasskeyRegistrationChallenge {
+    pub ceremony_id: Uuid,
+    pub options: CreationChallengeResponse,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyRegistrationFinish {
+    pub ceremony_id: Uuid,
+    pub name: String,
+    pub credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PasskeyInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PairingStatus {
+    /// Waiting for a new device to enter the code.
+    Pending,
+    /// Redeemed by a new device, waiting for approval.
+    Claimed,
+    Approved,
+    Rejected,
+    /// The new device has collected its credentials.
+    Completed,
+    Expired,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PairingCode {
+    pub id: Uuid,
+    /// Shown (and encoded in a QR code) on the original device.
+    pub code: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PairingInfo {
+    pub id: Uuid,
+    pub status: PairingStatus,
+    pub device_name: Option<String>,
+    pub device_public_key: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RedeemPairingRequest {
+    pub code: String,
+    pub device_name: String,
+    /// Lets the original device seal the encryption key to this device. Opaque to the server.
+    pub public_key: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PairingClaim {
+    pub id: Uuid,
+    /// Proves to `/pairing/{id}/poll` that the caller is the device that redeemed the code.
+    pub poll_token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApprovePairingRequest {
+    /// The encryption key, sealed to the new device's `public_key`. Opaque to the server.
+    pub sealed_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PollPairingRequest {
+    pub poll_token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PairingPending {
+    pub status: PairingStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PairingCredentials {
+    pub token: String,
+    pub sealed_key: Option<String>,
+}
+
+/// Permissions a personal access token can be limited to. Sessions have all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "history:read")]
+    HistoryRead,
+    #[serde(rename = "clips:write")]
+    ClipsWrite,
+    #[serde(rename = "clips:subscribe")]
+    ClipsSubscribe,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::HistoryRead => "history:read",
+            Self::ClipsWrite => "clips:write",
+            Self::ClipsSubscribe => "clips:subscribe",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "history:read" => Some(Self::HistoryRead),
+            "clips:write" => Some(Self::ClipsWrite),
+            "clips:subscribe" => Some(Self::ClipsSubscribe),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "user" => Some(Self::User),
+            "admin" => Some(Self::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateAccessTokenRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_in_days: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessTokenInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// Returned once on creation; only the hash of `token` is stored.
+#[derive(Debug, Serialize)]
+pub struct CreatedAccessToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub info: AccessTokenInfo,
+}
+
+/// `aud` of session tokens; the only audience accepted as a bearer credential.
+pub const SESSION_AUDIENCE: &str = "echo:session";
+/// `aud` of two-factor challenges, so one can never pass as a session.
+pub const CHALLENGE_AUDIENCE: &str = "echo:2fa";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
+    pub sid: String,
+    pub aud: String,
+    pub exp: usize,
+    pub iat: usize,
+}
+
+/// Short-lived proof that the password step of a two-factor login succeeded.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeClaims {
+    pub sub: String,
+    pub aud: String,
     pub exp: usize,
     pub iat: usize,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct WsQuery {
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
     pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserProfile {
+    pub id: Uuid,
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub email_verified: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceInfo {
+    pub id: Uuid,
+    /// Set for devices added through pairing; `None` for ordinary logins.
+    pub name: Option<String>,
+    /// Whether this is the session making the request.
+    pub current: bool,
+    /// Whether it has a socket or event stream open right now.
+    pub online: bool,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ActivityQuery {
+    pub limit: Option<i64>,
+    /// Return events older than this id, for paging back through the log.
+    pub before: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEventInfo {
+    pub id: i64,
+    pub event: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Everything Echo stores about an account, for `GET /me/export`.
+#[derive(Debug, Serialize)]
+pub struct AccountExport {
+    pub exported_at: DateTime<Utc>,
+    pub profile: UserProfile,
+    pub devices: Vec<DeviceInfo>,
+    /// Encrypted clips are exported as stored; only your devices can decrypt them.
+    pub clipboard_history: Vec<ClipboardMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateProfileRequest {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub email: Option<String>,
+    /// Required when changing the email address.
+    pub current_password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WsQuery {
+    /// Legacy: only honoured when `WS_ALLOW_QUERY_TOKEN` is enabled, since URLs end up in logs.
+    pub token: Option<String>,
+    /// Single-use ticket from `POST /ws/ticket`, for clients that can't set headers.
+    pub ticket: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    /// Single-use ticket from `POST /ws/ticket`, since `EventSource` can't set headers.
+    pub ticket: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WsTicket {
+    pub ticket: String,
+    pub expires_in: u64,
+}
+
+/// Control frames a client can send over the socket. Anything else is treated as a clip.
+/// An `auth` frame on an open socket replaces its credentials before they expire.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Auth {
+        token: String,
+    },
+    /// Sends a stored clip again as a new one, so large content needn't be re-uploaded.
+    /// Answered with an `unknown_hash` error if the server has no clip with that hash.
+    Recopy {
+        hash: String,
+        #[serde(default)]
+        targets: Option<Vec<Uuid>>,
+    },
+    /// Removes one stored clip.
+    Delete {
+        id: i64,
+    },
+    DeleteHistory(DeleteHistoryQuery),
+    Pin {
+        id: i64,
+    },
+    Unpin {
+        id: i64,
+    },
+}
+
+/// Control frames the server sends alongside relayed clips.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    /// `expires_at` is a unix timestamp, or `None` for credentials that don't expire.
+    Authenticated {
+        expires_at: Option<i64>,
+    },
+    /// Sent shortly before `expires_at`: reply with a fresh `auth` frame or be disconnected.
+    Reauthenticate {
+        expires_at: i64,
+    },
+    /// Stored clips removed by any of the user's devices, this one included. Drop them
+    /// from the local history too.
+    Deleted {
+        ids: Vec<i64>,
+    },
+    Pinned {
+        id: i64,
+    },
+    Unpinned {
+        id: i64,
+    },
+    /// A request from this device was refused; nothing was stored or relayed.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// Clips whose time-to-live ran out. Drop them, and clear the clipboard if it still
+    /// holds one of them.
+    Expired {
+        ids: Vec<i64>,
+    },
+    /// One of the user's devices (an id from `/me/devices` or `/me/tokens`) connected or
+    /// went away.
+    DeviceOnline {
+        device_id: Uuid,
+    },
+    DeviceOffline {
+        device_id: Uuid,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClipboardMessage {
+    /// Assigned by the server once the clip is stored; ignored if a client sends one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
     pub device_id: String,
     pub content: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub nonce: Option<String>,
     #[serde(default)]
     pub encrypted: bool,
+    /// Detected by the server for plaintext clips; senders of encrypted clips may set it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<ClipKind>,
     pub timestamp: u64,
+    /// Pinned clips are never trimmed from history. Set through `pin` frames or the API.
+    #[serde(default)]
+    pub pinned: bool,
+    /// Set by senders: remove the clip this many seconds after it's stored.
+    #[serde(default, skip_serializing)]
+    pub ttl_secs: Option<u64>,
+    /// Unix milliseconds, worked out by the server from `ttl_secs`. Receivers should drop
+    /// the clip (and clear their clipboard if it still holds it) by then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// SHA-256 of `content` in hex, set by the server. For encrypted clips it's the hash of
+    /// the ciphertext, so it says nothing about the plaintext.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// MIME type of a `binary` clip, whose `content` is base64.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    /// Session or access token ids of the devices the clip is for. Other devices aren't
+    /// sent it; `None` sends it to all of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub targets: Option<Vec<Uuid>>,
 }
 
 impl ClipboardMessage {
     pub fn new(device_id: impl Into<String>, content: impl Into<String>) -> Self {
         Self {
+            id: None,
             device_id: device_id.into(),
             content: content.into(),
             nonce: None,
             encrypted: false,
+            kind: None,
             timestamp: now_millis(),
+            pinned: false,
+            ttl_secs: None,
+            expires_at: None,
+            hash: None,
+            mime_type: None,
+            targets: None,
+        }
+    }
+
+    pub fn content_hash(&self) -> String {
+        hex::encode(Sha256::digest(self.content.as_bytes()))
+    }
+
+    /// Whether the device signed in with this session or access token should get the clip.
+    pub fn is_for(&self, credential_id: Uuid) -> bool {
+        self.targets
+            .as_ref()
+            .is_none_or(|targets| targets.contains(&credential_id))
+    }
+}
+
+/// Matches the desktop app's content types, plus `binary` for uploads with base64 content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipKind {
+    Text,
+    Url,
+    Code,
+    Binary,
+}
+
+const CODE_KEYWORDS: &[&str] = &[
+    "import",
+    "export",
+    "const",
+    "let",
+    "var",
+    "function",
+    "class",
+    "interface",
+    "type",
+    "def",
+    "fn",
+    "pub",
+    "async",
+    "await",
+];
+const CODE_CONTROL_FLOW: &[&str] = &["if", "for", "while", "switch", "try", "catch"];
+
+impl ClipKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Url => "url",
+            Self::Code => "code",
+            Self::Binary => "binary",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "text" => Some(Self::Text),
+            "url" => Some(Self::Url),
+            "code" => Some(Self::Code),
+            "binary" => Some(Self::Binary),
+            _ => None,
+        }
+    }
+
+    /// Same heuristics as `detectContentType` in the desktop app.
+    pub fn detect(content: &str) -> Self {
+        let trimmed = content.trim();
+
+        let lower = trimmed.to_ascii_lowercase();
+        let is_url = ["http://", "https://"]
+            .iter()
+            .any(|scheme| lower.len() > scheme.len() && lower.starts_with(scheme))
+            && !trimmed.contains(char::is_whitespace);
+        if is_url {
+            return Self::Url;
+        }
+
+        let starts_with_keyword = trimmed.lines().any(|line| {
+            CODE_KEYWORDS.iter().any(|kw| {
+                line.strip_prefix(kw)
+                    .is_some_and(|rest| rest.starts_with(char::is_whitespace))
+            })
+        });
+        let starts_with_control_flow = trimmed.lines().any(|line| {
+            let line = line.trim_start();
+            CODE_CONTROL_FLOW.iter().any(|kw| {
+                line.strip_prefix(kw)
+                    .is_some_and(|rest| rest.trim_start().starts_with('('))
+            })
+        });
+        let ends_with_bracket = trimmed
+            .strip_suffix(';')
+            .unwrap_or(trimmed)
+            .ends_with(['{', '}', '[', ']']);
+        let has_tag = trimmed.match_indices('<').any(|(i, _)| {
+            let rest = &trimmed[i + 1..];
+            let rest = rest.strip_prefix('/').unwrap_or(rest);
+            rest.starts_with(|c: char| c.is_ascii_alphabetic()) && rest.contains('>')
+        });
+
+        if starts_with_keyword || starts_with_control_flow || ends_with_bracket || has_tag {
+            Self::Code
+        } else {
+            Self::Text
         }
     }
 }
 
-fn now_millis() -> u64 {
+pub fn now_millis() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .map(|d| d.as_millis() as u64)
         .unwrap_or(0)
 }
+
+#[derive(Debug, Deserialize)]
+pub struct AdminUserQuery {
+    /// Matches email or name, case-insensitively.
+    pub q: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// Overrides of the server-wide defaults; `None` means the default applies.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct UserLimits {
+    pub max_messages_per_minute: Option<i32>,
+    pub max_connections: Option<i32>,
+    pub storage_quota_bytes: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminUserInfo {
+    pub id: Uuid,
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub role: Role,
+    pub email_verified: bool,
+    pub created_at: DateTime<Utc>,
+    pub disabled_at: Option<DateTime<Utc>>,
+    /// Open WebSocket connections and event streams right now.
+    pub connections: usize,
+    pub limits: UserLimits,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionCount {
+    pub user_id: Uuid,
+    pub connections: usize,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    pub limit: Option<i64>,
+    /// Page backwards: clips older than this id, newest first.
+    pub cursor: Option<i64>,
+    /// Incremental sync: clips newer than this id, oldest first.
+    pub since: Option<i64>,
+    pub device: Option<String>,
+    /// Unix milliseconds, inclusive, compared with the clip's `timestamp`.
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub kind: Option<ClipKind>,
+    pub encrypted: Option<bool>,
+    /// Clips whose content has this `hash`.
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryPage {
+    pub items: Vec<ClipboardMessage>,
+    /// Pass back as `cursor` (or `since`, when syncing) for the next page; `None` at the end.
+    pub next_cursor: Option<i64>,
+}
+
+/// Which clips `DELETE /history` and the `delete_history` frame remove. The filters
+/// combine; `all` clears everything and can't be mixed with them. Pinned clips are kept
+/// until they're unpinned or deleted one by one.
+#[derive(Debug, Default, Deserialize)]
+pub struct DeleteHistoryQuery {
+    pub device: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    #[serde(default)]
+    pub all: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    ClipTooLarge,
+    QuotaExceeded,
+    UnknownHash,
+    /// A clip's `targets` named a device that isn't signed in.
+    UnknownDevice,
+    /// The server couldn't save the clip, so it wasn't sent either. Safe to retry.
+    NotStored,
+}
+
+/// Storage used by a user's history, for `GET /me/usage`.
+#[derive(Debug, Serialize)]
+pub struct StorageUsage {
+    pub clips: i64,
+    pub pinned: i64,
+    pub bytes_used: i64,
+    pub quota_bytes: i64,
+    pub max_clip_bytes: usize,
+}
+
+/// How much history the server keeps for a user. Pinned clips are exempt. `null` means
+/// the server default: 50 items, no age or size limit.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub max_items: Option<i32>,
+    pub max_age_secs: Option<i64>,
+    /// Total content size of the clips kept, newest first.
+    pub max_bytes: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeletedClips {
+    pub deleted: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    pub clip: ClipboardMessage,
+    pub rank: f32,
+    /// HTML-escaped excerpt with matches wrapped in `<mark>`.
+    pub snippet: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResults {
+    pub items: Vec<SearchHit>,
+    /// Encrypted and binary clips, which have no text to match.
+    pub unsearchable: i64,
+}
+
+/// JSON body for `POST /clips`. Plain text and binary uploads are sent as the raw body.
+#[derive(Debug, Deserialize)]
+pub struct PushClipRequest {
+    pub content: String,
+    pub targets: Option<Vec<Uuid>>,
+    #[serde(default)]
+    pub encrypted: bool,
+    pub nonce: Option<String>,
+    pub kind: Option<ClipKind>,
+    pub mime_type: Option<String>,
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PushClipQuery {
+    pub ttl_secs: Option<u64>,
+    /// Comma-separated device ids, for bodies that aren't JSON.
+    pub targets: Option<String>,
+}
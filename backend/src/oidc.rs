@@ -0,0 +1,310 @@
+use anyhow::{bail, Context};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant};
+use tokio::sync::{OnceCell, RwLock};
+
+use crate::{config::Config, error::AppError, token};
+
+/// How long a user has to finish signing in at the provider.
+pub const LOGIN_TIMEOUT_SECS: u64 = 300;
+const HTTP_TIMEOUT_SECS: u64 = 10;
+/// Minimum gap between JWKS fetches, so tokens with made-up key ids can't make us hammer
+/// the provider.
+const JWKS_REFRESH_COOLDOWN_SECS: u64 = 60;
+
+/// ID tokens must be signed with an asymmetric key published in the provider's JWKS.
+const ALLOWED_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// The ID token claims Echo reads.
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub name: Option<String>,
+}
+
+impl IdTokenClaims {
+    /// Splits into first and last name, preferring the structured claims.
+    pub fn names(&self) -> (String, String) {
+        match (&self.given_name, &self.family_name, &self.name) {
+            (Some(given), family, _) => (given.clone(), family.clone().unwrap_or_default()),
+            (None, _, Some(name)) => match name.split_once(' ') {
+                Some((first, last)) => (first.into(), last.into()),
+                None => (name.clone(), String::new()),
+            },
+            _ => (String::new(), String::new()),
+        }
+    }
+}
+
+/// The provider's signing keys and when they were last fetched.
+struct CachedJwks {
+    keys: JwkSet,
+    fetched_at: Option<Instant>,
+}
+
+/// Authorization code + PKCE client for a single OpenID Connect provider.
+/// Discovery and the provider's keys are fetched lazily and cached.
+pub struct OidcClient {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: String,
+    http: reqwest::Client,
+    discovery: OnceCell<Discovery>,
+    jwks: RwLock<CachedJwks>,
+}
+
+/// Returns `None` when SSO isn't configured.
+pub fn build(config: &Config) -> anyhow::Result<Option<OidcClient>> {
+    let Some(issuer) = &config.oidc_issuer else {
+        return Ok(None);
+    };
+    let Some(client_id) = &config.oidc_client_id else {
+        bail!("OIDC_CLIENT_ID is required when OIDC_ISSUER is set");
+    };
+    Url::parse(&config.oidc_redirect_uri).context("Invalid OIDC_REDIRECT_URI")?;
+
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(HTTP_TIMEOUT_SECS))
+        .build()
+        .context("Failed to build HTTP client")?;
+
+    Ok(Some(OidcClient {
+        issuer: issuer.trim_end_matches('/').to_string(),
+        client_id: client_id.clone(),
+        client_secret: config.oidc_client_secret.clone(),
+        redirect_uri: config.oidc_redirect_uri.clone(),
+        http,
+        discovery: OnceCell::new(),
+        jwks: RwLock::new(CachedJwks {
+            keys: JwkSet { keys: Vec::new() },
+            fetched_at: None,
+        }),
+    }))
+}
+
+impl OidcClient {
+    /// The configured issuer without a trailing slash, as identities are stored.
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    async fn discovery(&self) -> Result<&Discovery, AppError> {
+        self.discovery
+            .get_or_try_init(|| async {
+                let url = format!("{}/.well-known/openid-configuration", self.issuer);
+                let discovery: Discovery = self.get_json(&url).await?;
+                if discovery.issuer.trim_end_matches('/') != self.issuer {
+                    return Err(AppError::Internal(format!(
+                        "OIDC discovery issuer {} doesn't match {}",
+                        discovery.issuer, self.issuer
+                    )));
+                }
+                Ok(discovery)
+            })
+            .await
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, AppError> {
+        self.http
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| AppError::Internal(format!("OIDC request to {url} failed: {e}")))?
+            .json()
+            .await
+            .map_err(|e| AppError::Internal(format!("Invalid OIDC response from {url}: {e}")))
+    }
+
+    pub async fn authorization_url(&self, pending: &PendingLogin) -> Result<String, AppError> {
+        let discovery = self.discovery().await?;
+        let mut url = Url::parse(&discovery.authorization_endpoint)
+            .map_err(|e| AppError::Internal(format!("Invalid authorization endpoint: {e}")))?;
+
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("scope", "openid email profile")
+            .append_pair("state", &pending.state)
+            .append_pair("nonce", &pending.nonce)
+            .append_pair("code_challenge", &pkce_challenge(&pending.verifier))
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(url.into())
+    }
+
+    /// Redeems an authorization code and returns the verified ID token claims.
+    pub async fn exchange(
+        &self,
+        code: &str,
+        pending: &PendingLogin,
+    ) -> Result<IdTokenClaims, AppError> {
+        let discovery = self.discovery().await?;
+
+        let mut request = self.http.post(&discovery.token_endpoint).form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_uri),
+            ("client_id", &self.client_id),
+            ("code_verifier", &pending.verifier),
+        ]);
+        if let Some(secret) = &self.client_secret {
+            request = request.basic_auth(&self.client_id, Some(secret));
+        }
+
+        let response: TokenResponse = request
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| {
+                tracing::warn!("OIDC code exchange failed: {e}");
+                AppError::Auth("Single sign-on failed".into())
+            })?
+            .json()
+            .await
+            .map_err(|e| AppError::Internal(format!("Invalid OIDC token response: {e}")))?;
+
+        let claims = self
+            .verify_id_token(&response.id_token)
+            .await
+            .map_err(|e| {
+                tracing::warn!("Rejected OIDC ID token: {e:?}");
+                AppError::Auth("Single sign-on failed".into())
+            })?;
+
+        if claims.nonce.as_deref() != Some(pending.nonce.as_str()) {
+            return Err(AppError::Auth("Single sign-on failed".into()));
+        }
+        Ok(claims)
+    }
+
+    async fn verify_id_token(&self, id_token: &str) -> anyhow::Result<IdTokenClaims> {
+        let header = decode_header(id_token)?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            bail!("Unsupported ID token algorithm {:?}", header.alg);
+        }
+
+        let key = self.decoding_key(header.kid.as_deref()).await?;
+        // `iss` must be exactly what the provider published, trailing slash and all.
+        let issuer = &self
+            .discovery()
+            .await
+            .map_err(|e| anyhow::anyhow!("{e:?}"))?
+            .issuer;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[issuer]);
+        validation.set_audience(&[&self.client_id]);
+
+        Ok(decode::<IdTokenClaims>(id_token, &key, &validation)?.claims)
+    }
+
+    /// Looks the key up in the cached JWKS, refetching in case the provider rotated, but
+    /// at most once per `JWKS_REFRESH_COOLDOWN_SECS`.
+    async fn decoding_key(&self, kid: Option<&str>) -> anyhow::Result<DecodingKey> {
+        if let Some(key) = find_key(&self.jwks.read().await.keys, kid)? {
+            return Ok(key);
+        }
+
+        let mut jwks = self.jwks.write().await;
+        // Another request may have refreshed the keys while we waited for the lock.
+        if let Some(key) = find_key(&jwks.keys, kid)? {
+            return Ok(key);
+        }
+        if jwks
+            .fetched_at
+            .is_some_and(|at| at.elapsed() < Duration::from_secs(JWKS_REFRESH_COOLDOWN_SECS))
+        {
+            bail!("ID token signed with an unknown key");
+        }
+
+        let jwks_uri = &self
+            .discovery()
+            .await
+            .map_err(|e| anyhow::anyhow!("{e:?}"))?
+            .jwks_uri;
+        let fetched: JwkSet = self
+            .get_json(jwks_uri)
+            .await
+            .map_err(|e| anyhow::anyhow!("{e:?}"))?;
+        *jwks = CachedJwks {
+            keys: fetched,
+            fetched_at: Some(Instant::now()),
+        };
+
+        find_key(&jwks.keys, kid)?.context("ID token signed with an unknown key")
+    }
+}
+
+fn find_key(jwks: &JwkSet, kid: Option<&str>) -> anyhow::Result<Option<DecodingKey>> {
+    let jwk = match kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    };
+    jwk.map(DecodingKey::from_jwk)
+        .transpose()
+        .map_err(Into::into)
+}
+
+/// RFC 7636 S256 code challenge.
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// Server-side half of an in-flight login, keyed by its `state` parameter.
+pub struct PendingLogin {
+    pub state: String,
+    pub nonce: String,
+    pub verifier: String,
+    pub started: Instant,
+}
+
+impl PendingLogin {
+    pub fn start() -> Self {
+        Self {
+            state: token::generate(),
+            nonce: token::generate(),
+            verifier: token::generate(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.started.elapsed().as_secs() >= LOGIN_TIMEOUT_SECS
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::Context;
+use std::time::Instant;
+use uuid::Uuid;
+use webauthn_rs::{
+    prelude::{PasskeyAuthentication, PasskeyRegistration, Url},
+    Webauthn, WebauthnBuilder,
+};
+
+use crate::config::Config;
+
+/// How long a client has to complete a ceremony after starting it.
+pub const CEREMONY_TIMEOUT_SECS: u64 = 300;
+
+pub fn build(config: &Config) -> anyhow::Result<Webauthn> {
+    let origin = Url::parse(&config.webauthn_rp_origin).context("Invalid WEBAUTHN_RP_ORIGIN")?;
+
+    WebauthnBuilder::new(&config.webauthn_rp_id, &origin)
+        .context("Invalid WebAuthn relying party")?
+        .rp_name("Echo")
+        .build()
+        .context("Failed to build WebAuthn relying party")
+}
+
+/// Server-side half of an in-flight registration or login, keyed by ceremony id.
+pub enum Ceremony {
+    Registration {
+        user_id: Uuid,
+        state: PasskeyRegistration,
+    },
+    Authentication {
+        user_id: Uuid,
+        state: PasskeyAuthentication,
+    },
+}
+
+pub struct PendingCeremony {
+    pub ceremony: Ceremony,
+    pub started: Instant,
+}
+
+impl PendingCeremony {
+    pub fn new(ceremony: Ceremony) -> Self {
+        Self {
+            ceremony,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.started.elapsed().as_secs() >= CEREMONY_TIMEOUT_SECS
+    }
+}
@@ -0,0 +1,108 @@
+//! Removes clips that outlived their time-to-live or their owner's retention policy.
+
+use std::{collections::HashMap, time::Duration};
+use uuid::Uuid;
+
+use crate::{
+    models::now_millis,
+    state::{AppState, HubEvent},
+};
+
+/// Sweeps every `retention_sweep_secs` for as long as the server runs, and in between
+/// wakes when the soonest TTL runs out, so expiring clips go on time without a task each.
+pub fn spawn_sweeper(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(state.config.retention_sweep_secs));
+        loop {
+            let next_expiry = next_expiry(&state).await.unwrap_or_else(|e| {
+                tracing::error!("failed to find the next clip expiry: {e:?}");
+                None
+            });
+            tokio::select! {
+                _ = interval.tick() => report(sweep(&state).await),
+                _ = until(next_expiry) => {
+                    let result = expire(&state).await;
+                    let failed = result.is_err();
+                    report(result);
+                    if failed {
+                        // Don't spin on a clip we can't delete; retry at the next sweep.
+                        interval.tick().await;
+                    }
+                }
+                // A new TTL may be sooner than the one we're waiting for.
+                _ = state.expiry_scheduled() => {}
+            }
+        }
+    });
+}
+
+fn report(result: Result<u64, sqlx::Error>) {
+    match result {
+        Ok(0) => {}
+        Ok(removed) => tracing::info!(removed, "retention sweep"),
+        Err(e) => tracing::error!("retention sweep failed: {e:?}"),
+    }
+}
+
+async fn until(at: Option<u64>) {
+    match at {
+        Some(at) => {
+            tokio::time::sleep(Duration::from_millis(at.saturating_sub(now_millis()))).await
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn next_expiry(state: &AppState) -> Result<Option<u64>, sqlx::Error> {
+    let at = sqlx::query_scalar!("SELECT MIN(expires_at) FROM clips")
+        .fetch_one(&state.pool)
+        .await?;
+    Ok(at.map(|at| at as u64))
+}
+
+/// One pass. Expired clips go even when pinned, since their sender asked for them to;
+/// clips older than their owner's `max_age_secs` only when unpinned.
+pub async fn sweep(state: &AppState) -> Result<u64, sqlx::Error> {
+    let expired = expire(state).await?;
+
+    let aged = sqlx::query!(
+        "DELETE FROM clips USING users
+         WHERE users.id = clips.user_id
+           AND users.history_max_age_secs IS NOT NULL
+           AND clips.pinned_at IS NULL
+           AND clips.created_at < NOW() - users.history_max_age_secs * INTERVAL '1 second'
+         RETURNING clips.id, clips.user_id"
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let removed = aged.len() as u64;
+    for (user_id, ids) in by_user(aged.into_iter().map(|clip| (clip.user_id, clip.id))) {
+        state.publish(&user_id, HubEvent::Deleted(ids));
+    }
+    Ok(expired + removed)
+}
+
+async fn expire(state: &AppState) -> Result<u64, sqlx::Error> {
+    let expired = sqlx::query!(
+        "DELETE FROM clips WHERE expires_at <= $1 RETURNING id, user_id",
+        now_millis() as i64
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let removed = expired.len() as u64;
+    for (user_id, ids) in by_user(expired.into_iter().map(|clip| (clip.user_id, clip.id))) {
+        state.publish(&user_id, HubEvent::Expired(ids));
+    }
+    Ok(removed)
+}
+
+fn by_user(clips: impl Iterator<Item = (Uuid, i64)>) -> HashMap<Uuid, Vec<i64>> {
+    let mut by_user: HashMap<Uuid, Vec<i64>> = HashMap::new();
+    for (user_id, id) in clips {
+        by_user.entry(user_id).or_default().push(id);
+    }
+    by_user
+}
@@ -1,15 +1,132 @@
-use crate::models::ClipboardMessage;
+use crate::{
+    config::Config,
+    keys::JwtKeys,
+    mailer::{self, Email, Mailer},
+    middleware::AuthUser,
+    models::{
+        ClipKind, ClipboardMessage, DeleteHistoryQuery, HistoryPage, HistoryQuery, SearchHit,
+        SearchResults, StorageUsage,
+    },
+    oidc::{self, OidcClient, PendingLogin},
+    passkey::{self, Ceremony, PendingCeremony},
+    token,
+};
 use dashmap::DashMap;
 use sqlx::PgPool;
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Instant;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, Notify};
 use uuid::Uuid;
+use webauthn_rs::Webauthn;
 
 const MAX_MESSAGES_PER_WINDOW: u32 = 30;
 const WINDOW_DURATION_SECS: u64 = 60;
 const MIN_INTERVAL_MS: u128 = 100;
 const MAX_HISTORY_SIZE: usize = 50;
+const MAX_SECOND_FACTOR_FAILURES: u32 = 5;
+const SECOND_FACTOR_LOCKOUT_SECS: u64 = 300;
+const MAX_PAIRING_FAILURES: u32 = 10;
+const PAIRING_LOCKOUT_SECS: u64 = 600;
+const MAX_SEARCH_TERMS: i64 = 16;
+/// Pins are exempt from trimming, so their number is capped instead.
+pub const MAX_PINNED_CLIPS: i64 = 100;
+pub const WS_TICKET_EXPIRY_SECS: u64 = 30;
+const CREDENTIAL_CHANGES_CAPACITY: usize = 64;
+
+struct ClipRow {
+    id: i64,
+    device_id: String,
+    content: String,
+    nonce: Option<String>,
+    encrypted: bool,
+    kind: Option<String>,
+    timestamp: i64,
+    expires_at: Option<i64>,
+    content_hash: String,
+    mime_type: Option<String>,
+    targets: Option<Vec<Uuid>>,
+    pinned: bool,
+}
+
+impl From<ClipRow> for ClipboardMessage {
+    fn from(row: ClipRow) -> Self {
+        Self {
+            id: Some(row.id),
+            device_id: row.device_id,
+            content: row.content,
+            nonce: row.nonce,
+            encrypted: row.encrypted,
+            kind: row.kind.as_deref().and_then(ClipKind::parse),
+            timestamp: row.timestamp as u64,
+            pinned: row.pinned,
+            ttl_secs: None,
+            expires_at: row.expires_at.map(|at| at as u64),
+            hash: Some(row.content_hash),
+            mime_type: row.mime_type,
+            targets: row.targets,
+        }
+    }
+}
+
+/// What a user's connected devices hear about on their shared channel.
+#[derive(Debug, Clone)]
+pub enum HubEvent {
+    Clip(ClipboardMessage),
+    Deleted(Vec<i64>),
+    Pinned {
+        id: i64,
+        pinned: bool,
+    },
+    Expired(Vec<i64>),
+    /// A device (session or access token) opened its first socket or event stream, or
+    /// closed its last one.
+    Presence {
+        device_id: Uuid,
+        online: bool,
+    },
+}
+
+/// What `add_to_history` did with a clip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stored {
+    New(i64),
+    /// The clip repeated the user's newest one, which has this id, so it wasn't stored.
+    Duplicate(i64),
+}
+
+impl Stored {
+    pub fn id(self) -> i64 {
+        match self {
+            Self::New(id) | Self::Duplicate(id) => id,
+        }
+    }
+}
+
+/// What `set_pinned` did with a clip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinOutcome {
+    /// Pinned or unpinned; also when it already was.
+    Done,
+    NotFound,
+    /// Pinning it would exceed `MAX_PINNED_CLIPS`.
+    LimitReached,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// Keeping the clip would take the user over `quota` bytes.
+    QuotaExceeded {
+        quota: i64,
+    },
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for StoreError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Database(e)
+    }
+}
 
 #[derive(Clone, Default)]
 pub struct RateLimitState {
@@ -18,31 +135,73 @@ pub struct RateLimitState {
     pub window_start: Option<Instant>,
 }
 
-type Hub = Arc<DashMap<Uuid, broadcast::Sender<ClipboardMessage>>>;
+type Hub = Arc<DashMap<Uuid, broadcast::Sender<HubEvent>>>;
 type RateLimits = Arc<DashMap<String, RateLimitState>>;
-type History = Arc<DashMap<Uuid, Vec<ClipboardMessage>>>;
+type SecondFactorFailures = Arc<DashMap<Uuid, (u32, Instant)>>;
+type PairingFailures = Arc<DashMap<IpAddr, (u32, Instant)>>;
+type PasskeyCeremonies = Arc<DashMap<Uuid, PendingCeremony>>;
+type OidcLogins = Arc<DashMap<String, PendingLogin>>;
+type WsTickets = Arc<DashMap<String, (AuthUser, Instant)>>;
+/// Open sockets and event streams per (user, device).
+type Presence = Arc<DashMap<(Uuid, Uuid), usize>>;
 
 #[derive(Clone)]
 pub struct AppState {
     pub pool: PgPool,
-    pub jwt_secret: String,
+    pub config: Arc<Config>,
+    pub jwt_keys: Arc<JwtKeys>,
+    pub webauthn: Arc<Webauthn>,
+    /// `None` when single sign-on isn't configured.
+    pub oidc: Option<Arc<OidcClient>>,
+    mailer: Arc<dyn Mailer>,
     hub: Hub,
+    presence: Presence,
     rate_limits: RateLimits,
-    history: History,
+    second_factor_failures: SecondFactorFailures,
+    pairing_failures: PairingFailures,
+    passkey_ceremonies: PasskeyCeremonies,
+    oidc_logins: OidcLogins,
+    ws_tickets: WsTickets,
+    /// User ids whose sessions or tokens were just revoked, so open sockets recheck at once.
+    credential_changes: broadcast::Sender<Uuid>,
+    /// Woken when a clip with a TTL is stored, so the sweeper can move its timer earlier.
+    expiry_scheduled: Arc<Notify>,
 }
 
 impl AppState {
-    pub fn new(pool: PgPool, jwt_secret: String) -> Self {
-        Self {
+    pub fn new(pool: PgPool, config: Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            jwt_keys: Arc::new(JwtKeys::from_config(&config)?),
+            webauthn: Arc::new(passkey::build(&config)?),
+            oidc: oidc::build(&config)?.map(Arc::new),
+            mailer: Arc::from(mailer::from_config(&config)?),
+            config: Arc::new(config),
             pool,
-            jwt_secret,
             hub: Arc::default(),
+            presence: Arc::default(),
             rate_limits: Arc::default(),
-            history: Arc::default(),
-        }
+            second_factor_failures: Arc::default(),
+            pairing_failures: Arc::default(),
+            passkey_ceremonies: Arc::default(),
+            oidc_logins: Arc::default(),
+            ws_tickets: Arc::default(),
+            credential_changes: broadcast::channel(CREDENTIAL_CHANGES_CAPACITY).0,
+            expiry_scheduled: Arc::default(),
+        })
+    }
+
+    /// Sends in the background so request latency (and timing) doesn't depend on the mail server.
+    pub fn send_email(&self, email: Email) {
+        let mailer = Arc::clone(&self.mailer);
+        tokio::spawn(async move {
+            if let Err(e) = mailer.send(email).await {
+                tracing::error!("Failed to send email: {:?}", e);
+            }
+        });
     }
 
-    pub fn check_rate_limit(&self, device_id: &str) -> bool {
+    /// `max_per_window` overrides the default for users with a custom limit.
+    pub fn check_rate_limit(&self, device_id: &str, max_per_window: Option<u32>) -> bool {
         let now = Instant::now();
         let mut entry = self.rate_limits.entry(device_id.to_string()).or_default();
         let state = entry.value_mut();
@@ -59,7 +218,7 @@ impl AppState {
             state.message_count = 0;
         }
 
-        if state.message_count >= MAX_MESSAGES_PER_WINDOW {
+        if state.message_count >= max_per_window.unwrap_or(MAX_MESSAGES_PER_WINDOW) {
             return false;
         }
 
@@ -68,32 +227,639 @@ impl AppState {
         true
     }
 
-    pub fn add_to_history(&self, user_id: Uuid, msg: ClipboardMessage) {
-        let mut entry = self.history.entry(user_id).or_default();
-        let history = entry.value_mut();
-        history.insert(0, msg);
-        history.truncate(MAX_HISTORY_SIZE);
+    /// Too many wrong 2FA codes lock the account's second step for a while.
+    pub fn second_factor_allowed(&self, user_id: &Uuid) -> bool {
+        match self.second_factor_failures.get(user_id) {
+            Some(entry) => {
+                let (failures, since) = *entry.value();
+                failures < MAX_SECOND_FACTOR_FAILURES
+                    || since.elapsed().as_secs() >= SECOND_FACTOR_LOCKOUT_SECS
+            }
+            None => true,
+        }
     }
 
-    pub fn get_history(&self, user_id: &Uuid) -> Vec<ClipboardMessage> {
-        self.history
-            .get(user_id)
-            .map(|h| h.value().clone())
-            .unwrap_or_default()
+    pub fn record_second_factor_failure(&self, user_id: Uuid) {
+        let mut entry = self
+            .second_factor_failures
+            .entry(user_id)
+            .or_insert((0, Instant::now()));
+        let (failures, since) = entry.value_mut();
+        if since.elapsed().as_secs() >= SECOND_FACTOR_LOCKOUT_SECS {
+            *failures = 0;
+            *since = Instant::now();
+        }
+        *failures += 1;
+    }
+
+    pub fn clear_second_factor_failures(&self, user_id: &Uuid) {
+        self.second_factor_failures.remove(user_id);
+    }
+
+    /// Pairing codes are short, so wrong guesses are limited per client address.
+    /// Failed code redemptions are counted per client IP rather than per code, since the
+    /// code is what a guesser varies. Behind a proxy this needs `TRUST_PROXY_HEADERS`, or
+    /// every client shares the proxy's address and one guesser locks out everyone.
+    pub fn pairing_allowed(&self, ip: &IpAddr) -> bool {
+        match self.pairing_failures.get(ip) {
+            Some(entry) => {
+                let (failures, since) = *entry.value();
+                failures < MAX_PAIRING_FAILURES || since.elapsed().as_secs() >= PAIRING_LOCKOUT_SECS
+            }
+            None => true,
+        }
+    }
+
+    pub fn record_pairing_failure(&self, ip: IpAddr) {
+        let mut entry = self
+            .pairing_failures
+            .entry(ip)
+            .or_insert((0, Instant::now()));
+        let (failures, since) = entry.value_mut();
+        if since.elapsed().as_secs() >= PAIRING_LOCKOUT_SECS {
+            *failures = 0;
+            *since = Instant::now();
+        }
+        *failures += 1;
+    }
+
+    pub fn start_passkey_ceremony(&self, ceremony: Ceremony) -> Uuid {
+        self.passkey_ceremonies
+            .retain(|_, pending| !pending.is_expired());
+
+        let id = Uuid::new_v4();
+        self.passkey_ceremonies
+            .insert(id, PendingCeremony::new(ceremony));
+        id
+    }
+
+    /// Ceremonies are single-use: taking one removes it whether or not it has expired.
+    pub fn take_passkey_ceremony(&self, id: &Uuid) -> Option<Ceremony> {
+        self.passkey_ceremonies
+            .remove(id)
+            .map(|(_, pending)| pending)
+            .filter(|pending| !pending.is_expired())
+            .map(|pending| pending.ceremony)
+    }
+
+    pub fn start_oidc_login(&self, pending: PendingLogin) {
+        self.oidc_logins.retain(|_, pending| !pending.is_expired());
+        self.oidc_logins.insert(pending.state.clone(), pending);
+    }
+
+    /// Like passkey ceremonies, a login's `state` can only be redeemed once.
+    pub fn take_oidc_login(&self, state: &str) -> Option<PendingLogin> {
+        self.oidc_logins
+            .remove(state)
+            .map(|(_, pending)| pending)
+            .filter(|pending| !pending.is_expired())
+    }
+
+    /// Issues a short-lived ticket that stands in for `auth` on one WebSocket upgrade.
+    pub fn issue_ws_ticket(&self, auth: AuthUser) -> String {
+        self.ws_tickets
+            .retain(|_, (_, issued)| issued.elapsed().as_secs() < WS_TICKET_EXPIRY_SECS);
+
+        let ticket = token::generate();
+        self.ws_tickets
+            .insert(token::hash(&ticket), (auth, Instant::now()));
+        ticket
+    }
+
+    pub fn redeem_ws_ticket(&self, ticket: &str) -> Option<AuthUser> {
+        self.ws_tickets
+            .remove(&token::hash(ticket))
+            .map(|(_, entry)| entry)
+            .filter(|(_, issued)| issued.elapsed().as_secs() < WS_TICKET_EXPIRY_SECS)
+            .map(|(auth, _)| auth)
+    }
+
+    pub fn notify_credentials_changed(&self, user_id: Uuid) {
+        let _ = self.credential_changes.send(user_id);
+    }
+
+    pub fn subscribe_credential_changes(&self) -> broadcast::Receiver<Uuid> {
+        self.credential_changes.subscribe()
+    }
+
+    /// Stores a clip and trims the user's history to their retention policy. Nothing is
+    /// stored if what's left after trimming would exceed their quota, or if the clip
+    /// repeats the user's newest one.
+    pub async fn add_to_history(
+        &self,
+        user_id: Uuid,
+        msg: &ClipboardMessage,
+    ) -> Result<Stored, StoreError> {
+        let mut tx = self.pool.begin().await?;
+
+        // Locking the user serialises their inserts, so concurrent clips can't both slip
+        // under the quota.
+        let quota = sqlx::query_scalar!(
+            r#"SELECT COALESCE(storage_quota_bytes, $2) AS "quota!" FROM users WHERE id = $1
+               FOR UPDATE"#,
+            user_id,
+            self.config.storage_quota_bytes
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // Copying the same thing twice, or a receiving device echoing a clip back, would
+        // otherwise fill the history with repeats.
+        let hash = msg.content_hash();
+        let newest = sqlx::query!(
+            "SELECT id, content_hash, targets FROM clips
+             WHERE user_id = $1 ORDER BY id DESC LIMIT 1",
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(newest) =
+            newest.filter(|newest| newest.content_hash == hash && newest.targets == msg.targets)
+        {
+            return Ok(Stored::Duplicate(newest.id));
+        }
+
+        let id = sqlx::query_scalar!(
+            "INSERT INTO clips (user_id, device_id, content, nonce, encrypted, kind, timestamp,
+                                expires_at, content_hash, mime_type, targets)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) RETURNING id",
+            user_id,
+            msg.device_id,
+            msg.content,
+            msg.nonce,
+            msg.encrypted,
+            msg.kind.map(ClipKind::as_str),
+            msg.timestamp as i64,
+            msg.expires_at.map(|at| at as i64),
+            hash,
+            msg.mime_type,
+            msg.targets.as_deref() as Option<&[Uuid]>
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let trimmed = trim_history(&mut tx, user_id).await?;
+
+        let used = sqlx::query_scalar!(
+            r#"SELECT COALESCE(SUM(OCTET_LENGTH(content)), 0)::BIGINT AS "used!"
+               FROM clips WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if used > quota {
+            return Err(StoreError::QuotaExceeded { quota });
+        }
+
+        tx.commit().await?;
+        if !trimmed.is_empty() {
+            self.publish(&user_id, HubEvent::Deleted(trimmed));
+        }
+        if msg.expires_at.is_some() {
+            self.expiry_scheduled.notify_one();
+        }
+        Ok(Stored::New(id))
+    }
+
+    /// Resolves once a clip with a TTL has been stored since the last call.
+    pub async fn expiry_scheduled(&self) {
+        self.expiry_scheduled.notified().await
+    }
+
+    /// Which of `targets` aren't a signed-in device (session or access token) of the user.
+    pub async fn unknown_targets(
+        &self,
+        user_id: &Uuid,
+        targets: &[Uuid],
+    ) -> Result<Vec<Uuid>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"SELECT target AS "target!" FROM UNNEST($2::UUID[]) AS target
+               WHERE target NOT IN (
+                   SELECT id FROM sessions
+                   WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+                   UNION ALL
+                   SELECT id FROM personal_access_tokens
+                   WHERE user_id = $1 AND revoked_at IS NULL
+                     AND (expires_at IS NULL OR expires_at > NOW())
+               )"#,
+            user_id,
+            targets
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// The user's newest clip with this content hash that `viewer` may see.
+    pub async fn clip_by_hash(
+        &self,
+        user_id: &Uuid,
+        viewer: Uuid,
+        hash: &str,
+    ) -> Result<Option<ClipboardMessage>, sqlx::Error> {
+        let row = sqlx::query_as!(
+            ClipRow,
+            r#"SELECT id, device_id, content, nonce, encrypted, kind, timestamp, expires_at,
+                      content_hash, mime_type, targets, pinned_at IS NOT NULL AS "pinned!"
+               FROM clips WHERE user_id = $1 AND content_hash = $2
+                 AND (targets IS NULL OR $3::UUID = ANY(targets) OR device_id = $3::UUID::TEXT)
+               ORDER BY id DESC LIMIT 1"#,
+            user_id,
+            hash,
+            viewer
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(Into::into))
+    }
+
+    pub async fn storage_usage(&self, user_id: &Uuid) -> Result<StorageUsage, sqlx::Error> {
+        let usage = sqlx::query!(
+            r#"SELECT COUNT(*) AS "clips!",
+                      COUNT(pinned_at) AS "pinned!",
+                      COALESCE(SUM(OCTET_LENGTH(content)), 0)::BIGINT AS "bytes_used!",
+                      (SELECT COALESCE(storage_quota_bytes, $2) FROM users WHERE id = $1)
+                          AS "quota_bytes!"
+               FROM clips WHERE user_id = $1"#,
+            user_id,
+            self.config.storage_quota_bytes
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(StorageUsage {
+            clips: usage.clips,
+            pinned: usage.pinned,
+            bytes_used: usage.bytes_used,
+            quota_bytes: usage.quota_bytes,
+            max_clip_bytes: self.config.max_clip_bytes,
+        })
+    }
+
+    /// Applies a changed retention policy's item and size limits straight away. Age limits
+    /// wait for the next sweep.
+    pub async fn apply_retention(&self, user_id: Uuid) -> Result<u64, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let trimmed = trim_history(&mut conn, user_id).await?;
+        let removed = trimmed.len() as u64;
+        if !trimmed.is_empty() {
+            self.publish(&user_id, HubEvent::Deleted(trimmed));
+        }
+        Ok(removed)
+    }
+
+    /// Everything stored for the user that `viewer` may see, newest first.
+    pub async fn get_history(
+        &self,
+        user_id: &Uuid,
+        viewer: Uuid,
+    ) -> Result<Vec<ClipboardMessage>, sqlx::Error> {
+        let rows = sqlx::query_as!(
+            ClipRow,
+            r#"SELECT id, device_id, content, nonce, encrypted, kind, timestamp, expires_at,
+                      content_hash, mime_type, targets, pinned_at IS NOT NULL AS "pinned!"
+               FROM clips WHERE user_id = $1
+                 AND (targets IS NULL OR $2::UUID = ANY(targets) OR device_id = $2::UUID::TEXT)
+               ORDER BY id DESC"#,
+            user_id,
+            viewer
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    /// Removes one clip and tells the user's devices. `false` if they had no such clip.
+    pub async fn delete_clip(&self, user_id: &Uuid, id: i64) -> Result<bool, sqlx::Error> {
+        let deleted = sqlx::query!(
+            "DELETE FROM clips WHERE id = $1 AND user_id = $2",
+            id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        let found = deleted.rows_affected() > 0;
+        if found {
+            self.publish(user_id, HubEvent::Deleted(vec![id]));
+        }
+        Ok(found)
+    }
+
+    /// Removes the unpinned clips matching `query` and tells the user's devices which
+    /// ones went.
+    pub async fn delete_history(
+        &self,
+        user_id: &Uuid,
+        query: &DeleteHistoryQuery,
+    ) -> Result<Vec<i64>, sqlx::Error> {
+        let ids = sqlx::query_scalar!(
+            "DELETE FROM clips WHERE user_id = $1 AND pinned_at IS NULL
+               AND ($2::TEXT IS NULL OR device_id = $2)
+               AND ($3::BIGINT IS NULL OR timestamp >= $3)
+               AND ($4::BIGINT IS NULL OR timestamp <= $4)
+             RETURNING id",
+            user_id,
+            query.device,
+            query.from,
+            query.to
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        // Even `all` names the ids, since pinned clips stay behind.
+        if !ids.is_empty() {
+            self.publish(user_id, HubEvent::Deleted(ids.clone()));
+        }
+        Ok(ids)
+    }
+
+    /// Pins or unpins a clip and tells the user's devices. The cap is checked in the same
+    /// statement and only for clips that aren't pinned yet, so re-pinning is always a no-op.
+    pub async fn set_pinned(
+        &self,
+        user_id: &Uuid,
+        id: i64,
+        pinned: bool,
+    ) -> Result<PinOutcome, sqlx::Error> {
+        let updated = sqlx::query!(
+            "UPDATE clips SET pinned_at = CASE WHEN $3 THEN COALESCE(pinned_at, NOW()) END
+             WHERE id = $1 AND user_id = $2
+               AND (NOT $3 OR pinned_at IS NOT NULL OR (
+                   SELECT COUNT(*) FROM clips WHERE user_id = $2 AND pinned_at IS NOT NULL
+               ) < $4)",
+            id,
+            user_id,
+            pinned,
+            MAX_PINNED_CLIPS
+        )
+        .execute(&self.pool)
+        .await?;
+
+        if updated.rows_affected() > 0 {
+            self.publish(user_id, HubEvent::Pinned { id, pinned });
+            return Ok(PinOutcome::Done);
+        }
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM clips WHERE id = $1 AND user_id = $2) AS "exists!""#,
+            id,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(if exists {
+            PinOutcome::LimitReached
+        } else {
+            PinOutcome::NotFound
+        })
+    }
+
+    /// Pinned clips `viewer` may see, most recently pinned first.
+    pub async fn pinned_clips(
+        &self,
+        user_id: &Uuid,
+        viewer: Uuid,
+    ) -> Result<Vec<ClipboardMessage>, sqlx::Error> {
+        let rows = sqlx::query_as!(
+            ClipRow,
+            r#"SELECT id, device_id, content, nonce, encrypted, kind, timestamp, expires_at,
+                      content_hash, mime_type, targets, TRUE AS "pinned!"
+               FROM clips WHERE user_id = $1 AND pinned_at IS NOT NULL
+                 AND (targets IS NULL OR $2::UUID = ANY(targets) OR device_id = $2::UUID::TEXT)
+               ORDER BY pinned_at DESC, id DESC"#,
+            user_id,
+            viewer
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Into::into).collect())
     }
 
-    pub fn get_or_create_channel(&self, user_id: Uuid) -> broadcast::Sender<ClipboardMessage> {
+    /// Ranked full-text matches for free text among the plaintext clips `viewer` may see.
+    ///
+    /// The text goes through the same `simple` parser as the stored clips, so URLs, hosts
+    /// and emails stay whole, and each resulting lexeme is matched as a prefix. Lexemes are
+    /// quoted, so user input can't inject tsquery operators.
+    pub async fn search_history(
+        &self,
+        user_id: &Uuid,
+        viewer: Uuid,
+        text: &str,
+        limit: i64,
+    ) -> Result<SearchResults, sqlx::Error> {
+        let rows = sqlx::query!(
+            r#"SELECT id, device_id, content, nonce, encrypted, kind, timestamp, expires_at,
+                      content_hash, mime_type, targets, pinned_at IS NOT NULL AS "pinned!",
+                      ts_rank(search, q) AS "rank!",
+                      ts_headline(
+                          'simple',
+                          replace(replace(replace(content, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'),
+                          q,
+                          'StartSel=<mark>, StopSel=</mark>, MaxFragments=2, MaxWords=20, MinWords=5'
+                      ) AS "snippet!"
+               FROM clips, (
+                   SELECT string_agg(
+                       '''' || replace(replace(lexeme, '\', '\\'), '''', '''''') || ''':*', ' & '
+                   )::tsquery
+                   FROM (SELECT lexeme FROM unnest(to_tsvector('simple', $2)) LIMIT $4) AS terms
+               ) AS query(q)
+               WHERE user_id = $1 AND search @@ q
+                 AND (targets IS NULL OR $5::UUID = ANY(targets) OR device_id = $5::UUID::TEXT)
+               ORDER BY ts_rank(search, q) DESC, id DESC
+               LIMIT $3"#,
+            user_id,
+            text,
+            limit,
+            MAX_SEARCH_TERMS,
+            viewer
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let unsearchable = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM clips
+               WHERE user_id = $1 AND search IS NULL
+                 AND (targets IS NULL OR $2::UUID = ANY(targets) OR device_id = $2::UUID::TEXT)"#,
+            user_id,
+            viewer
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        let items = rows
+            .into_iter()
+            .map(|r| SearchHit {
+                clip: ClipRow {
+                    id: r.id,
+                    device_id: r.device_id,
+                    content: r.content,
+                    nonce: r.nonce,
+                    encrypted: r.encrypted,
+                    kind: r.kind,
+                    timestamp: r.timestamp,
+                    expires_at: r.expires_at,
+                    content_hash: r.content_hash,
+                    mime_type: r.mime_type,
+                    targets: r.targets,
+                    pinned: r.pinned,
+                }
+                .into(),
+                rank: r.rank,
+                snippet: r.snippet,
+            })
+            .collect();
+
+        Ok(SearchResults {
+            items,
+            unsearchable,
+        })
+    }
+
+    /// One page of the history `viewer` may see matching `query`. Fetches a row past
+    /// `limit` to tell whether there's another page.
+    pub async fn history_page(
+        &self,
+        user_id: &Uuid,
+        viewer: Uuid,
+        query: &HistoryQuery,
+        limit: i64,
+    ) -> Result<HistoryPage, sqlx::Error> {
+        let kind = query.kind.map(ClipKind::as_str);
+        let mut rows = if let Some(since) = query.since {
+            sqlx::query_as!(
+                ClipRow,
+                r#"SELECT id, device_id, content, nonce, encrypted, kind, timestamp, expires_at,
+                          content_hash, mime_type, targets, pinned_at IS NOT NULL AS "pinned!"
+                   FROM clips
+                   WHERE user_id = $1 AND id > $2
+                     AND ($3::TEXT IS NULL OR device_id = $3)
+                     AND ($4::BIGINT IS NULL OR timestamp >= $4)
+                     AND ($5::BIGINT IS NULL OR timestamp <= $5)
+                     AND ($6::TEXT IS NULL OR kind = $6)
+                     AND ($7::BOOLEAN IS NULL OR encrypted = $7)
+                     AND ($9::TEXT IS NULL OR content_hash = $9)
+                     AND (targets IS NULL OR $10::UUID = ANY(targets) OR device_id = $10::UUID::TEXT)
+                   ORDER BY id ASC LIMIT $8"#,
+                user_id,
+                since,
+                query.device,
+                query.from,
+                query.to,
+                kind,
+                query.encrypted,
+                limit + 1,
+                query.hash,
+                viewer
+            )
+            .fetch_all(&self.pool)
+            .await?
+        } else {
+            sqlx::query_as!(
+                ClipRow,
+                r#"SELECT id, device_id, content, nonce, encrypted, kind, timestamp, expires_at,
+                          content_hash, mime_type, targets, pinned_at IS NOT NULL AS "pinned!"
+                   FROM clips
+                   WHERE user_id = $1 AND ($2::BIGINT IS NULL OR id < $2)
+                     AND ($3::TEXT IS NULL OR device_id = $3)
+                     AND ($4::BIGINT IS NULL OR timestamp >= $4)
+                     AND ($5::BIGINT IS NULL OR timestamp <= $5)
+                     AND ($6::TEXT IS NULL OR kind = $6)
+                     AND ($7::BOOLEAN IS NULL OR encrypted = $7)
+                     AND ($9::TEXT IS NULL OR content_hash = $9)
+                     AND (targets IS NULL OR $10::UUID = ANY(targets) OR device_id = $10::UUID::TEXT)
+                   ORDER BY id DESC LIMIT $8"#,
+                user_id,
+                query.cursor,
+                query.device,
+                query.from,
+                query.to,
+                kind,
+                query.encrypted,
+                limit + 1,
+                query.hash,
+                viewer
+            )
+            .fetch_all(&self.pool)
+            .await?
+        };
+
+        let has_more = rows.len() as i64 > limit;
+        rows.truncate(limit as usize);
+        let next_cursor = has_more.then(|| rows.last().map(|r| r.id)).flatten();
+
+        Ok(HistoryPage {
+            items: rows.into_iter().map(Into::into).collect(),
+            next_cursor,
+        })
+    }
+
+    pub fn get_or_create_channel(&self, user_id: Uuid) -> broadcast::Sender<HubEvent> {
         self.hub
             .entry(user_id)
             .or_insert_with(|| broadcast::channel(100).0)
             .clone()
     }
 
-    pub fn cleanup_channel_if_empty(
-        &self,
-        user_id: &Uuid,
-        tx: &broadcast::Sender<ClipboardMessage>,
-    ) {
+    /// Sends to the user's connected devices, if there are any.
+    pub fn publish(&self, user_id: &Uuid, event: HubEvent) {
+        if let Some(tx) = self.hub.get(user_id) {
+            let _ = tx.send(event);
+        }
+    }
+
+    /// Counts a connection for the device and tells the user's other devices when it's
+    /// the device's first.
+    pub fn device_connected(&self, user_id: Uuid, device_id: Uuid) {
+        let mut count = self.presence.entry((user_id, device_id)).or_default();
+        *count += 1;
+        let first = *count == 1;
+        drop(count);
+        if first {
+            tracing::debug!(user = %user_id, device = %device_id, "device online");
+            let online = true;
+            self.publish(&user_id, HubEvent::Presence { device_id, online });
+        }
+    }
+
+    /// Undoes [`Self::device_connected`]; the device goes offline with its last connection.
+    pub fn device_disconnected(&self, user_id: Uuid, device_id: Uuid) {
+        let gone = self
+            .presence
+            .remove_if_mut(&(user_id, device_id), |_, count| {
+                *count -= 1;
+                *count == 0
+            })
+            .is_some();
+        if gone {
+            tracing::debug!(user = %user_id, device = %device_id, "device offline");
+            let online = false;
+            self.publish(&user_id, HubEvent::Presence { device_id, online });
+        }
+    }
+
+    pub fn is_online(&self, user_id: Uuid, device_id: Uuid) -> bool {
+        self.presence.contains_key(&(user_id, device_id))
+    }
+
+    /// Open WebSocket connections and event streams for one user.
+    pub fn connection_count(&self, user_id: &Uuid) -> usize {
+        self.hub
+            .get(user_id)
+            .map(|tx| tx.receiver_count())
+            .unwrap_or_default()
+    }
+
+    pub fn connection_counts(&self) -> Vec<(Uuid, usize)> {
+        self.hub
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().receiver_count()))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+
+    pub fn cleanup_channel_if_empty(&self, user_id: &Uuid, tx: &broadcast::Sender<HubEvent>) {
         if tx.receiver_count() == 0 {
             self.hub.remove(user_id);
             tracing::info!(user = %user_id, "fully disconnected");
@@ -103,6 +869,32 @@ impl AppState {
     }
 }
 
+/// Drops the user's oldest unpinned clips beyond their item and size limits and returns
+/// their ids. Callers publish them once the deletion is committed.
+async fn trim_history(
+    conn: &mut sqlx::PgConnection,
+    user_id: Uuid,
+) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "DELETE FROM clips WHERE id IN (
+            SELECT ranked.id FROM (
+                SELECT id,
+                       ROW_NUMBER() OVER newest AS position,
+                       SUM(OCTET_LENGTH(content)) OVER newest AS bytes
+                FROM clips WHERE user_id = $1 AND pinned_at IS NULL
+                WINDOW newest AS (ORDER BY id DESC)
+            ) AS ranked
+            JOIN users ON users.id = $1
+            WHERE ranked.position > COALESCE(users.history_max_items, $2)
+               OR ranked.bytes > users.history_max_bytes
+        ) RETURNING id",
+        user_id,
+        MAX_HISTORY_SIZE as i64
+    )
+    .fetch_all(conn)
+    .await
+}
+
 #[cfg(test)]
 pub use test_utils::*;
 
@@ -110,6 +902,8 @@ pub use test_utils::*;
 mod test_utils {
     use super::*;
 
+    type History = Arc<DashMap<Uuid, Vec<ClipboardMessage>>>;
+
     #[derive(Clone, Default)]
     pub struct SyncEngine {
         pub hub: Hub,
@@ -158,18 +952,14 @@ mod test_utils {
                 .unwrap_or_default()
         }
 
-        pub fn get_or_create_channel(&self, user_id: Uuid) -> broadcast::Sender<ClipboardMessage> {
+        pub fn get_or_create_channel(&self, user_id: Uuid) -> broadcast::Sender<HubEvent> {
             self.hub
                 .entry(user_id)
                 .or_insert_with(|| broadcast::channel(100).0)
                 .clone()
         }
 
-        pub fn cleanup_channel_if_empty(
-            &self,
-            user_id: &Uuid,
-            tx: &broadcast::Sender<ClipboardMessage>,
-        ) {
+        pub fn cleanup_channel_if_empty(&self, user_id: &Uuid, tx: &broadcast::Sender<HubEvent>) {
             if tx.receiver_count() == 0 {
                 self.hub.remove(user_id);
             }
@@ -147,7 +147,7 @@ mod history_tests {
 
 #[cfg(test)]
 mod models_tests {
-    use crate::models::ClipboardMessage;
+    use crate::models::{ClipKind, ClipboardMessage};
 
     #[test]
     fn clipboard_message_new_sets_defaults() {
@@ -168,6 +168,21 @@ mod models_tests {
         assert_eq!(msg1.device_id, msg2.device_id);
         assert_eq!(msg1.content, msg2.content);
     }
+
+    #[test]
+    fn detects_clip_kinds() {
+        assert_eq!(ClipKind::detect("https://example.com/a?b=c"), ClipKind::Url);
+        assert_eq!(ClipKind::detect("see https://example.com"), ClipKind::Text);
+        assert_eq!(ClipKind::detect("fn main() {"), ClipKind::Code);
+        assert_eq!(ClipKind::detect("x = 1\nif (x) return"), ClipKind::Code);
+        assert_eq!(ClipKind::detect("<div>hi</div>"), ClipKind::Code);
+        assert_eq!(
+            ClipKind::detect("curl -s localhost | jq [.]"),
+            ClipKind::Code
+        );
+        assert_eq!(ClipKind::detect("buy milk and eggs"), ClipKind::Text);
+        assert_eq!(ClipKind::detect("a < b but c > d"), ClipKind::Text);
+    }
 }
 
 #[cfg(test)]
@@ -220,3 +235,3354 @@ mod channel_tests {
         assert!(engine.hub.contains_key(&user_id));
     }
 }
+
+#[cfg(test)]
+mod token_tests {
+    use crate::token;
+
+    #[test]
+    fn generates_unique_hex_tokens() {
+        let a = token::generate();
+        let b = token::generate();
+
+        assert_eq!(a.len(), 64);
+        assert!(a.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn hash_is_deterministic_and_hides_token() {
+        let t = token::generate();
+
+        assert_eq!(token::hash(&t), token::hash(&t));
+        assert_ne!(token::hash(&t), t);
+        assert_ne!(token::hash(&t), token::hash(&token::generate()));
+    }
+
+    #[test]
+    fn codes_are_readable_and_forgiving_to_type() {
+        let code = token::generate_code();
+
+        assert_eq!(code.len(), 9);
+        assert_eq!(&code[4..5], "-");
+        assert!(!code.contains(['0', 'O', '1', 'I']));
+        assert_eq!(
+            token::hash_code(&code),
+            token::hash_code(&code.to_lowercase().replace('-', " "))
+        );
+    }
+}
+
+#[cfg(test)]
+mod mailer_tests {
+    use crate::mailer::{Email, LogMailer, Mailer};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn log_mailer_writes_to_dir() {
+        let dir = std::env::temp_dir().join(format!("echo-mail-{}", Uuid::new_v4()));
+        let mailer = LogMailer::new(Some(dir.clone()));
+
+        mailer
+            .send(Email {
+                to: "user@example.com".into(),
+                subject: "Hello".into(),
+                body: "token=abc".into(),
+            })
+            .await
+            .unwrap();
+
+        let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+
+        let contents = std::fs::read_to_string(entries[0].as_ref().unwrap().path()).unwrap();
+        assert!(contents.contains("To: user@example.com"));
+        assert!(contents.contains("token=abc"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod totp_tests {
+    use crate::totp;
+    use totp_rs::{Algorithm, TOTP};
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn code_at(secret: &[u8], time: u64) -> String {
+        TOTP::new(
+            Algorithm::SHA1,
+            6,
+            0,
+            30,
+            secret.to_vec(),
+            None,
+            String::new(),
+        )
+        .unwrap()
+        .generate(time)
+    }
+
+    #[test]
+    fn accepts_current_and_adjacent_codes() {
+        let secret = totp::generate_secret();
+
+        assert!(totp::verify(&secret, &code_at(&secret, NOW), NOW, None).is_some());
+        assert!(totp::verify(&secret, &code_at(&secret, NOW - 30), NOW, None).is_some());
+        assert!(totp::verify(&secret, &code_at(&secret, NOW - 90), NOW, None).is_none());
+    }
+
+    #[test]
+    fn rejects_replayed_step() {
+        let secret = totp::generate_secret();
+        let code = code_at(&secret, NOW);
+
+        let step = totp::verify(&secret, &code, NOW, None).unwrap();
+        assert!(totp::verify(&secret, &code, NOW, Some(step)).is_none());
+    }
+
+    #[test]
+    fn provisioning_uri_names_issuer_and_account() {
+        let secret = totp::generate_secret();
+        let (base32, uri) = totp::provisioning(&secret, "user@example.com").unwrap();
+
+        assert!(uri.starts_with("otpauth://totp/Echo:user%40example.com?"));
+        assert!(uri.contains(&format!("secret={base32}")));
+    }
+
+    #[test]
+    fn recovery_codes_are_formatted_and_normalized() {
+        let code = totp::generate_recovery_code();
+
+        assert_eq!(code.len(), 19);
+        assert_eq!(code.matches('-').count(), 3);
+        assert_eq!(
+            totp::hash_recovery_code(&code),
+            totp::hash_recovery_code(&code.replace('-', "").to_uppercase())
+        );
+    }
+}
+
+/// Helpers for tests that drive the full router against a throwaway database (`#[sqlx::test]`).
+#[cfg(test)]
+mod support {
+    use crate::{config::Config, state::AppState};
+    use axum::{
+        body::Body,
+        extract::connect_info::MockConnectInfo,
+        http::{header, Method, Request, StatusCode},
+        Router,
+    };
+    use futures::StreamExt;
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{self, client::IntoClientRequest, http::HeaderValue, Message},
+        MaybeTlsStream, WebSocketStream,
+    };
+    use tower::ServiceExt;
+
+    pub const ORIGIN: &str = "http://localhost:1420";
+
+    pub fn test_config() -> Config {
+        Config {
+            database_url: String::new(),
+            jwt_secret: Some("test-secret".into()),
+            jwt_signing_key: None,
+            jwt_verification_keys: Vec::new(),
+            app_url: ORIGIN.into(),
+            mail_from: "Echo <no-reply@localhost>".into(),
+            smtp_url: None,
+            mail_dir: None,
+            webauthn_rp_id: "localhost".into(),
+            webauthn_rp_origin: ORIGIN.into(),
+            ws_allow_query_token: false,
+            trust_proxy_headers: false,
+            run_migrations: false,
+            retention_sweep_secs: 60,
+            ws_max_frame_bytes: 64 * 1024,
+            max_clip_bytes: 1024,
+            storage_quota_bytes: 1024 * 1024,
+            oidc_issuer: None,
+            oidc_client_id: None,
+            oidc_client_secret: None,
+            oidc_redirect_uri: format!("{ORIGIN}/oidc/callback"),
+        }
+    }
+
+    pub fn test_app(pool: PgPool) -> Router {
+        with_connect_info(crate::app(AppState::new(pool, test_config()).unwrap()))
+    }
+
+    /// `oneshot` requests don't come from a socket, so supply a client address.
+    pub fn with_connect_info(app: Router) -> Router {
+        app.layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))))
+    }
+
+    pub async fn send(
+        app: &Router,
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let request = match body {
+            Some(body) => request
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        }
+        .unwrap();
+
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (status, value)
+    }
+
+    /// Registers a user and returns their session token.
+    pub async fn register(app: &Router, email: &str, password: &str) -> String {
+        let (status, body) = send(
+            app,
+            Method::POST,
+            "/register",
+            None,
+            Some(json!({
+                "first_name": "Test",
+                "last_name": "User",
+                "email": email,
+                "password": password,
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        body["token"].as_str().unwrap().to_string()
+    }
+
+    pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Opens an authenticated WebSocket to a server started with [`spawn`].
+    pub async fn connect(addr: SocketAddr, token: &str) -> Socket {
+        let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+        request.headers_mut().insert(
+            "sec-websocket-protocol",
+            HeaderValue::from_str(&format!("echo.v1, bearer.{token}")).unwrap(),
+        );
+        connect_async(request).await.unwrap().0
+    }
+
+    /// The next JSON frame, skipping pings; `None` once the socket closes.
+    pub async fn next_text(
+        ws: &mut (impl StreamExt<Item = Result<Message, tungstenite::Error>> + Unpin),
+    ) -> Option<Value> {
+        while let Some(Ok(msg)) = ws.next().await {
+            match msg {
+                Message::Text(text) => return serde_json::from_str(&text).ok(),
+                Message::Close(_) => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    pub async fn close_code(ws: &mut Socket) -> Option<u16> {
+        while let Some(Ok(msg)) = ws.next().await {
+            if let Message::Close(frame) = msg {
+                return frame.map(|f| u16::from(f.code));
+            }
+        }
+        None
+    }
+
+    /// Connects two devices of the user behind `token`. The second signs in with its own
+    /// session, since a device doesn't hear its own clips; the first has already heard
+    /// it come online.
+    pub async fn devices(app: &Router, token: &str) -> (Socket, Socket) {
+        let (_, me) = send(app, Method::GET, "/me", Some(token), None).await;
+        let credentials = json!({ "email": me["email"], "password": "password" });
+        let (_, login) = send(app, Method::POST, "/login", None, Some(credentials)).await;
+        let addr = spawn(app.clone()).await;
+        let mut laptop = connect(addr, token).await;
+        // Each socket subscribes to the user's channel just after the upgrade.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let phone = connect(addr, login["token"].as_str().unwrap()).await;
+        let online = next_text(&mut laptop).await.unwrap();
+        assert_eq!(online["type"], "device_online");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        (laptop, phone)
+    }
+
+    /// Serves the app on a random local port, for tests that need a real socket.
+    pub async fn spawn(app: Router) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+}
+
+#[cfg(test)]
+mod two_factor_login_tests {
+    use super::support::{register, send, test_app};
+    use axum::{
+        http::{Method, StatusCode},
+        Router,
+    };
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use totp_rs::{Algorithm, Secret, TOTP};
+
+    fn code_at(secret: &str, time: u64) -> String {
+        let secret = Secret::Encoded(secret.into()).to_bytes().unwrap();
+        TOTP::new(Algorithm::SHA1, 6, 0, 30, secret, None, String::new())
+            .unwrap()
+            .generate(time)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// Enrolls TOTP for the session's user; returns the secret and recovery codes.
+    async fn enroll(app: &Router, token: &str) -> (String, Vec<String>) {
+        let (status, enrollment) = send(app, Method::POST, "/me/2fa/totp", Some(token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let secret = enrollment["secret"].as_str().unwrap().to_string();
+        let code = Some(json!({ "code": code_at(&secret, now()) }));
+        let (status, codes) =
+            send(app, Method::POST, "/me/2fa/totp/confirm", Some(token), code).await;
+        assert_eq!(status, StatusCode::OK);
+        let codes = codes["recovery_codes"].as_array().unwrap();
+        let codes = codes.iter().map(|c| c.as_str().unwrap().to_string());
+        (secret, codes.collect())
+    }
+
+    async fn challenge(app: &Router) -> String {
+        let credentials = Some(json!({ "email": "2fa@example.com", "password": "password" }));
+        let (status, body) = send(app, Method::POST, "/login", None, credentials).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["two_factor_required"], true);
+        assert!(body.get("token").is_none());
+        body["challenge_token"].as_str().unwrap().to_string()
+    }
+
+    async fn second_step(app: &Router, body: Value) -> (StatusCode, Value) {
+        send(app, Method::POST, "/login/2fa", None, Some(body)).await
+    }
+
+    #[sqlx::test]
+    async fn login_needs_a_fresh_totp_code(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "2fa@example.com", "password").await;
+        let (secret, _) = enroll(&app, &token).await;
+
+        let challenge_token = challenge(&app).await;
+        let (status, _) = send(&app, Method::GET, "/me", Some(&challenge_token), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let swapped = json!({ "challenge_token": token, "code": code_at(&secret, now() + 30) });
+        let (status, _) = second_step(&app, swapped).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let wrong = json!({ "challenge_token": challenge_token, "code": "000000" });
+        let (status, _) = second_step(&app, wrong).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // The confirmation spent the current step, so sign in with the next one.
+        let code = code_at(&secret, now() + 30);
+        let body = json!({ "challenge_token": challenge_token, "code": code });
+        let (status, login) = second_step(&app, body.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let session = login["token"].as_str().unwrap();
+        let (status, _) = send(&app, Method::GET, "/me", Some(session), None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = second_step(&app, body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test]
+    async fn recovery_codes_work_once(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "2fa@example.com", "password").await;
+        let (_, codes) = enroll(&app, &token).await;
+
+        let body = json!({ "challenge_token": challenge(&app).await, "recovery_code": codes[0] });
+        let (status, _) = second_step(&app, body).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let body = json!({ "challenge_token": challenge(&app).await, "recovery_code": codes[0] });
+        let (status, _) = second_step(&app, body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let body = json!({ "challenge_token": challenge(&app).await, "recovery_code": codes[1] });
+        let (status, _) = second_step(&app, body).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
+
+#[cfg(test)]
+mod email_token_tests {
+    use super::support::{register, send, test_config};
+    use crate::state::AppState;
+    use axum::{
+        http::{Method, StatusCode},
+        Router,
+    };
+    use serde_json::json;
+    use sqlx::PgPool;
+    use std::{path::PathBuf, time::Duration};
+    use uuid::Uuid;
+
+    fn app_with_mail_dir(pool: PgPool) -> (Router, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("echo-mail-{}", Uuid::new_v4()));
+        let mut config = test_config();
+        config.mail_dir = Some(dir.clone());
+        let app = crate::app(AppState::new(pool, config).unwrap());
+        (app, dir)
+    }
+
+    /// Waits for an email with this subject, removes it and returns the token it links to.
+    async fn mailed_token(dir: &PathBuf, subject: &str) -> String {
+        for _ in 0..50 {
+            for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+                let contents = std::fs::read_to_string(entry.path()).unwrap();
+                if !contents.contains(&format!("Subject: {subject}")) {
+                    continue;
+                }
+                std::fs::remove_file(entry.path()).unwrap();
+                let (_, rest) = contents.split_once("token=").unwrap();
+                return rest.split_whitespace().next().unwrap().to_string();
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("no email with subject {subject:?}");
+    }
+
+    #[sqlx::test]
+    async fn verification_tokens_are_single_use_and_expire(pool: PgPool) {
+        let (app, dir) = app_with_mail_dir(pool.clone());
+        let session = register(&app, "verify@example.com", "password").await;
+        let subject = "Verify your Echo email address";
+        let expired = mailed_token(&dir, subject).await;
+        sqlx::query("UPDATE email_tokens SET expires_at = NOW() - INTERVAL '1 minute'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (status, _) = send(
+            &app,
+            Method::POST,
+            "/verify-email",
+            None,
+            Some(json!({ "token": expired })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = send(
+            &app,
+            Method::POST,
+            "/verify-email/resend",
+            Some(&session),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let token = mailed_token(&dir, subject).await;
+        let verify = Some(json!({ "token": token }));
+        let (status, _) = send(&app, Method::POST, "/verify-email", None, verify.clone()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let verified = sqlx::query_scalar::<_, bool>(
+            "SELECT email_verified_at IS NOT NULL FROM users WHERE email = 'verify@example.com'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert!(verified);
+
+        let (status, _) = send(&app, Method::POST, "/verify-email", None, verify).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[sqlx::test]
+    async fn password_reset_revokes_sessions_and_tokens_are_single_use(pool: PgPool) {
+        let (app, dir) = app_with_mail_dir(pool.clone());
+        let session = register(&app, "reset@example.com", "password").await;
+        let subject = "Reset your Echo password";
+        let forgot = Some(json!({ "email": "reset@example.com" }));
+
+        let (status, _) = send(&app, Method::POST, "/password/forgot", None, forgot.clone()).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let expired = mailed_token(&dir, subject).await;
+        sqlx::query("UPDATE email_tokens SET expires_at = NOW() - INTERVAL '1 minute'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let reset = |token: &str| Some(json!({ "token": token, "password": "new-password" }));
+        let (status, _) = send(&app, Method::POST, "/password/reset", None, reset(&expired)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Unknown emails are accepted too, so they can't be probed.
+        let unknown = Some(json!({ "email": "nobody@example.com" }));
+        let (status, _) = send(&app, Method::POST, "/password/forgot", None, unknown).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        send(&app, Method::POST, "/password/forgot", None, forgot).await;
+        let token = mailed_token(&dir, subject).await;
+        let (status, _) = send(&app, Method::POST, "/password/reset", None, reset(&token)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = send(&app, Method::GET, "/protected", Some(&session), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let login =
+            |password: &str| Some(json!({ "email": "reset@example.com", "password": password }));
+        let (status, _) = send(&app, Method::POST, "/login", None, login("password")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send(&app, Method::POST, "/login", None, login("new-password")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = send(&app, Method::POST, "/password/reset", None, reset(&token)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
+
+#[cfg(test)]
+mod profile_tests {
+    use super::support::{register, send, test_app};
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn updates_names_within_the_length_limit(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "profile@example.com", "password").await;
+
+        let (status, me) = send(&app, Method::GET, "/me", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(me["first_name"], "Test");
+
+        for name in [" ".to_string(), "x".repeat(101)] {
+            let body = Some(json!({ "first_name": name }));
+            let (status, _) = send(&app, Method::PATCH, "/me", Some(&token), body).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+
+        let body = Some(json!({ "first_name": "x".repeat(100), "last_name": "Lovelace" }));
+        let (status, me) = send(&app, Method::PATCH, "/me", Some(&token), body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(me["first_name"], "x".repeat(100));
+        assert_eq!(me["last_name"], "Lovelace");
+        assert_eq!(me["email"], "profile@example.com");
+    }
+
+    #[sqlx::test]
+    async fn changing_email_needs_the_password_and_clears_verification(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "old@example.com", "password").await;
+        register(&app, "taken@example.com", "password").await;
+        sqlx::query("UPDATE users SET email_verified_at = NOW()")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let body = Some(json!({ "email": "new@example.com" }));
+        let (status, _) = send(&app, Method::PATCH, "/me", Some(&token), body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let body = Some(json!({ "email": "taken@example.com", "current_password": "password" }));
+        let (status, _) = send(&app, Method::PATCH, "/me", Some(&token), body).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let body = Some(json!({ "email": "new@example.com", "current_password": "password" }));
+        let (status, me) = send(&app, Method::PATCH, "/me", Some(&token), body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(me["email"], "new@example.com");
+        assert_eq!(me["email_verified"], false);
+    }
+
+    #[sqlx::test]
+    async fn changing_password_signs_out_other_sessions_only(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "password@example.com", "password").await;
+        let login =
+            |password: &str| Some(json!({ "email": "password@example.com", "password": password }));
+        let (_, other) = send(&app, Method::POST, "/login", None, login("password")).await;
+        let other = other["token"].as_str().unwrap().to_string();
+
+        let wrong = Some(json!({ "current_password": "nope", "new_password": "new-password" }));
+        let (status, _) = send(&app, Method::POST, "/me/password", Some(&token), wrong).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let body = Some(json!({ "current_password": "password", "new_password": "new-password" }));
+        let (status, _) = send(&app, Method::POST, "/me/password", Some(&token), body).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = send(&app, Method::GET, "/me", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&app, Method::GET, "/me", Some(&other), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send(&app, Method::POST, "/login", None, login("new-password")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
+
+#[cfg(test)]
+mod passkey_tests {
+    use super::support::{register, send, test_app, ORIGIN};
+    use axum::http::{Method, StatusCode};
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+    use webauthn_rs::prelude::Url;
+
+    fn authenticator() -> WebauthnAuthenticator<SoftPasskey> {
+        WebauthnAuthenticator::new(SoftPasskey::new(true))
+    }
+
+    async fn register_passkey(
+        app: &axum::Router,
+        token: &str,
+        authenticator: &mut WebauthnAuthenticator<SoftPasskey>,
+    ) {
+        let (status, challenge) = send(
+            app,
+            Method::POST,
+            "/me/passkeys/register/start",
+            Some(token),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let options = serde_json::from_value(challenge["options"].clone()).unwrap();
+        let credential = authenticator
+            .do_registration(Url::parse(ORIGIN).unwrap(), options)
+            .unwrap();
+
+        let (status, _) = send(
+            app,
+            Method::POST,
+            "/me/passkeys/register/finish",
+            Some(token),
+            Some(json!({
+                "ceremony_id": challenge["ceremony_id"],
+                "name": "Laptop",
+                "credential": credential,
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    async fn start_login(app: &axum::Router, email: &str) -> Value {
+        let (status, challenge) = send(
+            app,
+            Method::POST,
+            "/login/passkey/start",
+            None,
+            Some(json!({ "email": email })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        challenge
+    }
+
+    #[sqlx::test]
+    async fn registers_and_logs_in_with_software_authenticator(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "passkey@example.com", "password").await;
+        let mut authenticator = authenticator();
+
+        register_passkey(&app, &token, &mut authenticator).await;
+
+        let (_, passkeys) = send(&app, Method::GET, "/me/passkeys", Some(&token), None).await;
+        assert_eq!(passkeys.as_array().unwrap().len(), 1);
+        assert_eq!(passkeys[0]["name"], "Laptop");
+
+        let challenge = start_login(&app, "passkey@example.com").await;
+        let options = serde_json::from_value(challenge["options"].clone()).unwrap();
+        let credential = authenticator
+            .do_authentication(Url::parse(ORIGIN).unwrap(), options)
+            .unwrap();
+
+        let (status, body) = send(
+            &app,
+            Method::POST,
+            "/login/passkey/finish",
+            None,
+            Some(json!({ "ceremony_id": challenge["ceremony_id"], "credential": credential })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let session = body["token"].as_str().unwrap();
+        let (status, me) = send(&app, Method::GET, "/me", Some(session), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(me["email"], "passkey@example.com");
+    }
+
+    #[sqlx::test]
+    async fn deleted_passkey_can_no_longer_log_in(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "passkey@example.com", "password").await;
+        register_passkey(&app, &token, &mut authenticator()).await;
+
+        let (_, passkeys) = send(&app, Method::GET, "/me/passkeys", Some(&token), None).await;
+        let uri = format!("/me/passkeys/{}", passkeys[0]["id"].as_str().unwrap());
+        let (status, _) = send(&app, Method::DELETE, &uri, Some(&token), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = send(
+            &app,
+            Method::POST,
+            "/login/passkey/start",
+            None,
+            Some(json!({ "email": "passkey@example.com" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test]
+    async fn ceremony_cannot_be_reused(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "passkey@example.com", "password").await;
+        let mut authenticator = authenticator();
+        register_passkey(&app, &token, &mut authenticator).await;
+
+        let challenge = start_login(&app, "passkey@example.com").await;
+        let options = serde_json::from_value(challenge["options"].clone()).unwrap();
+        let credential = authenticator
+            .do_authentication(Url::parse(ORIGIN).unwrap(), options)
+            .unwrap();
+        let body = json!({ "ceremony_id": challenge["ceremony_id"], "credential": credential });
+
+        let (first, _) = send(
+            &app,
+            Method::POST,
+            "/login/passkey/finish",
+            None,
+            Some(body.clone()),
+        )
+        .await;
+        let (second, _) = send(
+            &app,
+            Method::POST,
+            "/login/passkey/finish",
+            None,
+            Some(body),
+        )
+        .await;
+
+        assert_eq!(first, StatusCode::OK);
+        assert_eq!(second, StatusCode::BAD_REQUEST);
+    }
+}
+
+#[cfg(test)]
+mod access_token_tests {
+    use super::support::{register, send, test_app};
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    async fn create_token(app: &axum::Router, session: &str, scopes: &[&str]) -> (String, String) {
+        let (status, body) = send(
+            app,
+            Method::POST,
+            "/me/tokens",
+            Some(session),
+            Some(json!({ "name": "ci", "scopes": scopes })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        (
+            body["token"].as_str().unwrap().to_string(),
+            body["id"].as_str().unwrap().to_string(),
+        )
+    }
+
+    #[sqlx::test]
+    async fn scoped_token_reads_history_but_not_account(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let session = register(&app, "pat@example.com", "password").await;
+        let (token, id) = create_token(&app, &session, &["history:read"]).await;
+        assert!(token.starts_with("echo_pat_"));
+
+        let (status, _) = send(&app, Method::GET, "/history", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = send(&app, Method::GET, "/me", Some(&token), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let last_used: Option<chrono::DateTime<chrono::Utc>> = sqlx::query_scalar(
+            "SELECT last_used_at FROM personal_access_tokens WHERE id = $1::uuid",
+        )
+        .bind(&id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert!(last_used.is_some());
+    }
+
+    #[sqlx::test]
+    async fn token_without_scope_is_forbidden(pool: PgPool) {
+        let app = test_app(pool);
+        let session = register(&app, "pat@example.com", "password").await;
+        let (token, _) = create_token(&app, &session, &["clips:write"]).await;
+
+        let (status, _) = send(&app, Method::GET, "/history", Some(&token), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[sqlx::test]
+    async fn revoked_token_is_rejected(pool: PgPool) {
+        let app = test_app(pool);
+        let session = register(&app, "pat@example.com", "password").await;
+        let (token, id) = create_token(&app, &session, &["history:read"]).await;
+
+        let uri = format!("/me/tokens/{id}");
+        let (status, _) = send(&app, Method::DELETE, &uri, Some(&session), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = send(&app, Method::GET, "/history", Some(&token), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (_, tokens) = send(&app, Method::GET, "/me/tokens", Some(&session), None).await;
+        assert!(tokens.as_array().unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn lifetime_is_capped(pool: PgPool) {
+        let app = test_app(pool);
+        let session = register(&app, "pat@example.com", "password").await;
+
+        for (days, expected) in [
+            (3650, StatusCode::CREATED),
+            (3651, StatusCode::BAD_REQUEST),
+            (u32::MAX, StatusCode::BAD_REQUEST),
+        ] {
+            let body = json!({ "name": "ci", "scopes": ["history:read"], "expires_in_days": days });
+            let (status, _) =
+                send(&app, Method::POST, "/me/tokens", Some(&session), Some(body)).await;
+            assert_eq!(status, expected, "{days} days");
+        }
+    }
+}
+
+#[cfg(test)]
+mod keys_tests {
+    use crate::keys::JwtKeys;
+    use crate::models::Claims;
+    use ed25519_dalek::pkcs8::{spki::der::pem::LineEnding, EncodePrivateKey, EncodePublicKey};
+    use jsonwebtoken::Validation;
+
+    fn ed25519_pem() -> String {
+        ed25519_dalek::SigningKey::from_bytes(&rand::random())
+            .to_pkcs8_pem(LineEnding::LF)
+            .unwrap()
+            .to_string()
+    }
+
+    fn p256_pem() -> String {
+        p256::SecretKey::from_slice(&rand::random::<[u8; 32]>())
+            .unwrap()
+            .to_pkcs8_pem(LineEnding::LF)
+            .unwrap()
+            .to_string()
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            sub: "user".into(),
+            sid: "session".into(),
+            aud: "echo:session".into(),
+            iat: 0,
+            exp: usize::MAX / 2,
+        }
+    }
+
+    fn validation() -> Validation {
+        let mut validation = Validation::default();
+        validation.set_audience(&["echo:session"]);
+        validation
+    }
+
+    fn kid(token: &str) -> Option<String> {
+        jsonwebtoken::decode_header(token).unwrap().kid
+    }
+
+    #[test]
+    fn signs_with_kid_and_publishes_jwks() {
+        for pem in [ed25519_pem(), p256_pem()] {
+            let keys = JwtKeys::new(None, Some(&pem), &[]).unwrap();
+            let token = keys.encode(&claims()).unwrap();
+
+            let decoded: Claims = keys.decode(&token, validation()).unwrap();
+            assert_eq!(decoded.sub, "user");
+
+            let jwks = keys.jwks();
+            assert_eq!(jwks["keys"].as_array().unwrap().len(), 1);
+            assert_eq!(jwks["keys"][0]["kid"].as_str(), kid(&token).as_deref());
+            assert!(jwks["keys"][0].get("d").is_none());
+        }
+    }
+
+    #[test]
+    fn rotated_key_still_verifies_old_tokens() {
+        let old_pem = ed25519_pem();
+        let old_keys = JwtKeys::new(None, Some(&old_pem), &[]).unwrap();
+        let old_token = old_keys.encode(&claims()).unwrap();
+
+        let rotated = JwtKeys::new(None, Some(&p256_pem()), &[old_pem]).unwrap();
+        let new_token = rotated.encode(&claims()).unwrap();
+
+        assert_ne!(kid(&old_token), kid(&new_token));
+        assert!(rotated.decode::<Claims>(&old_token, validation()).is_ok());
+        assert_eq!(rotated.jwks()["keys"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn accepts_public_pem_for_verification() {
+        let signing = ed25519_dalek::SigningKey::from_bytes(&rand::random());
+        let private_pem = signing.to_pkcs8_pem(LineEnding::LF).unwrap().to_string();
+        let public_pem = signing
+            .verifying_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+
+        let token = JwtKeys::new(None, Some(&private_pem), &[])
+            .unwrap()
+            .encode(&claims())
+            .unwrap();
+        let verifier = JwtKeys::new(None, Some(&p256_pem()), &[public_pem]).unwrap();
+
+        assert!(verifier.decode::<Claims>(&token, validation()).is_ok());
+    }
+
+    #[test]
+    fn rejects_tokens_from_unknown_keys() {
+        let stranger = JwtKeys::new(None, Some(&ed25519_pem()), &[]).unwrap();
+        let keys = JwtKeys::new(None, Some(&ed25519_pem()), &[]).unwrap();
+
+        let token = stranger.encode(&claims()).unwrap();
+        assert!(keys.decode::<Claims>(&token, validation()).is_err());
+    }
+
+    #[test]
+    fn legacy_secret_tokens_survive_switch_to_asymmetric_keys() {
+        let legacy = JwtKeys::new(Some("secret"), None, &[]).unwrap();
+        let token = legacy.encode(&claims()).unwrap();
+        assert_eq!(kid(&token), None);
+
+        let keys = JwtKeys::new(Some("secret"), Some(&ed25519_pem()), &[]).unwrap();
+        assert!(keys.decode::<Claims>(&token, validation()).is_ok());
+        assert!(kid(&keys.encode(&claims()).unwrap()).is_some());
+        assert_eq!(keys.jwks()["keys"].as_array().unwrap().len(), 1);
+    }
+}
+
+#[cfg(test)]
+mod ws_auth_tests {
+    use super::support::{close_code, connect, next_text, register, send, spawn, test_app};
+    use crate::{
+        keys::JwtKeys,
+        models::{Claims, SESSION_AUDIENCE},
+    };
+    use axum::http::{Method, StatusCode};
+    use futures::{SinkExt, StreamExt};
+    use jsonwebtoken::Validation;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use std::time::Duration;
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{client::IntoClientRequest, http::HeaderValue, Error, Message},
+    };
+
+    /// Re-signs a session token so it expires in `secs` seconds.
+    fn expiring_in(token: &str, secs: usize) -> String {
+        let keys = JwtKeys::new(Some("test-secret"), None, &[]).unwrap();
+        let mut validation = Validation::default();
+        validation.set_audience(&[SESSION_AUDIENCE]);
+        let mut claims = keys.decode::<Claims>(token, validation).unwrap();
+        claims.exp = claims.iat + secs;
+        keys.encode(&claims).unwrap()
+    }
+
+    #[sqlx::test]
+    async fn query_token_is_rejected_by_default(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "ws@example.com", "password").await;
+        let addr = spawn(app).await;
+
+        let result = connect_async(format!("ws://{addr}/ws?token={token}")).await;
+        match result {
+            Err(Error::Http(response)) => assert_eq!(response.status(), 401),
+            other => panic!("expected 401, got {other:?}"),
+        }
+    }
+
+    #[sqlx::test]
+    async fn bearer_subprotocol_authenticates(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "ws@example.com", "password").await;
+        let addr = spawn(app).await;
+
+        let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+        request.headers_mut().insert(
+            "sec-websocket-protocol",
+            HeaderValue::from_str(&format!("echo.v1, bearer.{token}")).unwrap(),
+        );
+        let (_, response) = connect_async(request).await.unwrap();
+        assert_eq!(response.headers()["sec-websocket-protocol"], "echo.v1");
+    }
+
+    #[sqlx::test]
+    async fn first_frame_auth(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "ws@example.com", "password").await;
+        let addr = spawn(app).await;
+
+        let (mut ws, _) = connect_async(format!("ws://{addr}/ws")).await.unwrap();
+        ws.send(Message::text(
+            json!({ "type": "auth", "token": token }).to_string(),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(next_text(&mut ws).await.unwrap()["type"], "authenticated");
+    }
+
+    #[sqlx::test]
+    async fn bad_first_frame_closes_with_4001(pool: PgPool) {
+        let addr = spawn(test_app(pool)).await;
+
+        let (mut ws, _) = connect_async(format!("ws://{addr}/ws")).await.unwrap();
+        ws.send(Message::text(
+            json!({ "type": "auth", "token": "nope" }).to_string(),
+        ))
+        .await
+        .unwrap();
+
+        match ws.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4001),
+            other => panic!("expected close frame, got {other:?}"),
+        }
+    }
+
+    #[sqlx::test]
+    async fn ticket_is_single_use(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "ws@example.com", "password").await;
+        let (status, body) = send(&app, Method::POST, "/ws/ticket", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let ticket = body["ticket"].as_str().unwrap().to_string();
+        let addr = spawn(app).await;
+
+        let url = format!("ws://{addr}/ws?ticket={ticket}");
+        assert!(connect_async(&url).await.is_ok());
+        match connect_async(&url).await {
+            Err(Error::Http(response)) => assert_eq!(response.status(), 401),
+            other => panic!("expected 401, got {other:?}"),
+        }
+    }
+
+    #[sqlx::test]
+    async fn expiring_socket_is_asked_to_reauthenticate_then_closed(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "ws@example.com", "password").await;
+        let addr = spawn(app).await;
+
+        let mut ws = connect(addr, &expiring_in(&token, 2)).await;
+        assert_eq!(next_text(&mut ws).await.unwrap()["type"], "reauthenticate");
+        assert_eq!(close_code(&mut ws).await, Some(4002));
+    }
+
+    #[sqlx::test]
+    async fn in_band_refresh_keeps_socket_open(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "ws@example.com", "password").await;
+        let (status, body) = send(&app, Method::POST, "/token/refresh", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let refreshed = body["token"].as_str().unwrap().to_string();
+        let addr = spawn(app).await;
+
+        let mut ws = connect(addr, &expiring_in(&token, 2)).await;
+        assert_eq!(next_text(&mut ws).await.unwrap()["type"], "reauthenticate");
+        ws.send(Message::text(
+            json!({ "type": "auth", "token": refreshed }).to_string(),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(next_text(&mut ws).await.unwrap()["type"], "authenticated");
+
+        let closed = tokio::time::timeout(Duration::from_secs(3), close_code(&mut ws)).await;
+        assert!(closed.is_err(), "socket should stay open, got {closed:?}");
+    }
+
+    #[sqlx::test]
+    async fn revoking_token_closes_socket(pool: PgPool) {
+        let app = test_app(pool);
+        let session = register(&app, "ws@example.com", "password").await;
+        let (_, body) = send(
+            &app,
+            Method::POST,
+            "/me/tokens",
+            Some(&session),
+            Some(json!({ "name": "cli", "scopes": ["clips:subscribe"] })),
+        )
+        .await;
+        let token = body["token"].as_str().unwrap().to_string();
+        let id = body["id"].as_str().unwrap().to_string();
+        let addr = spawn(app.clone()).await;
+
+        let mut ws = connect(addr, &token).await;
+        let (status, _) = send(
+            &app,
+            Method::DELETE,
+            &format!("/me/tokens/{id}"),
+            Some(&session),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(close_code(&mut ws).await, Some(4003));
+    }
+}
+
+#[cfg(test)]
+mod oidc_tests {
+    use super::support::{register, send, test_config, with_connect_info};
+    use crate::{oidc::pkce_challenge, state::AppState};
+    use axum::{
+        extract::State,
+        http::{Method, StatusCode},
+        routing::{get, post},
+        Form, Json, Router,
+    };
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use ed25519_dalek::pkcs8::{spki::der::pem::LineEnding, EncodePrivateKey};
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    const CLIENT_ID: &str = "echo";
+
+    struct Grant {
+        challenge: String,
+        nonce: String,
+        sub: String,
+        email: String,
+        email_verified: bool,
+    }
+
+    /// Minimal OpenID provider: discovery, JWKS, and a token endpoint that checks PKCE.
+    struct MockProvider {
+        /// Where Echo is configured to find the provider.
+        issuer: String,
+        /// What the provider puts in discovery and `iss`, which may add a trailing slash.
+        published_issuer: String,
+        key: EncodingKey,
+        jwk: Value,
+        /// The `kid` ID tokens are signed with.
+        kid: Mutex<String>,
+        jwks_fetches: AtomicUsize,
+        grants: Mutex<HashMap<String, Grant>>,
+    }
+
+    impl MockProvider {
+        async fn spawn() -> Arc<Self> {
+            Self::spawn_publishing("").await
+        }
+
+        /// A provider whose published issuer ends in `suffix`, like Auth0's trailing `/`.
+        async fn spawn_publishing(suffix: &str) -> Arc<Self> {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let signing = ed25519_dalek::SigningKey::from_bytes(&rand::random());
+            let pem = signing.to_pkcs8_pem(LineEnding::LF).unwrap();
+
+            let provider = Arc::new(Self {
+                published_issuer: format!("{issuer}{suffix}"),
+                issuer,
+                key: EncodingKey::from_ed_pem(pem.as_bytes()).unwrap(),
+                jwk: json!({
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "x": URL_SAFE_NO_PAD.encode(signing.verifying_key().as_bytes()),
+                    "kid": "mock",
+                    "alg": "EdDSA",
+                }),
+                kid: Mutex::new("mock".into()),
+                jwks_fetches: AtomicUsize::new(0),
+                grants: Mutex::default(),
+            });
+
+            let router = Router::new()
+                .route("/.well-known/openid-configuration", get(discovery))
+                .route("/jwks", get(jwks))
+                .route("/token", post(token))
+                .with_state(Arc::clone(&provider));
+            tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+            provider
+        }
+
+        /// Stands in for the user signing in at the provider; returns `code` and `state`.
+        fn authorize(&self, url: &str, sub: &str, email: &str, verified: bool) -> (String, String) {
+            let url = reqwest::Url::parse(url).unwrap();
+            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+            assert_eq!(params["code_challenge_method"], "S256");
+
+            let code = crate::token::generate();
+            self.grants.lock().unwrap().insert(
+                code.clone(),
+                Grant {
+                    challenge: params["code_challenge"].clone(),
+                    nonce: params["nonce"].clone(),
+                    sub: sub.into(),
+                    email: email.into(),
+                    email_verified: verified,
+                },
+            );
+            (code, params["state"].clone())
+        }
+    }
+
+    async fn discovery(State(provider): State<Arc<MockProvider>>) -> Json<Value> {
+        let issuer = &provider.issuer;
+        Json(json!({
+            "issuer": provider.published_issuer,
+            "authorization_endpoint": format!("{issuer}/authorize"),
+            "token_endpoint": format!("{issuer}/token"),
+            "jwks_uri": format!("{issuer}/jwks"),
+        }))
+    }
+
+    async fn jwks(State(provider): State<Arc<MockProvider>>) -> Json<Value> {
+        provider.jwks_fetches.fetch_add(1, Ordering::SeqCst);
+        Json(json!({ "keys": [provider.jwk] }))
+    }
+
+    async fn token(
+        State(provider): State<Arc<MockProvider>>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<Value>, StatusCode> {
+        let grant = provider
+            .grants
+            .lock()
+            .unwrap()
+            .remove(&form["code"])
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        if pkce_challenge(&form["code_verifier"]) != grant.challenge {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = json!({
+            "iss": provider.published_issuer,
+            "aud": CLIENT_ID,
+            "sub": grant.sub,
+            "email": grant.email,
+            "email_verified": grant.email_verified,
+            "nonce": grant.nonce,
+            "given_name": "Sso",
+            "family_name": "User",
+            "iat": now,
+            "exp": now + 300,
+        });
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(provider.kid.lock().unwrap().clone());
+        let id_token = jsonwebtoken::encode(&header, &claims, &provider.key).unwrap();
+        Ok(Json(
+            json!({ "id_token": id_token, "token_type": "Bearer" }),
+        ))
+    }
+
+    fn app(pool: PgPool, provider: &MockProvider) -> Router {
+        let mut config = test_config();
+        config.oidc_issuer = Some(provider.issuer.clone());
+        config.oidc_client_id = Some(CLIENT_ID.into());
+        with_connect_info(crate::app(AppState::new(pool, config).unwrap()))
+    }
+
+    async fn sso_login(
+        app: &Router,
+        provider: &MockProvider,
+        sub: &str,
+        email: &str,
+        verified: bool,
+    ) -> (StatusCode, Value) {
+        let (status, start) = send(app, Method::POST, "/login/oidc/start", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (code, state) = provider.authorize(
+            start["authorization_url"].as_str().unwrap(),
+            sub,
+            email,
+            verified,
+        );
+        send(
+            app,
+            Method::POST,
+            "/login/oidc/finish",
+            None,
+            Some(json!({ "code": code, "state": state })),
+        )
+        .await
+    }
+
+    async fn user_id(app: &Router, token: &str) -> String {
+        let (_, me) = send(app, Method::GET, "/me", Some(token), None).await;
+        me["id"].as_str().unwrap().to_string()
+    }
+
+    #[sqlx::test]
+    async fn first_login_provisions_user_and_later_logins_reuse_it(pool: PgPool) {
+        let provider = MockProvider::spawn().await;
+        let app = app(pool, &provider);
+
+        let (status, body) = sso_login(&app, &provider, "sub-1", "sso@example.com", true).await;
+        assert_eq!(status, StatusCode::OK);
+        let token = body["token"].as_str().unwrap();
+        let (_, me) = send(&app, Method::GET, "/me", Some(token), None).await;
+        assert_eq!(me["email"], "sso@example.com");
+        assert_eq!(me["first_name"], "Sso");
+        assert_eq!(me["email_verified"], true);
+
+        // The provider's email can change; the subject is what identifies the account.
+        let (status, again) =
+            sso_login(&app, &provider, "sub-1", "renamed@example.com", true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            user_id(&app, again["token"].as_str().unwrap()).await,
+            me["id"].as_str().unwrap()
+        );
+    }
+
+    #[sqlx::test]
+    async fn links_existing_account_by_verified_email(pool: PgPool) {
+        let provider = MockProvider::spawn().await;
+        let app = app(pool.clone(), &provider);
+        let session = register(&app, "Linked@Example.com", "password").await;
+
+        let (status, _) = sso_login(&app, &provider, "sub-2", "linked@example.com", true).await;
+        assert_eq!(
+            status,
+            StatusCode::CONFLICT,
+            "unverified accounts aren't linked"
+        );
+
+        sqlx::query("UPDATE users SET email_verified_at = NOW()")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (status, body) = sso_login(&app, &provider, "sub-2", "linked@example.com", true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            user_id(&app, body["token"].as_str().unwrap()).await,
+            user_id(&app, &session).await
+        );
+    }
+
+    #[sqlx::test]
+    async fn accepts_an_issuer_published_with_a_trailing_slash(pool: PgPool) {
+        let provider = MockProvider::spawn_publishing("/").await;
+        let app = app(pool, &provider);
+
+        let (status, body) = sso_login(&app, &provider, "sub-5", "slash@example.com", true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["token"].is_string());
+    }
+
+    #[sqlx::test]
+    async fn unknown_key_ids_dont_refetch_the_jwks_every_time(pool: PgPool) {
+        let provider = MockProvider::spawn().await;
+        let app = app(pool, &provider);
+        let (status, _) = sso_login(&app, &provider, "sub-6", "keys@example.com", true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(provider.jwks_fetches.load(Ordering::SeqCst), 1);
+
+        *provider.kid.lock().unwrap() = "made-up".into();
+        for _ in 0..3 {
+            let (status, _) = sso_login(&app, &provider, "sub-6", "keys@example.com", true).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(provider.jwks_fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[sqlx::test]
+    async fn sso_accounts_can_be_deleted_right_after_signing_in(pool: PgPool) {
+        let provider = MockProvider::spawn().await;
+        let app = app(pool.clone(), &provider);
+
+        let (_, body) = sso_login(&app, &provider, "sub-7", "leaving@example.com", true).await;
+        let token = body["token"].as_str().unwrap();
+        let id = user_id(&app, token).await;
+
+        let (status, _) = send(&app, Method::DELETE, "/me", Some(token), Some(json!({}))).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(users, 0);
+        let recorded: Value = sqlx::query_scalar(
+            "SELECT details FROM audit_events WHERE event = 'account_deleted' AND user_id IS NULL",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(recorded["user_id"], id);
+    }
+
+    #[sqlx::test]
+    async fn totp_is_still_required_after_sso(pool: PgPool) {
+        let provider = MockProvider::spawn().await;
+        let app = app(pool.clone(), &provider);
+
+        let (_, body) = sso_login(&app, &provider, "sub-4", "mfa@example.com", true).await;
+        let id = user_id(&app, body["token"].as_str().unwrap()).await;
+        sqlx::query(
+            "INSERT INTO user_totp (user_id, secret, enabled_at) VALUES ($1::uuid, 'secret', NOW())",
+        )
+        .bind(&id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let (status, body) = sso_login(&app, &provider, "sub-4", "mfa@example.com", true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["two_factor_required"], true);
+        assert!(body["challenge_token"].is_string());
+        assert!(body.get("token").is_none());
+    }
+
+    #[sqlx::test]
+    async fn concurrent_first_logins_share_one_account(pool: PgPool) {
+        let provider = MockProvider::spawn().await;
+        let app = app(pool.clone(), &provider);
+
+        let logins = (0..4).map(|_| sso_login(&app, &provider, "sub-5", "race@example.com", true));
+        let mut ids = Vec::new();
+        for (status, body) in futures::future::join_all(logins).await {
+            assert_eq!(status, StatusCode::OK);
+            ids.push(user_id(&app, body["token"].as_str().unwrap()).await);
+        }
+        ids.dedup();
+        assert_eq!(ids.len(), 1);
+
+        let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(users, 1);
+    }
+
+    #[sqlx::test]
+    async fn rejects_unverified_email_and_replayed_state(pool: PgPool) {
+        let provider = MockProvider::spawn().await;
+        let app = app(pool, &provider);
+
+        let (status, _) = sso_login(&app, &provider, "sub-3", "new@example.com", false).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (_, start) = send(&app, Method::POST, "/login/oidc/start", None, None).await;
+        let url = start["authorization_url"].as_str().unwrap();
+        let (code, state) = provider.authorize(url, "sub-3", "new@example.com", true);
+        let body = json!({ "code": code, "state": state });
+        let (status, _) = send(
+            &app,
+            Method::POST,
+            "/login/oidc/finish",
+            None,
+            Some(body.clone()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&app, Method::POST, "/login/oidc/finish", None, Some(body)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
+
+#[cfg(test)]
+mod pairing_tests {
+    use super::support::{register, send, test_app};
+    use axum::{
+        http::{Method, StatusCode},
+        Router,
+    };
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+
+    async fn create(app: &Router, session: &str) -> (String, String) {
+        let (status, body) = send(app, Method::POST, "/pairing", Some(session), None).await;
+        assert_eq!(status, StatusCode::CREATED);
+        (
+            body["id"].as_str().unwrap().to_string(),
+            body["code"].as_str().unwrap().to_string(),
+        )
+    }
+
+    async fn redeem(app: &Router, code: &str) -> (StatusCode, Value) {
+        send(
+            app,
+            Method::POST,
+            "/pairing/redeem",
+            None,
+            Some(json!({ "code": code, "device_name": "Phone", "public_key": "pk" })),
+        )
+        .await
+    }
+
+    async fn poll(app: &Router, id: &str, poll_token: &str) -> (StatusCode, Value) {
+        send(
+            app,
+            Method::POST,
+            &format!("/pairing/{id}/poll"),
+            None,
+            Some(json!({ "poll_token": poll_token })),
+        )
+        .await
+    }
+
+    #[sqlx::test]
+    async fn approved_device_gets_its_own_session(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let session = register(&app, "pair@example.com", "password").await;
+        let (id, code) = create(&app, &session).await;
+
+        let (status, claim) = redeem(&app, &code.to_lowercase().replace('-', "")).await;
+        assert_eq!(status, StatusCode::OK);
+        let poll_token = claim["poll_token"].as_str().unwrap();
+
+        let (status, body) = poll(&app, &id, poll_token).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["status"], "claimed");
+
+        let uri = format!("/pairing/{id}");
+        let (_, info) = send(&app, Method::GET, &uri, Some(&session), None).await;
+        assert_eq!(info["device_name"], "Phone");
+        assert_eq!(info["device_public_key"], "pk");
+
+        let (status, _) = send(
+            &app,
+            Method::POST,
+            &format!("/pairing/{id}/approve"),
+            Some(&session),
+            Some(json!({ "sealed_key": "sealed" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, creds) = poll(&app, &id, poll_token).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(creds["sealed_key"], "sealed");
+        let token = creds["token"].as_str().unwrap();
+        let (status, _) = send(&app, Method::GET, "/me", Some(token), None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = poll(&app, &id, poll_token).await;
+        assert_eq!(
+            status,
+            StatusCode::BAD_REQUEST,
+            "credentials are handed out once"
+        );
+
+        let devices: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM sessions WHERE device_name = 'Phone'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(devices, 1);
+    }
+
+    #[sqlx::test]
+    async fn completion_is_tied_to_the_new_session(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let session = register(&app, "pair@example.com", "password").await;
+        let (id, code) = create(&app, &session).await;
+        let (_, claim) = redeem(&app, &code).await;
+        let poll_token = claim["poll_token"].as_str().unwrap();
+        let approve = format!("/pairing/{id}/approve");
+        let body = Some(json!({ "sealed_key": "sealed" }));
+        send(&app, Method::POST, &approve, Some(&session), body).await;
+
+        // A session that can't be created doesn't use up the approval.
+        let set_disabled = |disabled: bool| {
+            sqlx::query("UPDATE users SET disabled_at = CASE WHEN $1 THEN NOW() END")
+                .bind(disabled)
+                .execute(&pool)
+        };
+        set_disabled(true).await.unwrap();
+        assert_eq!(poll(&app, &id, poll_token).await.0, StatusCode::FORBIDDEN);
+        set_disabled(false).await.unwrap();
+
+        let polls = (0..4).map(|_| poll(&app, &id, poll_token));
+        let statuses: Vec<StatusCode> = futures::future::join_all(polls)
+            .await
+            .into_iter()
+            .map(|(status, _)| status)
+            .collect();
+        assert_eq!(statuses.iter().filter(|s| **s == StatusCode::OK).count(), 1);
+
+        let devices: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM sessions WHERE device_name = 'Phone'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(devices, 1);
+    }
+
+    #[sqlx::test]
+    async fn only_the_requesting_device_can_approve(pool: PgPool) {
+        let app = test_app(pool);
+        let session = register(&app, "pair@example.com", "password").await;
+        let (_, login) = send(
+            &app,
+            Method::POST,
+            "/login",
+            None,
+            Some(json!({ "email": "pair@example.com", "password": "password" })),
+        )
+        .await;
+        let other_session = login["token"].as_str().unwrap();
+
+        let (id, code) = create(&app, &session).await;
+        let (_, claim) = redeem(&app, &code).await;
+
+        let approve = format!("/pairing/{id}/approve");
+        let body = Some(json!({}));
+        let (status, _) = send(&app, Method::POST, &approve, Some(other_session), body).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let reject = format!("/pairing/{id}/reject");
+        let (status, _) = send(&app, Method::POST, &reject, Some(&session), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = poll(&app, &id, claim["poll_token"].as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[sqlx::test]
+    async fn codes_are_single_use_and_guesses_are_limited(pool: PgPool) {
+        let app = test_app(pool);
+        let session = register(&app, "pair@example.com", "password").await;
+        let (_, code) = create(&app, &session).await;
+
+        assert_eq!(redeem(&app, &code).await.0, StatusCode::OK);
+        assert_eq!(redeem(&app, &code).await.0, StatusCode::BAD_REQUEST);
+
+        let (_, code) = create(&app, &session).await;
+        for _ in 0..9 {
+            assert_eq!(redeem(&app, "AAAA-AAAA").await.0, StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(redeem(&app, &code).await.0, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[sqlx::test]
+    async fn code_creation_is_rate_limited(pool: PgPool) {
+        let app = test_app(pool);
+        let session = register(&app, "pair@example.com", "password").await;
+
+        for _ in 0..5 {
+            create(&app, &session).await;
+        }
+        let (status, _) = send(&app, Method::POST, "/pairing", Some(&session), None).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
+
+#[cfg(test)]
+mod session_tests {
+    use super::support::{register, send, test_app};
+    use axum::http::{Method, StatusCode};
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn refresh_stops_at_the_session_max_age(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "session@example.com", "password").await;
+
+        let (status, _) = send(&app, Method::POST, "/token/refresh", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Near the deadline the session is extended only up to it.
+        sqlx::query("UPDATE sessions SET max_expires_at = NOW() + INTERVAL '1 hour'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (status, _) = send(&app, Method::POST, "/token/refresh", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let capped: bool = sqlx::query_scalar("SELECT expires_at = max_expires_at FROM sessions")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(capped);
+
+        sqlx::query("UPDATE sessions SET max_expires_at = NOW() - INTERVAL '1 second'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (status, _) = send(&app, Method::POST, "/token/refresh", Some(&token), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[cfg(test)]
+mod account_tests {
+    use super::support::{close_code, connect, register, send, spawn, test_app};
+    use axum::http::{Method, StatusCode};
+    use futures::SinkExt;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::Message;
+    use totp_rs::{Algorithm, TOTP};
+
+    #[sqlx::test]
+    async fn export_includes_profile_devices_and_history(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "export@example.com", "password").await;
+        let addr = spawn(app.clone()).await;
+
+        let mut ws = connect(addr, &token).await;
+        ws.send(Message::text("hello")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let (status, export) = send(&app, Method::GET, "/me/export", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(export["profile"]["email"], "export@example.com");
+        assert_eq!(export["devices"].as_array().unwrap().len(), 1);
+        assert_eq!(export["clipboard_history"][0]["content"], "hello");
+    }
+
+    #[sqlx::test]
+    async fn delete_requires_password_and_removes_everything(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "delete@example.com", "password").await;
+        let addr = spawn(app.clone()).await;
+        let mut ws = connect(addr, &token).await;
+
+        let (status, _) = send(
+            &app,
+            Method::DELETE,
+            "/me",
+            Some(&token),
+            Some(json!({ "password": "wrong" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = send(
+            &app,
+            Method::DELETE,
+            "/me",
+            Some(&token),
+            Some(json!({ "password": "password" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(close_code(&mut ws).await, Some(4003));
+
+        let (status, _) = send(&app, Method::GET, "/me", Some(&token), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let sessions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sessions")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(sessions, 0);
+    }
+
+    #[sqlx::test]
+    async fn delete_without_password_needs_a_fresh_sign_in_or_totp(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "stale@example.com", "password").await;
+        sqlx::query("UPDATE sessions SET signed_in_at = NOW() - INTERVAL '1 hour'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let (status, _) = send(&app, Method::DELETE, "/me", Some(&token), Some(json!({}))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let secret = b"12345678901234567890".to_vec();
+        sqlx::query(
+            "INSERT INTO user_totp (user_id, secret, enabled_at) SELECT id, $1, NOW() FROM users",
+        )
+        .bind(&secret)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let wrong = Some(json!({ "code": "000000" }));
+        let (status, _) = send(&app, Method::DELETE, "/me", Some(&token), wrong).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let code = TOTP::new(Algorithm::SHA1, 6, 0, 30, secret, None, String::new())
+            .unwrap()
+            .generate_current()
+            .unwrap();
+        let body = Some(json!({ "code": code }));
+        let (status, _) = send(&app, Method::DELETE, "/me", Some(&token), body).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+}
+
+#[cfg(test)]
+mod audit_tests {
+    use super::support::{
+        close_code, connect, register, send, spawn, test_app, test_config, with_connect_info,
+    };
+    use crate::state::AppState;
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn logins_show_up_in_activity(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "audit@example.com", "password").await;
+
+        for password in ["wrong", "password"] {
+            send(
+                &app,
+                Method::POST,
+                "/login",
+                None,
+                Some(json!({ "email": "audit@example.com", "password": password })),
+            )
+            .await;
+        }
+
+        let (status, events) = send(&app, Method::GET, "/me/activity", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let names: Vec<&str> = events
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["login_succeeded", "login_failed", "registered"]);
+        assert_eq!(events[0]["details"]["method"], "password");
+
+        let before = events[1]["id"].as_i64().unwrap();
+        let (_, older) = send(
+            &app,
+            Method::GET,
+            &format!("/me/activity?before={before}&limit=1"),
+            Some(&token),
+            None,
+        )
+        .await;
+        assert_eq!(older.as_array().unwrap().len(), 1);
+        assert_eq!(older[0]["event"], "registered");
+    }
+
+    #[sqlx::test]
+    async fn proxy_header_ip_is_the_right_most_entry(pool: PgPool) {
+        use axum::{body::Body, http::Request};
+        use tower::ServiceExt;
+
+        let mut config = test_config();
+        config.trust_proxy_headers = true;
+        let app = with_connect_info(crate::app(AppState::new(pool.clone(), config).unwrap()));
+
+        let request = Request::post("/login")
+            .header("content-type", "application/json")
+            .header("x-forwarded-for", "1.2.3.4, 203.0.113.7")
+            .body(Body::from(
+                json!({ "email": "nobody@example.com", "password": "wrong" }).to_string(),
+            ))
+            .unwrap();
+        app.oneshot(request).await.unwrap();
+
+        let ip: Option<String> = sqlx::query_scalar("SELECT ip FROM audit_events")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(ip.as_deref(), Some("203.0.113.7"));
+    }
+
+    #[sqlx::test]
+    async fn operator_changes_show_up_in_activity(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "audit@example.com", "password").await;
+        let (_, me) = send(&app, Method::GET, "/me", Some(&token), None).await;
+        let id = me["id"].as_str().unwrap().parse().unwrap();
+
+        let details = json!({ "role": "admin" });
+        crate::audit::record_operator(&pool, id, crate::audit::AuditEvent::RoleChanged, details)
+            .await
+            .unwrap();
+
+        let (_, events) = send(&app, Method::GET, "/me/activity", Some(&token), None).await;
+        assert_eq!(events[0]["event"], "role_changed");
+        assert_eq!(events[0]["user_agent"], "echo-admin");
+        assert_eq!(events[0]["details"]["role"], "admin");
+    }
+
+    #[sqlx::test]
+    async fn events_cannot_be_altered(pool: PgPool) {
+        let app = test_app(pool.clone());
+        register(&app, "tamper@example.com", "password").await;
+
+        let update = sqlx::query("UPDATE audit_events SET event = 'nothing'")
+            .execute(&pool)
+            .await;
+        assert!(update.is_err());
+        let delete = sqlx::query("DELETE FROM audit_events").execute(&pool).await;
+        assert!(delete.is_err());
+    }
+
+    #[sqlx::test]
+    async fn revoking_a_device_disconnects_it(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "devices@example.com", "password").await;
+        let (_, login) = send(
+            &app,
+            Method::POST,
+            "/login",
+            None,
+            Some(json!({ "email": "devices@example.com", "password": "password" })),
+        )
+        .await;
+        let other = login["token"].as_str().unwrap().to_string();
+        let addr = spawn(app.clone()).await;
+        let mut ws = connect(addr, &other).await;
+
+        let (status, devices) = send(&app, Method::GET, "/me/devices", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let devices = devices.as_array().unwrap();
+        assert_eq!(devices.len(), 2);
+        let other_device = devices.iter().find(|d| d["current"] == false).unwrap();
+        let path = format!("/me/devices/{}", other_device["id"].as_str().unwrap());
+
+        let (status, _) = send(&app, Method::DELETE, &path, Some(&token), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(close_code(&mut ws).await, Some(4003));
+
+        let (status, _) = send(&app, Method::DELETE, &path, Some(&token), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (_, events) = send(&app, Method::GET, "/me/activity", Some(&token), None).await;
+        assert_eq!(events[0]["event"], "device_revoked");
+        assert_eq!(events[1]["event"], "device_connected");
+    }
+}
+
+#[cfg(test)]
+mod admin_tests {
+    use super::support::{close_code, connect, register, send, spawn, test_app};
+    use axum::{
+        http::{Method, StatusCode},
+        Router,
+    };
+    use serde_json::json;
+    use sqlx::PgPool;
+    use std::time::Duration;
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{client::IntoClientRequest, http::HeaderValue},
+    };
+
+    async fn admin(app: &Router, pool: &PgPool) -> String {
+        let token = register(app, "admin@example.com", "password").await;
+        sqlx::query("UPDATE users SET role = 'admin' WHERE email = 'admin@example.com'")
+            .execute(pool)
+            .await
+            .unwrap();
+        token
+    }
+
+    async fn user_id(app: &Router, token: &str) -> String {
+        let (_, me) = send(app, Method::GET, "/me", Some(token), None).await;
+        me["id"].as_str().unwrap().to_string()
+    }
+
+    async fn login(app: &Router, email: &str) -> (StatusCode, serde_json::Value) {
+        send(
+            app,
+            Method::POST,
+            "/login",
+            None,
+            Some(json!({ "email": email, "password": "password" })),
+        )
+        .await
+    }
+
+    #[sqlx::test]
+    async fn only_admins_can_use_the_admin_api(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let user = register(&app, "user@example.com", "password").await;
+        let admin = admin(&app, &pool).await;
+
+        let (status, _) = send(&app, Method::GET, "/admin/users", Some(&user), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let addr = spawn(app.clone()).await;
+        let _ws = connect(addr, &user).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let (status, users) = send(
+            &app,
+            Method::GET,
+            "/admin/users?q=USER%40",
+            Some(&admin),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let users = users.as_array().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0]["email"], "user@example.com");
+        assert_eq!(users[0]["role"], "user");
+        assert_eq!(users[0]["connections"], 1);
+
+        let (_, connections) =
+            send(&app, Method::GET, "/admin/connections", Some(&admin), None).await;
+        assert_eq!(connections[0]["user_id"], users[0]["id"]);
+    }
+
+    #[sqlx::test]
+    async fn disabled_users_are_signed_out_and_locked_out(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let user = register(&app, "disabled@example.com", "password").await;
+        let admin = admin(&app, &pool).await;
+        let id = user_id(&app, &user).await;
+        let addr = spawn(app.clone()).await;
+        let mut ws = connect(addr, &user).await;
+
+        let path = format!("/admin/users/{id}/disable");
+        let (status, _) = send(&app, Method::POST, &path, Some(&admin), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(close_code(&mut ws).await, Some(4003));
+
+        let (status, _) = login(&app, "disabled@example.com").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // A fresh session minted before the account was disabled can't open a socket either.
+        sqlx::query("UPDATE sessions SET revoked_at = NULL")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+        request.headers_mut().insert(
+            "sec-websocket-protocol",
+            HeaderValue::from_str(&format!("echo.v1, bearer.{user}")).unwrap(),
+        );
+        assert!(connect_async(request).await.is_err());
+
+        let path = format!("/admin/users/{id}/enable");
+        let (status, _) = send(&app, Method::POST, &path, Some(&admin), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = login(&app, "disabled@example.com").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn connection_limit_is_enforced(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let user = register(&app, "limited@example.com", "password").await;
+        let admin = admin(&app, &pool).await;
+        let id = user_id(&app, &user).await;
+
+        let (status, _) = send(
+            &app,
+            Method::PUT,
+            &format!("/admin/users/{id}/limits"),
+            Some(&admin),
+            Some(json!({ "max_messages_per_minute": null, "max_connections": 1 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let addr = spawn(app.clone()).await;
+        let _first = connect(addr, &user).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let mut second = connect(addr, &user).await;
+        assert_eq!(close_code(&mut second).await, Some(4004));
+    }
+
+    #[sqlx::test]
+    async fn admins_cannot_disable_themselves(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let admin = admin(&app, &pool).await;
+        let id = user_id(&app, &admin).await;
+
+        let path = format!("/admin/users/{id}/disable");
+        let (status, _) = send(&app, Method::POST, &path, Some(&admin), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
+
+#[cfg(test)]
+mod stored_history_tests {
+    use super::support::{register, send, test_app, test_config};
+    use crate::{
+        models::{ClipKind, ClipboardMessage},
+        state::AppState,
+    };
+    use axum::http::{Method, StatusCode};
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn history_survives_restarts_and_is_trimmed(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "stored@example.com", "password").await;
+        let (_, me) = send(&app, Method::GET, "/me", Some(&token), None).await;
+        let user_id = me["id"].as_str().unwrap().parse().unwrap();
+
+        let state = AppState::new(pool.clone(), test_config()).unwrap();
+        for i in 0..55 {
+            let msg = ClipboardMessage::new("d1", format!("msg_{i}"));
+            state.add_to_history(user_id, &msg).await.unwrap();
+        }
+
+        let restarted = test_app(pool);
+        let (status, history) = send(&restarted, Method::GET, "/history", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let history = history["items"].as_array().unwrap();
+        assert_eq!(history.len(), 50);
+        assert_eq!(history[0]["content"], "msg_54");
+        assert_eq!(history[49]["content"], "msg_5");
+    }
+
+    async fn seed(app: &axum::Router, pool: &PgPool, token: &str) {
+        let (_, me) = send(app, Method::GET, "/me", Some(token), None).await;
+        let user_id = me["id"].as_str().unwrap().parse().unwrap();
+        let state = AppState::new(pool.clone(), test_config()).unwrap();
+
+        let clips = [
+            (
+                "laptop",
+                "https://example.com",
+                false,
+                Some(ClipKind::Url),
+                1_000,
+            ),
+            ("laptop", "hello", false, Some(ClipKind::Text), 2_000),
+            ("phone", "fn main() {}", false, Some(ClipKind::Code), 3_000),
+            ("phone", "c2VjcmV0", true, None, 4_000),
+            ("laptop", "bye", false, Some(ClipKind::Text), 5_000),
+        ];
+        for (device, content, encrypted, kind, timestamp) in clips {
+            let mut msg = ClipboardMessage::new(device, content);
+            msg.encrypted = encrypted;
+            msg.kind = kind;
+            msg.timestamp = timestamp;
+            state.add_to_history(user_id, &msg).await.unwrap();
+        }
+    }
+
+    fn contents(page: &serde_json::Value) -> Vec<&str> {
+        page["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["content"].as_str().unwrap())
+            .collect()
+    }
+
+    #[sqlx::test]
+    async fn pages_backwards_with_a_cursor(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "pages@example.com", "password").await;
+        seed(&app, &pool, &token).await;
+
+        let (_, first) = send(&app, Method::GET, "/history?limit=2", Some(&token), None).await;
+        assert_eq!(contents(&first), ["bye", "c2VjcmV0"]);
+
+        let cursor = first["next_cursor"].as_i64().unwrap();
+        let uri = format!("/history?limit=2&cursor={cursor}");
+        let (_, second) = send(&app, Method::GET, &uri, Some(&token), None).await;
+        assert_eq!(contents(&second), ["fn main() {}", "hello"]);
+
+        let cursor = second["next_cursor"].as_i64().unwrap();
+        let uri = format!("/history?limit=2&cursor={cursor}");
+        let (_, last) = send(&app, Method::GET, &uri, Some(&token), None).await;
+        assert_eq!(contents(&last), ["https://example.com"]);
+        assert!(last["next_cursor"].is_null());
+    }
+
+    #[sqlx::test]
+    async fn syncs_forwards_from_since(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "since@example.com", "password").await;
+        seed(&app, &pool, &token).await;
+
+        let (_, all) = send(&app, Method::GET, "/history", Some(&token), None).await;
+        let third_newest = all["items"][2]["id"].as_i64().unwrap();
+
+        let uri = format!("/history?since={third_newest}");
+        let (_, newer) = send(&app, Method::GET, &uri, Some(&token), None).await;
+        assert_eq!(contents(&newer), ["c2VjcmV0", "bye"]);
+
+        let uri = format!("/history?since={third_newest}&cursor=1");
+        let (status, _) = send(&app, Method::GET, &uri, Some(&token), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn filters_history(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "filters@example.com", "password").await;
+        seed(&app, &pool, &token).await;
+
+        for (query, expected) in [
+            ("device=phone", vec!["c2VjcmV0", "fn main() {}"]),
+            ("kind=text", vec!["bye", "hello"]),
+            ("encrypted=true", vec!["c2VjcmV0"]),
+            ("from=2000&to=3000", vec!["fn main() {}", "hello"]),
+            ("device=laptop&kind=url", vec!["https://example.com"]),
+        ] {
+            let uri = format!("/history?{query}");
+            let (status, page) = send(&app, Method::GET, &uri, Some(&token), None).await;
+            assert_eq!(status, StatusCode::OK, "{query}");
+            assert_eq!(contents(&page), expected, "{query}");
+        }
+    }
+
+    #[sqlx::test]
+    async fn searches_plaintext_history_by_prefix(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "search@example.com", "password").await;
+        seed(&app, &pool, &token).await;
+
+        let (_, me) = send(&app, Method::GET, "/me", Some(&token), None).await;
+        let user_id = me["id"].as_str().unwrap().parse().unwrap();
+        let state = AppState::new(pool, test_config()).unwrap();
+        for content in [
+            "curl -X POST http://localhost:8080/login",
+            "grep -c '<b>' index.html",
+        ] {
+            let msg = ClipboardMessage::new("laptop", content);
+            state.add_to_history(user_id, &msg).await.unwrap();
+        }
+
+        let (status, results) = send(
+            &app,
+            Method::GET,
+            "/history/search?q=cur%20loc",
+            Some(&token),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            contents(&results),
+            ["curl -X POST http://localhost:8080/login"]
+        );
+        assert_eq!(results["unsearchable"], 1);
+        let snippet = results["items"][0]["snippet"].as_str().unwrap();
+        assert!(snippet.contains("<mark>curl</mark>"), "{snippet}");
+
+        let (_, results) = send(
+            &app,
+            Method::GET,
+            "/history/search?q=grep",
+            Some(&token),
+            None,
+        )
+        .await;
+        let snippet = results["items"][0]["snippet"].as_str().unwrap();
+        assert_eq!(snippet, "<mark>grep</mark> -c '&lt;b&gt;' index.html");
+
+        let (_, results) = send(
+            &app,
+            Method::GET,
+            "/history/search?q=c2Vj",
+            Some(&token),
+            None,
+        )
+        .await;
+        assert!(contents(&results).is_empty());
+
+        let (status, _) = send(
+            &app,
+            Method::GET,
+            "/history/search?q=%21%3A*",
+            Some(&token),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn searches_urls_by_host(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "search@example.com", "password").await;
+        seed(&app, &pool, &token).await;
+
+        for query in ["example.com", "exam", "https%3A%2F%2Fexample.com"] {
+            let uri = format!("/history/search?q={query}");
+            let (status, results) = send(&app, Method::GET, &uri, Some(&token), None).await;
+            assert_eq!(status, StatusCode::OK, "{query}");
+            assert_eq!(contents(&results), ["https://example.com"], "{query}");
+        }
+
+        // Quotes and operators are just text.
+        let uri = "/history/search?q=it%27s%20%26%20example.com%20%7C%20%21";
+        let (status, results) = send(&app, Method::GET, uri, Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(contents(&results).is_empty());
+    }
+}
+
+#[cfg(test)]
+mod db_tests {
+    use crate::db;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn migrating_an_up_to_date_database_is_a_no_op(pool: PgPool) {
+        db::migrate(&pool).await.unwrap();
+        db::check_schema(&pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn refuses_a_schema_newer_than_the_build(pool: PgPool) {
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+             VALUES (99991231000000, 'from the future', TRUE, '\\x00', 0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert!(db::check_schema(&pool).await.is_err());
+        assert!(db::migrate(&pool).await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod history_sync_tests {
+    use super::support::{
+        connect, devices, next_text, register, send, spawn, test_app, test_config, Socket,
+    };
+    use crate::{models::ClipboardMessage, state::AppState};
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+    };
+    use futures::SinkExt;
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::Message;
+    use tower::ServiceExt;
+
+    async fn copy(ws: &mut Socket, other: &mut Socket, content: &str) -> Value {
+        ws.send(Message::text(content)).await.unwrap();
+        let clip = next_text(other).await.unwrap();
+        assert_eq!(clip["content"], content);
+        // Past the per-device rate limit's minimum interval.
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        clip
+    }
+
+    #[sqlx::test]
+    async fn deleting_a_clip_reaches_every_device(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "delete@example.com", "password").await;
+        let (mut laptop, mut phone) = devices(&app, &token).await;
+
+        let password = copy(&mut laptop, &mut phone, "hunter2").await;
+        copy(&mut laptop, &mut phone, "harmless").await;
+
+        let frame = json!({ "type": "delete", "id": password["id"] });
+        laptop.send(Message::text(frame.to_string())).await.unwrap();
+        for ws in [&mut laptop, &mut phone] {
+            let deleted = next_text(ws).await.unwrap();
+            assert_eq!(
+                deleted,
+                json!({ "type": "deleted", "ids": [password["id"]] })
+            );
+        }
+
+        let (_, history) = send(&app, Method::GET, "/history", Some(&token), None).await;
+        assert_eq!(history["items"].as_array().unwrap().len(), 1);
+        assert_eq!(history["items"][0]["content"], "harmless");
+
+        let path = format!("/history/{}", password["id"]);
+        let (status, _) = send(&app, Method::DELETE, &path, Some(&token), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = send(
+            &app,
+            Method::DELETE,
+            "/history?all=true",
+            Some(&token),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["deleted"], 1);
+        assert_eq!(
+            next_text(&mut phone).await.unwrap(),
+            json!({ "type": "deleted", "ids": [history["items"][0]["id"]] })
+        );
+    }
+
+    #[sqlx::test]
+    async fn deletes_by_device_and_refuses_empty_filters(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "filtered@example.com", "password").await;
+        let (mut laptop, mut phone) = devices(&app, &token).await;
+
+        let from_laptop = copy(&mut laptop, &mut phone, "one").await;
+        copy(&mut phone, &mut laptop, "two").await;
+
+        for uri in [
+            "/history",
+            "/history?all=true&device=x",
+            "/history?from=2&to=1",
+        ] {
+            let (status, _) = send(&app, Method::DELETE, uri, Some(&token), None).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+        }
+
+        let uri = format!(
+            "/history?device={}",
+            from_laptop["device_id"].as_str().unwrap()
+        );
+        let (status, body) = send(&app, Method::DELETE, &uri, Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["deleted"], 1);
+        let deleted = next_text(&mut phone).await.unwrap();
+        assert_eq!(deleted["ids"], json!([from_laptop["id"]]));
+
+        let (_, history) = send(&app, Method::GET, "/history", Some(&token), None).await;
+        assert_eq!(history["items"][0]["content"], "two");
+        assert_eq!(history["items"].as_array().unwrap().len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn clearing_everything_keeps_pinned_clips(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "keep@example.com", "password").await;
+        let (mut laptop, mut phone) = devices(&app, &token).await;
+
+        let kept = copy(&mut laptop, &mut phone, "keep me").await;
+        let gone = copy(&mut laptop, &mut phone, "lose me").await;
+        let path = format!("/history/{}/pin", kept["id"]);
+        let (status, _) = send(&app, Method::PUT, &path, Some(&token), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(next_text(&mut phone).await.unwrap()["type"], "pinned");
+
+        let (status, body) = send(
+            &app,
+            Method::DELETE,
+            "/history?all=true",
+            Some(&token),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["deleted"], 1);
+        assert_eq!(
+            next_text(&mut phone).await.unwrap(),
+            json!({ "type": "deleted", "ids": [gone["id"]] })
+        );
+
+        let (_, history) = send(&app, Method::GET, "/history", Some(&token), None).await;
+        let items = history["items"].as_array().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0]["id"], kept["id"]);
+        assert_eq!(items[0]["pinned"], true);
+    }
+
+    #[sqlx::test]
+    async fn repeats_are_dropped_and_clips_can_be_recopied_by_hash(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "dedup@example.com", "password").await;
+        let (mut laptop, mut phone) = devices(&app, &token).await;
+
+        let hello = copy(&mut laptop, &mut phone, "hello").await;
+        let hash = hello["hash"].as_str().unwrap().to_string();
+        assert_eq!(hash.len(), 64);
+
+        // The repeat is dropped, so the phone's next clip is "world".
+        laptop.send(Message::text("hello")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        copy(&mut laptop, &mut phone, "world").await;
+
+        let uri = format!("/history?hash={hash}");
+        let (status, page) = send(&app, Method::GET, &uri, Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+        assert_eq!(page["items"][0]["content"], "hello");
+
+        let recopy = json!({ "type": "recopy", "hash": hash });
+        laptop
+            .send(Message::text(recopy.to_string()))
+            .await
+            .unwrap();
+        let relayed = next_text(&mut phone).await.unwrap();
+        assert_eq!(relayed["content"], "hello");
+        assert_eq!(relayed["hash"], hash.as_str());
+        assert_ne!(relayed["id"], hello["id"]);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        let unknown = json!({ "type": "recopy", "hash": "0".repeat(64) });
+        laptop
+            .send(Message::text(unknown.to_string()))
+            .await
+            .unwrap();
+        let error = next_text(&mut laptop).await.unwrap();
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["code"], "unknown_hash");
+
+        let (_, history) = send(&app, Method::GET, "/history", Some(&token), None).await;
+        assert_eq!(history["items"].as_array().unwrap().len(), 3);
+    }
+
+    /// Signs in again and returns the new session's token and device id.
+    async fn sign_in(app: &axum::Router, email: &str) -> (String, Value) {
+        let credentials = json!({ "email": email, "password": "password" });
+        let (_, login) = send(app, Method::POST, "/login", None, Some(credentials)).await;
+        let token = login["token"].as_str().unwrap().to_string();
+        let (_, devices) = send(app, Method::GET, "/me/devices", Some(&token), None).await;
+        let current = devices
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|d| d["current"] == true);
+        (token, current.unwrap()["id"].clone())
+    }
+
+    #[sqlx::test]
+    async fn targeted_clips_only_reach_their_devices(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "targets@example.com", "password").await;
+        let (phone_token, phone_id) = sign_in(&app, "targets@example.com").await;
+        let (tablet_token, tablet_id) = sign_in(&app, "targets@example.com").await;
+        let addr = spawn(app.clone()).await;
+        let mut laptop = connect(addr, &token).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let mut phone = connect(addr, &phone_token).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let mut tablet = connect(addr, &tablet_token).await;
+        let online = |device: &Value| json!({ "type": "device_online", "device_id": device });
+        assert_eq!(next_text(&mut laptop).await.unwrap(), online(&phone_id));
+        assert_eq!(next_text(&mut laptop).await.unwrap(), online(&tablet_id));
+        assert_eq!(next_text(&mut phone).await.unwrap(), online(&tablet_id));
+
+        let clip = json!({
+            "device_id": "",
+            "content": "work snippet",
+            "timestamp": 0,
+            "targets": [phone_id],
+        });
+        laptop.send(Message::text(clip.to_string())).await.unwrap();
+        let received = next_text(&mut phone).await.unwrap();
+        assert_eq!(received["content"], "work snippet");
+        assert_eq!(received["targets"], json!([phone_id]));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        // The tablet skipped the targeted clip, so the next one it sees is this.
+        copy(&mut laptop, &mut tablet, "for everyone").await;
+
+        let (_, history) = send(&app, Method::GET, "/history", Some(&phone_token), None).await;
+        assert_eq!(history["items"][0]["content"], "for everyone");
+        assert!(history["items"][0].get("targets").is_none());
+        assert_eq!(history["items"][1]["targets"], json!([phone_id]));
+
+        let stranger = "00000000-0000-0000-0000-000000000001";
+        let clip = json!({
+            "device_id": "",
+            "content": "lost",
+            "timestamp": 0,
+            "targets": [stranger],
+        });
+        laptop.send(Message::text(clip.to_string())).await.unwrap();
+        let error = next_text(&mut laptop).await.unwrap();
+        assert_eq!(error["code"], "unknown_device");
+    }
+
+    #[sqlx::test]
+    async fn sockets_see_the_targeted_clips_they_sent(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "sender@example.com", "password").await;
+        let (_, phone_id) = sign_in(&app, "sender@example.com").await;
+        let (tablet_token, _) = sign_in(&app, "sender@example.com").await;
+        let addr = spawn(app.clone()).await;
+        let mut laptop = connect(addr, &token).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let clip = json!({
+            "device_id": "",
+            "content": "for the phone",
+            "timestamp": 0,
+            "targets": [phone_id],
+        });
+        laptop.send(Message::text(clip.to_string())).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let (_, history) = send(&app, Method::GET, "/history", Some(&token), None).await;
+        assert_eq!(history["items"][0]["content"], "for the phone");
+        let (_, me) = send(&app, Method::GET, "/me/devices", Some(&token), None).await;
+        assert_eq!(history["items"][0]["device_id"], me[0]["id"]);
+        let (_, history) = send(&app, Method::GET, "/history", Some(&tablet_token), None).await;
+        assert_eq!(history["items"], json!([]));
+    }
+
+    #[sqlx::test]
+    async fn targeted_clips_stay_out_of_other_devices_history(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "aimed@example.com", "password").await;
+        let (phone_token, phone_id) = sign_in(&app, "aimed@example.com").await;
+        let (tablet_token, _) = sign_in(&app, "aimed@example.com").await;
+
+        let uri = format!("/clips?targets={}", phone_id.as_str().unwrap());
+        let request = || {
+            Request::builder()
+                .method(Method::POST)
+                .uri(&uri)
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::CONTENT_TYPE, "text/plain")
+                .body(Body::from("work snippet"))
+                .unwrap()
+        };
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        // A repeat resolves to the stored clip, which the sender can still see.
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for (viewer, visible) in [(&token, 1), (&phone_token, 1), (&tablet_token, 0)] {
+            let (_, history) = send(&app, Method::GET, "/history", Some(viewer), None).await;
+            assert_eq!(history["items"].as_array().unwrap().len(), visible);
+            let (_, found) = send(
+                &app,
+                Method::GET,
+                "/history/search?q=work",
+                Some(viewer),
+                None,
+            )
+            .await;
+            assert_eq!(found["items"].as_array().unwrap().len(), visible);
+        }
+    }
+
+    #[sqlx::test]
+    async fn pinned_clips_survive_trimming_and_clearing(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "pins@example.com", "password").await;
+        let (mut laptop, mut phone) = devices(&app, &token).await;
+
+        let snippet = copy(&mut laptop, &mut phone, "ssh deploy@10.0.0.7").await;
+        let frame = json!({ "type": "pin", "id": snippet["id"] });
+        phone.send(Message::text(frame.to_string())).await.unwrap();
+        for ws in [&mut laptop, &mut phone] {
+            let pinned = next_text(ws).await.unwrap();
+            assert_eq!(pinned, json!({ "type": "pinned", "id": snippet["id"] }));
+        }
+
+        let (_, me) = send(&app, Method::GET, "/me", Some(&token), None).await;
+        let user_id = me["id"].as_str().unwrap().parse().unwrap();
+        let state = AppState::new(pool, test_config()).unwrap();
+        for i in 0..60 {
+            let msg = ClipboardMessage::new("d1", format!("msg_{i}"));
+            state.add_to_history(user_id, &msg).await.unwrap();
+        }
+
+        let (_, history) = send(&app, Method::GET, "/history?limit=200", Some(&token), None).await;
+        let items = history["items"].as_array().unwrap();
+        assert_eq!(items.len(), 51);
+        assert_eq!(items[50]["content"], "ssh deploy@10.0.0.7");
+        assert_eq!(items[50]["pinned"], true);
+
+        let (status, _) = send(
+            &app,
+            Method::DELETE,
+            "/history?all=true",
+            Some(&token),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, pinned) = send(&app, Method::GET, "/history/pinned", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(pinned[0]["id"], snippet["id"]);
+        assert_eq!(pinned.as_array().unwrap().len(), 1);
+
+        let path = format!("/history/{}/pin", snippet["id"]);
+        let (status, _) = send(&app, Method::DELETE, &path, Some(&token), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(next_text(&mut phone).await.unwrap()["type"], "deleted");
+        assert_eq!(
+            next_text(&mut phone).await.unwrap(),
+            json!({ "type": "unpinned", "id": snippet["id"] })
+        );
+
+        let (status, _) = send(&app, Method::PUT, "/history/999999/pin", Some(&token), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn repinning_at_the_cap_is_a_no_op(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "pins@example.com", "password").await;
+        let (_, me) = send(&app, Method::GET, "/me", Some(&token), None).await;
+        let user_id = me["id"].as_str().unwrap().parse().unwrap();
+        let state = AppState::new(pool.clone(), test_config()).unwrap();
+
+        // Pin as we go, or trimming would drop the older ones.
+        let mut ids = Vec::new();
+        for i in 0..101 {
+            let msg = ClipboardMessage::new("laptop", format!("clip {i}"));
+            let id = state.add_to_history(user_id, &msg).await.unwrap().id();
+            if i < 100 {
+                sqlx::query("UPDATE clips SET pinned_at = NOW() WHERE id = $1")
+                    .bind(id)
+                    .execute(&pool)
+                    .await
+                    .unwrap();
+            }
+            ids.push(id);
+        }
+
+        let pin = |id: i64| format!("/history/{id}/pin");
+        let (status, _) = send(&app, Method::PUT, &pin(ids[0]), Some(&token), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = send(&app, Method::PUT, &pin(ids[100]), Some(&token), None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, _) = send(&app, Method::DELETE, &pin(ids[0]), Some(&token), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = send(&app, Method::PUT, &pin(ids[100]), Some(&token), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+}
+
+#[cfg(test)]
+mod retention_tests {
+    use super::support::{
+        connect, devices, next_text, register, send, spawn, test_app, test_config,
+        with_connect_info,
+    };
+    use crate::{
+        models::{now_millis, ClipboardMessage},
+        retention,
+        state::AppState,
+    };
+    use axum::http::{Method, StatusCode};
+    use futures::SinkExt;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::Message;
+    use uuid::Uuid;
+
+    async fn user_id(app: &axum::Router, token: &str) -> Uuid {
+        let (_, me) = send(app, Method::GET, "/me", Some(token), None).await;
+        me["id"].as_str().unwrap().parse().unwrap()
+    }
+
+    fn contents(history: &serde_json::Value) -> Vec<&str> {
+        history["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["content"].as_str().unwrap())
+            .collect()
+    }
+
+    #[sqlx::test]
+    async fn item_and_size_limits_apply_on_insert_and_on_change(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "retention@example.com", "password").await;
+        let user_id = user_id(&app, &token).await;
+        let state = AppState::new(pool, test_config()).unwrap();
+
+        for content in ["aaaa", "bbbb", "cccc", "dddd", "eeee"] {
+            let msg = ClipboardMessage::new("d1", content);
+            state.add_to_history(user_id, &msg).await.unwrap();
+        }
+
+        let policy = json!({ "max_items": 4, "max_age_secs": null, "max_bytes": 10 });
+        let (status, _) = send(
+            &app,
+            Method::PUT,
+            "/me/retention",
+            Some(&token),
+            Some(policy.clone()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, history) = send(&app, Method::GET, "/history", Some(&token), None).await;
+        assert_eq!(contents(&history), ["eeee", "dddd"]);
+
+        let (_, stored) = send(&app, Method::GET, "/me/retention", Some(&token), None).await;
+        assert_eq!(stored, policy);
+
+        let msg = ClipboardMessage::new("d1", "ffff");
+        state.add_to_history(user_id, &msg).await.unwrap();
+        let (_, history) = send(&app, Method::GET, "/history", Some(&token), None).await;
+        assert_eq!(contents(&history), ["ffff", "eeee"]);
+
+        for policy in [json!({ "max_items": 0 }), json!({ "max_age_secs": 1 })] {
+            let (status, _) = send(
+                &app,
+                Method::PUT,
+                "/me/retention",
+                Some(&token),
+                Some(policy),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[sqlx::test]
+    async fn sweep_removes_old_and_expired_clips_but_keeps_pins(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "sweep@example.com", "password").await;
+        let user_id = user_id(&app, &token).await;
+        let state = AppState::new(pool.clone(), test_config()).unwrap();
+
+        let mut expired = ClipboardMessage::new("d1", "expired");
+        expired.expires_at = Some(now_millis() - 1);
+        state.add_to_history(user_id, &expired).await.unwrap();
+        let old = state
+            .add_to_history(user_id, &ClipboardMessage::new("d1", "old"))
+            .await
+            .unwrap()
+            .id();
+        let pinned = state
+            .add_to_history(user_id, &ClipboardMessage::new("d1", "pinned"))
+            .await
+            .unwrap()
+            .id();
+        state.set_pinned(&user_id, pinned, true).await.unwrap();
+        state
+            .add_to_history(user_id, &ClipboardMessage::new("d1", "new"))
+            .await
+            .unwrap();
+        sqlx::query("UPDATE clips SET created_at = NOW() - INTERVAL '2 hours' WHERE id = ANY($1)")
+            .bind(vec![old, pinned])
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let policy = json!({ "max_age_secs": 3600 });
+        send(
+            &app,
+            Method::PUT,
+            "/me/retention",
+            Some(&token),
+            Some(policy),
+        )
+        .await;
+        assert_eq!(retention::sweep(&state).await.unwrap(), 2);
+
+        let (_, history) = send(&app, Method::GET, "/history", Some(&token), None).await;
+        assert_eq!(contents(&history), ["new", "pinned"]);
+    }
+
+    #[sqlx::test]
+    async fn trimmed_and_aged_clips_are_announced(pool: PgPool) {
+        let state = AppState::new(pool.clone(), test_config()).unwrap();
+        let app = with_connect_info(crate::app(state.clone()));
+        let token = register(&app, "announce@example.com", "password").await;
+        let user_id = user_id(&app, &token).await;
+        let mut ws = connect(spawn(app.clone()).await, &token).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let add = |content: &'static str| {
+            let state = state.clone();
+            async move {
+                let msg = ClipboardMessage::new("d1", content);
+                state.add_to_history(user_id, &msg).await.unwrap().id()
+            }
+        };
+        let first = add("first").await;
+        let second = add("second").await;
+
+        let policy = Some(json!({ "max_items": 1 }));
+        send(&app, Method::PUT, "/me/retention", Some(&token), policy).await;
+        let deleted = next_text(&mut ws).await.unwrap();
+        assert_eq!(deleted, json!({ "type": "deleted", "ids": [first] }));
+
+        let third = add("third").await;
+        let deleted = next_text(&mut ws).await.unwrap();
+        assert_eq!(deleted, json!({ "type": "deleted", "ids": [second] }));
+
+        let policy = Some(json!({ "max_items": 1, "max_age_secs": 3600 }));
+        send(&app, Method::PUT, "/me/retention", Some(&token), policy).await;
+        sqlx::query("UPDATE clips SET created_at = NOW() - INTERVAL '2 hours'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(retention::sweep(&state).await.unwrap(), 1);
+        let deleted = next_text(&mut ws).await.unwrap();
+        assert_eq!(deleted, json!({ "type": "deleted", "ids": [third] }));
+    }
+
+    #[sqlx::test]
+    async fn clips_with_a_ttl_expire_on_every_device(pool: PgPool) {
+        // Expiry runs off the sweeper's timer; the interval alone would take a minute.
+        let state = AppState::new(pool, test_config()).unwrap();
+        retention::spawn_sweeper(state.clone());
+        let app = with_connect_info(crate::app(state));
+        let token = register(&app, "ttl@example.com", "password").await;
+        let (mut laptop, mut phone) = devices(&app, &token).await;
+
+        let clip = json!({ "device_id": "", "content": "hunter2", "timestamp": 0, "ttl_secs": 1 });
+        laptop.send(Message::text(clip.to_string())).await.unwrap();
+        let received = next_text(&mut phone).await.unwrap();
+        let expires_at = received["expires_at"].as_u64().unwrap();
+        assert!(expires_at > now_millis() && expires_at <= now_millis() + 1000);
+        assert!(received.get("ttl_secs").is_none());
+
+        for ws in [&mut laptop, &mut phone] {
+            let expired = next_text(ws).await.unwrap();
+            assert_eq!(
+                expired,
+                json!({ "type": "expired", "ids": [received["id"]] })
+            );
+        }
+        let (_, history) = send(&app, Method::GET, "/history", Some(&token), None).await;
+        assert!(contents(&history).is_empty());
+    }
+}
+
+#[cfg(test)]
+mod quota_tests {
+    use super::support::{
+        close_code, connect, devices, next_text, register, send, spawn, test_app,
+    };
+    use axum::http::{Method, StatusCode};
+    use futures::SinkExt;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[sqlx::test]
+    async fn oversized_clips_get_an_error_frame(pool: PgPool) {
+        let app = test_app(pool);
+        let token = register(&app, "size@example.com", "password").await;
+        let (mut laptop, mut phone) = devices(&app, &token).await;
+
+        laptop.send(Message::text("x".repeat(2000))).await.unwrap();
+        let error = next_text(&mut laptop).await.unwrap();
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["code"], "clip_too_large");
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        laptop.send(Message::text("small")).await.unwrap();
+        assert_eq!(next_text(&mut phone).await.unwrap()["content"], "small");
+
+        laptop
+            .send(Message::text("x".repeat(100 * 1024)))
+            .await
+            .unwrap();
+        assert_eq!(close_code(&mut laptop).await, Some(1009));
+    }
+
+    #[sqlx::test]
+    async fn clips_that_cant_be_stored_are_not_relayed(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "unsaved@example.com", "password").await;
+        let (mut laptop, mut phone) = devices(&app, &token).await;
+        sqlx::query("ALTER TABLE clips ADD CONSTRAINT no_boom CHECK (content <> 'boom')")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        laptop.send(Message::text("boom")).await.unwrap();
+        let error = next_text(&mut laptop).await.unwrap();
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["code"], "not_stored");
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        let body = Some(json!({ "content": "boom" }));
+        let (status, _) = send(&app, Method::POST, "/clips", Some(&token), body).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        laptop.send(Message::text("fine")).await.unwrap();
+        assert_eq!(next_text(&mut phone).await.unwrap()["content"], "fine");
+    }
+
+    #[sqlx::test]
+    async fn quota_rejects_clips_and_usage_reports_it(pool: PgPool) {
+        let app = test_app(pool.clone());
+        let token = register(&app, "quota@example.com", "password").await;
+        sqlx::query("UPDATE users SET storage_quota_bytes = 1500")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let addr = spawn(app.clone()).await;
+        let mut laptop = connect(addr, &token).await;
+
+        laptop.send(Message::text("a".repeat(1000))).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        laptop.send(Message::text("b".repeat(1000))).await.unwrap();
+        let error = next_text(&mut laptop).await.unwrap();
+        assert_eq!(error["code"], "quota_exceeded");
+
+        let (status, usage) = send(&app, Method::GET, "/me/usage", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            usage,
+            json!({
+                "clips": 1,
+                "pinned": 0,
+                "bytes_used": 1000,
+                "quota_bytes": 1500,
+                "max_clip_bytes": 1024,
+            })
+        );
+    }
+}
+
+#[cfg(test)]
+mod push_clip_tests {
+    use super::support::{connect, next_text, register, send, spawn, test_app};
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+        Router,
+    };
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    async fn push(
+        app: &Router,
+        token: &str,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/clips")
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    async fn create_token(app: &Router, session: &str, scopes: &[&str]) -> String {
+        let (status, body) = send(
+            app,
+            Method::POST,
+            "/me/tokens",
+            Some(session),
+            Some(json!({ "name": "script", "scopes": scopes })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        body["token"].as_str().unwrap().to_string()
+    }
+
+    #[sqlx::test]
+    async fn pushed_clips_reach_connected_devices(pool: PgPool) {
+        let app = test_app(pool);
+        let session = register(&app, "push@example.com", "password").await;
+        let token = create_token(&app, &session, &["clips:write"]).await;
+        let addr = spawn(app.clone()).await;
+        let mut laptop = connect(addr, &session).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let (status, clip) = push(&app, &token, "text/plain", b"make deploy".to_vec()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(clip["kind"], "text");
+        let relayed = next_text(&mut laptop).await.unwrap();
+        assert_eq!(relayed["content"], "make deploy");
+        assert_eq!(relayed["id"], clip["id"]);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        // A repeat isn't stored again; the caller gets the clip it repeated.
+        let (status, repeat) = push(&app, &token, "text/plain", b"make deploy".to_vec()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(repeat["id"], clip["id"]);
+        let (status, _) = push(&app, &token, "text/plain", b"again".to_vec()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        let read_only = create_token(&app, &session, &["history:read"]).await;
+        let (status, _) = push(&app, &read_only, "text/plain", b"nope".to_vec()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[sqlx::test]
+    async fn accepts_encrypted_and_binary_clips(pool: PgPool) {
+        let app = test_app(pool);
+        let session = register(&app, "binary@example.com", "password").await;
+
+        let encrypted = json!({
+            "content": "c2VjcmV0",
+            "encrypted": true,
+            "nonce": "bm9uY2U=",
+            "ttl_secs": 60,
+        });
+        let (status, clip) = push(
+            &app,
+            &session,
+            "application/json",
+            encrypted.to_string().into_bytes(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(clip["encrypted"], true);
+        assert_eq!(clip["nonce"], "bm9uY2U=");
+        assert!(clip["expires_at"].is_u64());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        let png = vec![0x89, b'P', b'N', b'G', 0, 1, 2, 0xff];
+        let (status, clip) = push(&app, &session, "image/png", png.clone()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(clip["kind"], "binary");
+        assert_eq!(clip["mime_type"], "image/png");
+        assert_eq!(clip["content"], STANDARD.encode(&png));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        let (status, _) = push(&app, &session, "application/octet-stream", vec![0; 1024]).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        let (status, results) = send(
+            &app,
+            Method::GET,
+            "/history/search?q=iVBOR",
+            Some(&session),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(results["items"], json!([]));
+        assert_eq!(results["unsearchable"], 2);
+    }
+}
+
+#[cfg(test)]
+mod event_stream_tests {
+    use super::support::{register, send, test_app};
+    use axum::{
+        body::{Body, BodyDataStream},
+        http::{header, Method, Request, StatusCode},
+        Router,
+    };
+    use futures::StreamExt;
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    struct Events {
+        body: BodyDataStream,
+        buffer: String,
+    }
+
+    impl Events {
+        async fn open(app: &Router, token: &str, last_event_id: Option<&str>) -> Self {
+            let mut request = Request::builder()
+                .uri("/events")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"));
+            if let Some(id) = last_event_id {
+                request = request.header("last-event-id", id);
+            }
+            Self::start(app, request).await
+        }
+
+        async fn start(app: &Router, request: axum::http::request::Builder) -> Self {
+            let response = app
+                .clone()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "text/event-stream"
+            );
+            Self {
+                body: response.into_body().into_data_stream(),
+                buffer: String::new(),
+            }
+        }
+
+        /// The next event's id and data, skipping keep-alive comments.
+        async fn next(&mut self) -> (Option<String>, Value) {
+            loop {
+                if let Some(end) = self.buffer.find("\n\n") {
+                    let raw: String = self.buffer.drain(..end + 2).collect();
+                    let field = |name: &str| {
+                        raw.lines()
+                            .find_map(|line| line.strip_prefix(name))
+                            .map(|value| value.trim_start().to_string())
+                    };
+                    if let Some(data) = field("data:") {
+                        return (field("id:"), serde_json::from_str(&data).unwrap());
+                    }
+                    continue;
+                }
+                let chunk = tokio::time::timeout(Duration::from_secs(5), self.body.next())
+                    .await
+                    .expect("timed out waiting for an event")
+                    .unwrap()
+                    .unwrap();
+                self.buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+            }
+        }
+    }
+
+    async fn push(app: &Router, token: &str, content: &str) -> Value {
+        let (status, clip) = send(
+            app,
+            Method::POST,
+            "/clips",
+            Some(token),
+            Some(json!({ "content": content })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        // Past the per-device rate limit's minimum interval.
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        clip
+    }
+
+    #[sqlx::test]
+    async fn devices_coming_and_going_are_announced(pool: PgPool) {
+        let app = test_app(pool);
+        let session = register(&app, "presence@example.com", "password").await;
+        let (_, login) = send(
+            &app,
+            Method::POST,
+            "/login",
+            None,
+            Some(json!({ "email": "presence@example.com", "password": "password" })),
+        )
+        .await;
+        let phone = login["token"].as_str().unwrap().to_string();
+        let (_, devices) = send(&app, Method::GET, "/me/devices", Some(&phone), None).await;
+        let phone_id = devices[1]["id"].clone();
+        assert_eq!(devices[1]["current"], true);
+        assert_eq!(devices[1]["online"], false);
+
+        let mut events = Events::open(&app, &session, None).await;
+        let phone_events = Events::open(&app, &phone, None).await;
+        let (_, frame) = events.next().await;
+        assert_eq!(
+            frame,
+            json!({ "type": "device_online", "device_id": phone_id })
+        );
+        let (_, devices) = send(&app, Method::GET, "/me/devices", Some(&session), None).await;
+        assert_eq!(devices[0]["online"], true);
+        assert_eq!(devices[1]["online"], true);
+
+        drop(phone_events);
+        let (_, frame) = events.next().await;
+        assert_eq!(
+            frame,
+            json!({ "type": "device_offline", "device_id": phone_id })
+        );
+        let (_, devices) = send(&app, Method::GET, "/me/devices", Some(&session), None).await;
+        assert_eq!(devices[1]["online"], false);
+    }
+
+    #[sqlx::test]
+    async fn browsers_can_subscribe_with_a_ticket(pool: PgPool) {
+        let app = test_app(pool);
+        let session = register(&app, "sse@example.com", "password").await;
+
+        let (status, _) = send(&app, Method::GET, "/events", None, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (_, ticket) = send(&app, Method::POST, "/ws/ticket", Some(&session), None).await;
+        let uri = format!("/events?ticket={}", ticket["ticket"].as_str().unwrap());
+        let mut events = Events::start(&app, Request::builder().uri(&uri)).await;
+        let (_, login) = send(
+            &app,
+            Method::POST,
+            "/login",
+            None,
+            Some(json!({ "email": "sse@example.com", "password": "password" })),
+        )
+        .await;
+        push(&app, login["token"].as_str().unwrap(), "hello").await;
+        assert_eq!(events.next().await.1["content"], "hello");
+
+        let (status, _) = send(&app, Method::GET, &uri, None, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "tickets are single use");
+    }
+
+    #[sqlx::test]
+    async fn streams_events_and_resumes_from_the_last_event_id(pool: PgPool) {
+        let app = test_app(pool);
+        let session = register(&app, "sse@example.com", "password").await;
+        let (_, body) = send(
+            &app,
+            Method::POST,
+            "/me/tokens",
+            Some(&session),
+            Some(json!({ "name": "script", "scopes": ["clips:write"] })),
+        )
+        .await;
+        let token = body["token"].as_str().unwrap().to_string();
+
+        let (status, _) = send(&app, Method::GET, "/events", Some(&token), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let mut events = Events::open(&app, &session, None).await;
+        let first = push(&app, &token, "first").await;
+        let (id, clip) = events.next().await;
+        assert_eq!(clip["content"], "first");
+        assert_eq!(id, Some(first["id"].to_string()));
+
+        let uri = format!("/history/{}/pin", first["id"]);
+        send(&app, Method::PUT, &uri, Some(&session), None).await;
+        let (id, frame) = events.next().await;
+        assert_eq!(id, None);
+        assert_eq!(frame, json!({ "type": "pinned", "id": first["id"] }));
+        drop(events);
+
+        push(&app, &token, "second").await;
+        push(&app, &token, "third").await;
+        let last_seen = first["id"].to_string();
+        let mut events = Events::open(&app, &session, Some(&last_seen)).await;
+        assert_eq!(events.next().await.1["content"], "second");
+        assert_eq!(events.next().await.1["content"], "third");
+        push(&app, &token, "fourth").await;
+        assert_eq!(events.next().await.1["content"], "fourth");
+    }
+}
@@ -0,0 +1,35 @@
+use sha2::{Digest, Sha256};
+
+const TOKEN_BYTES: usize = 32;
+/// No 0/O or 1/I, since these codes are read off one screen and typed into another.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LEN: usize = 8;
+
+/// Generates a random, URL-safe secret to hand out to the user.
+pub fn generate() -> String {
+    hex::encode(rand::random::<[u8; TOKEN_BYTES]>())
+}
+
+/// Hashes a secret for storage. Secrets are high-entropy, so a fast hash is enough.
+pub fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Generates a short code such as `K7QM-2XRB`. Only 40 bits, so callers must expire
+/// codes quickly and limit guesses.
+pub fn generate_code() -> String {
+    let chars: String = (0..CODE_LEN)
+        .map(|_| CODE_ALPHABET[rand::random_range(0..CODE_ALPHABET.len())] as char)
+        .collect();
+    format!("{}-{}", &chars[..CODE_LEN / 2], &chars[CODE_LEN / 2..])
+}
+
+/// Hashes a code, ignoring case, dashes and spaces the user may have typed.
+pub fn hash_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    hash(&normalized)
+}
@@ -0,0 +1,68 @@
+use totp_rs::{Algorithm, TOTP};
+
+use crate::{error::AppError, token};
+
+const ISSUER: &str = "Echo";
+const SECRET_BYTES: usize = 20;
+const DIGITS: usize = 6;
+const STEP_SECS: u64 = 30;
+/// Accept codes from one step either side of now to tolerate clock drift.
+const SKEW_STEPS: u64 = 1;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+pub fn generate_secret() -> Vec<u8> {
+    rand::random::<[u8; SECRET_BYTES]>().to_vec()
+}
+
+fn build(secret: Vec<u8>, account: &str) -> Result<TOTP, AppError> {
+    TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        0,
+        STEP_SECS,
+        secret,
+        Some(ISSUER.into()),
+        account.into(),
+    )
+    .map_err(|e| AppError::Internal(format!("TOTP setup failed: {e}")))
+}
+
+/// Returns the base32 secret and the `otpauth://` URI for authenticator apps.
+pub fn provisioning(secret: &[u8], account: &str) -> Result<(String, String), AppError> {
+    let totp = build(secret.to_vec(), account)?;
+    Ok((totp.get_secret_base32(), totp.get_url()))
+}
+
+/// Checks `code` at `now` and returns the matching time step.
+///
+/// Steps at or before `last_used_step` are rejected so a code can't be replayed.
+pub fn verify(secret: &[u8], code: &str, now: u64, last_used_step: Option<i64>) -> Option<i64> {
+    let totp = build(secret.to_vec(), "").ok()?;
+    let code = code.trim();
+    let current = now / STEP_SECS;
+
+    (current.saturating_sub(SKEW_STEPS)..=current + SKEW_STEPS)
+        .map(|step| step as i64)
+        .filter(|step| last_used_step.is_none_or(|last| *step > last))
+        .find(|step| totp.check(code, *step as u64 * STEP_SECS))
+}
+
+/// Formats 64 random bits as `xxxx-xxxx-xxxx-xxxx`.
+pub fn generate_recovery_code() -> String {
+    let hex = &token::generate()[..16];
+    hex.as_bytes()
+        .chunks(4)
+        .map(|c| std::str::from_utf8(c).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Hashes a recovery code, ignoring case and dashes the user may have mistyped.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    token::hash(&normalized)
+}
@@ -10,6 +10,7 @@ import {
   saveEncryptionKey,
   loadEncryptionKey,
   generateLinkUri,
+  sealKeyForDevice,
   getKeyFingerprint,
   getOrCreateDeviceId,
 } from "./crypto";
@@ -169,7 +170,7 @@ interface AppState {
   keyFingerprint: string | null;
   showQR: boolean;
   showDevices: boolean;
-  linkUri: string | null;
+  pairing: Pairing | null;
   history: ClipboardEntry[];
   searchQuery: string;
   selectedEntry: ClipboardEntry | null;
@@ -177,6 +178,15 @@ interface AppState {
   filterType: ContentType | "all";
 }
 
+interface Pairing {
+  id: string;
+  code: string;
+  uri: string;
+  // Set once a new device has redeemed the code
+  deviceName: string | null;
+  devicePublicKey: string | null;
+}
+
 const initialState: AppState = {
   view: "login",
   email: "",
@@ -190,7 +200,7 @@ const initialState: AppState = {
   keyFingerprint: null,
   showQR: false,
   showDevices: false,
-  linkUri: null,
+  pairing: null,
   history: [],
   searchQuery: "",
   selectedEntry: null,
@@ -373,7 +383,8 @@ export default function App() {
     (token: string) => {
       if (wsRef.current?.readyState === WebSocket.OPEN) return;
 
-      const socket = new WebSocket(`${WS_URL}/ws?token=${token}`);
+      // Pass the token as a subprotocol so it never appears in the URL
+      const socket = new WebSocket(`${WS_URL}/ws`, ["echo.v1", `bearer.${token}`]);
 
       socket.onopen = () => {
         update("connected", true);
@@ -398,6 +409,21 @@ export default function App() {
         if (event.data === "pong") return;
         try {
           const msg = JSON.parse(event.data);
+          if (msg.type === "reauthenticate") {
+            // Swap in a fresh token before the server closes the socket
+            const current = loadToken();
+            if (!current) return;
+            const response = await fetch(`${API_URL}/token/refresh`, {
+              method: "POST",
+              headers: { Authorization: `Bearer ${current}` },
+            });
+            if (!response.ok) return;
+            const { token: refreshed } = await response.json();
+            saveToken(refreshed);
+            socket.send(JSON.stringify({ type: "auth", token: refreshed }));
+            return;
+          }
+          if (msg.type) return;
           if (msg.device_id === deviceIdRef.current) return;
 
           const text =
@@ -511,12 +537,85 @@ export default function App() {
     showToast("Signed out");
   };
 
-  const handleShowQR = () => {
-    if (!state.encryptionKey) return;
-    const uri = generateLinkUri(deviceIdRef.current, state.encryptionKey, WS_URL);
-    setState((prev) => ({ ...prev, showQR: true, linkUri: uri }));
+  const pairingRequest = async (method: "GET" | "POST", path: string, body?: object) => {
+    const response = await fetch(`${API_URL}${path}`, {
+      method,
+      headers: {
+        Authorization: `Bearer ${loadToken()}`,
+        "Content-Type": "application/json",
+      },
+      body: body === undefined ? undefined : JSON.stringify(body),
+    });
+    const data = response.status === 204 ? null : await response.json();
+    if (!response.ok) throw new Error(data?.error || "Pairing failed");
+    return data;
+  };
+
+  const handleShowQR = async () => {
+    try {
+      const { id, code } = await pairingRequest("POST", "/pairing");
+      const uri = generateLinkUri(code, API_URL);
+      setState((prev) => ({
+        ...prev,
+        showQR: true,
+        pairing: { id, code, uri, deviceName: null, devicePublicKey: null },
+      }));
+    } catch (error) {
+      showToast(error instanceof Error ? error.message : "Pairing failed", "error");
+    }
+  };
+
+  const respondToPairing = async (approve: boolean) => {
+    const pairing = state.pairing;
+    if (!pairing) return;
+    try {
+      if (approve) {
+        // The server only ever relays the key sealed to the new device
+        const sealedKey =
+          state.encryptionKey && pairing.devicePublicKey
+            ? await sealKeyForDevice(state.encryptionKey, pairing.devicePublicKey)
+            : null;
+        await pairingRequest("POST", `/pairing/${pairing.id}/approve`, {
+          sealed_key: sealedKey,
+        });
+      } else {
+        await pairingRequest("POST", `/pairing/${pairing.id}/reject`);
+      }
+      setState((prev) => ({ ...prev, showQR: false, pairing: null }));
+      showToast(approve ? `${pairing.deviceName} linked` : "Pairing request rejected");
+    } catch (error) {
+      showToast(error instanceof Error ? error.message : "Pairing failed", "error");
+    }
   };
 
+  useEffect(() => {
+    const id = state.pairing?.id;
+    if (!state.showQR || !id || state.pairing?.deviceName) return;
+
+    const timer = setInterval(async () => {
+      try {
+        const info = await pairingRequest("GET", `/pairing/${id}`);
+        if (info.status !== "claimed") return;
+        setState((prev) =>
+          prev.pairing?.id === id
+            ? {
+                ...prev,
+                pairing: {
+                  ...prev.pairing,
+                  deviceName: info.device_name,
+                  devicePublicKey: info.device_public_key,
+                },
+              }
+            : prev
+        );
+      } catch {
+        // Keep polling; the code may just be about to be redeemed
+      }
+    }, 2000);
+
+    return () => clearInterval(timer);
+  }, [state.showQR, state.pairing?.id, state.pairing?.deviceName]);
+
   const filteredHistory = state.history
     .filter((e) => {
       if (state.filterType !== "all" && e.contentType !== state.filterType) return false;
@@ -902,30 +1001,51 @@ export default function App() {
         </main>
       </div>
 
-      {state.showQR && state.linkUri && (
+      {state.showQR && state.pairing && (
         <div className="modal-backdrop" onClick={() => update("showQR", false)}>
           <div className="modal" onClick={(e) => e.stopPropagation()}>
             <div className="modal-header">
               <h2>Link a Device</h2>
-              <p>Scan this QR code with the Echo mobile app</p>
+              <p>Scan this QR code with the Echo mobile app, or enter the code</p>
             </div>
             <div className="modal-body">
-              <div className="qr-wrapper">
-                <img
-                  src={`https://api.qrserver.com/v1/create-qr-code/?size=200x200&data=${encodeURIComponent(state.linkUri)}`}
-                  alt="QR Code"
-                  width={200}
-                  height={200}
-                />
-              </div>
+              {state.pairing.deviceName ? (
+                <p>
+                  <strong>{state.pairing.deviceName}</strong> wants to join your account.
+                </p>
+              ) : (
+                <>
+                  <div className="qr-wrapper">
+                    <img
+                      src={`https://api.qrserver.com/v1/create-qr-code/?size=200x200&data=${encodeURIComponent(state.pairing.uri)}`}
+                      alt="QR Code"
+                      width={200}
+                      height={200}
+                    />
+                  </div>
+                  <p className="pairing-code">{state.pairing.code}</p>
+                </>
+              )}
               <p className="modal-hint">
-                Your encryption key will be securely transferred to the new device.
+                The code expires in 10 minutes. You'll be asked to approve the new device, and
+                your encryption key is sealed so only that device can read it.
               </p>
             </div>
             <div className="modal-footer">
-              <button className="btn btn-primary" onClick={() => update("showQR", false)}>
-                Done
-              </button>
+              {state.pairing.deviceName ? (
+                <>
+                  <button className="btn btn-ghost" onClick={() => respondToPairing(false)}>
+                    Reject
+                  </button>
+                  <button className="btn btn-primary" onClick={() => respondToPairing(true)}>
+                    Approve
+                  </button>
+                </>
+              ) : (
+                <button className="btn btn-primary" onClick={() => update("showQR", false)}>
+                  Cancel
+                </button>
+              )}
             </div>
           </div>
         </div>
@@ -41,16 +41,45 @@ export async function getKeyFingerprint(key: Uint8Array): Promise<string> {
   return toHex(new Uint8Array(hash)).slice(0, 8).toUpperCase();
 }
 
-export function generateLinkUri(
-  deviceId: string,
+// Carries only a single-use pairing code; credentials are issued once the request is approved
+export function generateLinkUri(code: string, serverUrl: string): string {
+  return `echo://pair?${new URLSearchParams({ code, server: serverUrl })}`;
+}
+
+// Encrypts the key to a pairing device's P-256 ECDH public key (base64url, raw point)
+// with an ephemeral key pair. Output: `epk.iv.ciphertext`, each base64url.
+export async function sealKeyForDevice(
   key: Uint8Array,
-  wsUrl: string
-): string {
-  return `echo://connect?${new URLSearchParams({
-    id: deviceId,
-    key: toBase64Url(key),
-    server: wsUrl,
-  })}`;
+  devicePublicKey: string
+): Promise<string> {
+  const curve = { name: "ECDH", namedCurve: "P-256" };
+  const recipient = await crypto.subtle.importKey(
+    "raw",
+    fromBase64Url(devicePublicKey).buffer as ArrayBuffer,
+    curve,
+    false,
+    []
+  );
+  const ephemeral = await crypto.subtle.generateKey(curve, true, ["deriveKey"]);
+  const wrappingKey = await crypto.subtle.deriveKey(
+    { name: "ECDH", public: recipient },
+    ephemeral.privateKey,
+    { name: "AES-GCM", length: 256 },
+    false,
+    ["encrypt"]
+  );
+
+  const iv = randomBytes(12);
+  const ciphertext = await crypto.subtle.encrypt(
+    { name: "AES-GCM", iv: iv.buffer as ArrayBuffer },
+    wrappingKey,
+    new Uint8Array(key).buffer as ArrayBuffer
+  );
+  const epk = await crypto.subtle.exportKey("raw", ephemeral.publicKey);
+
+  return [new Uint8Array(epk), iv, new Uint8Array(ciphertext)]
+    .map(toBase64Url)
+    .join(".");
 }
 
 export async function saveEncryptionKey(key: Uint8Array): Promise<void> {